@@ -17,8 +17,8 @@ use crossbeam_channel::bounded;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use zello_client::{
-    PCM_CHANNEL_CAPACITY, connect_to_zello, create_decoder, initialize_logging, load_credentials,
-    setup_audio_output, utilities::load_dotenv_from_file,
+    DeviceSelector, PCM_CHANNEL_CAPACITY, connect_to_zello, create_decoder, initialize_logging,
+    load_credentials, setup_audio_output, utilities::load_dotenv_from_file,
 };
 
 #[derive(Parser, Debug)]
@@ -67,7 +67,7 @@ async fn main() -> Result<()> {
 
     let (pcm_tx, pcm_rx) = bounded::<Vec<i16>>(PCM_CHANNEL_CAPACITY);
     let pcm_rx = Arc::new(Mutex::new(pcm_rx));
-    let _stream = setup_audio_output(pcm_rx)?;
+    let _stream = setup_audio_output(pcm_rx, &DeviceSelector::Default)?;
 
     let mut client = connect_to_zello(&credentials).await?;
 
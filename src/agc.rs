@@ -0,0 +1,249 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Automatic gain control and loudness normalization for received audio
+
+use std::time::Duration;
+
+use crate::{CPAL_SAMPLE_RATE, PCM_I16_TO_F32};
+
+/// Samples per level measurement (10ms at the Zello sample rate)
+const AGC_BLOCK_SIZE: usize = 160;
+
+/// Release time of the peak limiter
+const LIMITER_RELEASE: Duration = Duration::from_millis(50);
+
+/// Settings for automatic gain control
+#[derive(Debug, Clone)]
+pub struct AgcConfig {
+    /// Target RMS level in dBFS
+    pub target_level_dbfs: f32,
+    /// Largest boost applied to quiet audio, in dB
+    pub max_gain_db: f32,
+    /// Time to reduce the gain when audio gets louder
+    pub attack: Duration,
+    /// Time to raise the gain when audio gets quieter
+    pub release: Duration,
+    /// Level below which audio is treated as background noise and not boosted, in dBFS
+    pub noise_floor_dbfs: f32,
+    /// Peak level the limiter holds the output below, in dBFS
+    pub limiter_ceiling_dbfs: f32,
+}
+
+impl Default for AgcConfig {
+    fn default() -> Self {
+        Self {
+            target_level_dbfs: -20.0,
+            max_gain_db: 24.0,
+            attack: Duration::from_millis(20),
+            release: Duration::from_millis(800),
+            noise_floor_dbfs: -55.0,
+            limiter_ceiling_dbfs: -1.0,
+        }
+    }
+}
+
+/// Automatic gain control with a peak limiter, for 16kHz mono PCM
+#[derive(Debug, Clone)]
+pub struct AutomaticGainControl {
+    config: AgcConfig,
+    target: f32,
+    max_gain: f32,
+    noise_floor: f32,
+    ceiling: f32,
+    attack_coefficient: f32,
+    release_coefficient: f32,
+    limiter_release_coefficient: f32,
+    gain: f32,
+    limiter_gain: f32,
+}
+
+impl AutomaticGainControl {
+    /// Create a gain control with these settings
+    #[must_use]
+    pub fn new(config: AgcConfig) -> Self {
+        #[allow(clippy::cast_precision_loss)]
+        let block = AGC_BLOCK_SIZE as f32 / CPAL_SAMPLE_RATE.0 as f32;
+        #[allow(clippy::cast_precision_loss)]
+        let sample = 1.0 / CPAL_SAMPLE_RATE.0 as f32;
+
+        Self {
+            target: db_to_linear(config.target_level_dbfs),
+            max_gain: db_to_linear(config.max_gain_db),
+            noise_floor: db_to_linear(config.noise_floor_dbfs),
+            ceiling: db_to_linear(config.limiter_ceiling_dbfs),
+            attack_coefficient: smoothing_coefficient(block, config.attack),
+            release_coefficient: smoothing_coefficient(block, config.release),
+            limiter_release_coefficient: smoothing_coefficient(sample, LIMITER_RELEASE),
+            gain: 1.0,
+            limiter_gain: 1.0,
+            config,
+        }
+    }
+
+    /// Settings in use
+    #[must_use]
+    pub fn config(&self) -> &AgcConfig {
+        &self.config
+    }
+
+    /// Current gain, excluding the limiter
+    #[must_use]
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Start again from unity gain, as at the start of a new transmission
+    pub fn reset(&mut self) {
+        self.gain = 1.0;
+        self.limiter_gain = 1.0;
+    }
+
+    /// Normalize a chunk of PCM in place
+    pub fn process(&mut self, pcm: &mut [i16]) {
+        self.process_with_gain(pcm, 1.0);
+    }
+
+    /// Normalize a chunk of PCM in place, then scale it by `output_gain`
+    ///
+    /// The limiter comes last, so an output gain above 1.0 cannot clip.
+    pub fn process_with_gain(&mut self, pcm: &mut [i16], output_gain: f32) {
+        for block in pcm.chunks_mut(AGC_BLOCK_SIZE) {
+            let start_gain = self.gain;
+            self.update_gain(rms(block));
+
+            #[allow(clippy::cast_precision_loss)]
+            let step = (self.gain - start_gain) / block.len() as f32;
+            let mut gain = start_gain;
+
+            for sample in block.iter_mut() {
+                gain += step;
+                let value = f32::from(*sample) * PCM_I16_TO_F32 * gain * output_gain;
+                *sample = to_i16(self.limit(value));
+            }
+        }
+    }
+
+    /// Move the gain towards the level that brings this block to the target
+    fn update_gain(&mut self, level: f32) {
+        if level < self.noise_floor {
+            return;
+        }
+
+        let desired = (self.target / level).min(self.max_gain);
+        let coefficient = if desired < self.gain {
+            self.attack_coefficient
+        } else {
+            self.release_coefficient
+        };
+        self.gain += (desired - self.gain) * coefficient;
+    }
+
+    /// Hold a sample below the ceiling, reducing the limiter gain instantly
+    fn limit(&mut self, value: f32) -> f32 {
+        let peak = value.abs() * self.limiter_gain;
+        if peak > self.ceiling {
+            self.limiter_gain = self.ceiling / value.abs();
+        } else {
+            self.limiter_gain += (1.0 - self.limiter_gain) * self.limiter_release_coefficient;
+        }
+        value * self.limiter_gain
+    }
+}
+
+pub(crate) fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// One-pole smoothing coefficient for a step of `step` seconds and a time constant
+fn smoothing_coefficient(step: f32, time_constant: Duration) -> f32 {
+    let time_constant = time_constant.as_secs_f32();
+    if time_constant <= 0.0 {
+        1.0
+    } else {
+        1.0 - (-step / time_constant).exp()
+    }
+}
+
+pub(crate) fn rms(block: &[i16]) -> f32 {
+    if block.is_empty() {
+        return 0.0;
+    }
+    let sum: f32 = block
+        .iter()
+        .map(|&s| {
+            let v = f32::from(s) * PCM_I16_TO_F32;
+            v * v
+        })
+        .sum();
+    #[allow(clippy::cast_precision_loss)]
+    let mean = sum / block.len() as f32;
+    mean.sqrt()
+}
+
+fn to_i16(value: f32) -> i16 {
+    // Truncation is intended: the value is clamped to the i16 range
+    #[allow(clippy::cast_possible_truncation)]
+    let sample = (value * 32768.0).clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16;
+    sample
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(amplitude: f32, samples: usize) -> Vec<i16> {
+        (0..samples)
+            .map(|i| {
+                #[allow(clippy::cast_precision_loss)]
+                let phase = i as f32 * 2.0 * std::f32::consts::PI * 440.0 / 16000.0;
+                to_i16(amplitude * phase.sin())
+            })
+            .collect()
+    }
+
+    fn level_dbfs(pcm: &[i16]) -> f32 {
+        20.0 * rms(pcm).log10()
+    }
+
+    #[test]
+    fn test_quiet_audio_is_raised() {
+        let mut agc = AutomaticGainControl::new(AgcConfig::default());
+        let mut pcm = tone(0.01, 16000 * 5);
+        agc.process(&mut pcm);
+        let level = level_dbfs(&pcm[pcm.len() - 1600..]);
+        assert!((level + 20.0).abs() < 2.0, "level {level}");
+    }
+
+    #[test]
+    fn test_loud_audio_is_limited() {
+        let mut agc = AutomaticGainControl::new(AgcConfig::default());
+        let mut pcm = tone(1.0, 16000);
+        agc.process(&mut pcm);
+        let ceiling = to_i16(db_to_linear(-1.0));
+        assert!(
+            pcm.iter()
+                .all(|s| s.unsigned_abs() <= ceiling.unsigned_abs() + 1)
+        );
+    }
+
+    #[test]
+    fn test_output_gain_is_limited() {
+        let mut agc = AutomaticGainControl::new(AgcConfig::default());
+        let mut pcm = tone(0.5, 16000);
+        agc.process_with_gain(&mut pcm, 4.0);
+        let ceiling = to_i16(db_to_linear(-1.0));
+        assert!(
+            pcm.iter()
+                .all(|s| s.unsigned_abs() <= ceiling.unsigned_abs() + 1)
+        );
+    }
+
+    #[test]
+    fn test_noise_is_not_boosted() {
+        let mut agc = AutomaticGainControl::new(AgcConfig::default());
+        let mut pcm = tone(0.0005, 16000);
+        agc.process(&mut pcm);
+        assert!((agc.gain() - 1.0).abs() < f32::EPSILON);
+    }
+}
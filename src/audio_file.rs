@@ -0,0 +1,457 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Loading WAV and Ogg/Opus files as Opus packets ready to transmit
+
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek};
+use std::path::Path;
+
+use crate::encoder::{EncoderConfig, OpusEncoder};
+use crate::error::{Result, ZelloError};
+use crate::message::CodecHeader;
+use crate::{CPAL_SAMPLE_RATE, OPUS_SAMPLE_RATE, PCM_I16_TO_F32};
+use audiopus::coder::Decoder;
+use audiopus::packet::Packet;
+use audiopus::{Channels, MutSignals};
+use rubato::{
+    Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+
+/// Input frames per resampler call when converting a whole file
+const RESAMPLE_CHUNK_SIZE: usize = 1024;
+
+/// Samples per channel in the longest Opus packet (120ms) at the Zello sample rate
+const MAX_DECODED_SAMPLES: usize = 1920;
+
+/// Audio encoded as Opus packets of a single duration
+#[derive(Debug, Clone)]
+pub struct EncodedAudio {
+    /// Codec header describing the packets, for `start_stream`
+    pub codec_header: CodecHeader,
+    /// Duration of each packet in milliseconds
+    pub packet_duration: u32,
+    /// The Opus packets in order
+    pub packets: Vec<Vec<u8>>,
+}
+
+impl EncodedAudio {
+    /// Total duration of the audio in milliseconds
+    #[must_use]
+    pub fn duration_ms(&self) -> u64 {
+        self.packets.len() as u64 * u64::from(self.packet_duration)
+    }
+}
+
+/// Load a WAV or Ogg/Opus file for transmission
+///
+/// WAV audio is mixed down to mono, resampled to 16kHz and encoded with
+/// `config`. Ogg/Opus audio that is already 16kHz mono with a fixed packet
+/// duration is sent as-is; any other Opus stream is decoded and re-encoded.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read, is not WAV or Ogg/Opus, or cannot be encoded
+pub fn load_audio_file(path: &Path, config: &EncoderConfig) -> Result<EncodedAudio> {
+    let file = BufReader::new(File::open(path)?);
+    load_audio(file, &path.display().to_string(), config)
+}
+
+/// Load WAV or Ogg/Opus audio held in memory, as [`load_audio_file`] does
+///
+/// # Errors
+///
+/// Returns an error if the data is not WAV or Ogg/Opus, or cannot be encoded
+pub fn load_audio_bytes(data: &[u8], config: &EncoderConfig) -> Result<EncodedAudio> {
+    load_audio(Cursor::new(data), "Audio", config)
+}
+
+/// Load audio from `reader`, naming it `name` in errors
+fn load_audio(
+    mut reader: impl Read + Seek,
+    name: &str,
+    config: &EncoderConfig,
+) -> Result<EncodedAudio> {
+    let mut magic = [0u8; 12];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| ZelloError::AudioError(format!("{name} is too short to be audio")))?;
+    reader.rewind()?;
+
+    if &magic[..4] == b"RIFF" && &magic[8..] == b"WAVE" {
+        encode_pcm(&read_wav(reader)?, config)
+    } else if &magic[..4] == b"OggS" {
+        let ogg = read_ogg_opus(reader)?;
+        match ogg.passthrough() {
+            Some(audio) => Ok(audio),
+            None => encode_pcm(&ogg.decode()?, config),
+        }
+    } else {
+        Err(ZelloError::AudioError(format!(
+            "{name} is not a WAV or Ogg/Opus file"
+        )))
+    }
+}
+
+/// Read WAV audio as 16kHz mono PCM
+fn read_wav(reader: impl Read) -> Result<Vec<i16>> {
+    let reader = hound::WavReader::new(reader).map_err(audio_error)?;
+    let spec = reader.spec();
+
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .into_samples::<f32>()
+            .collect::<std::result::Result<_, _>>()
+            .map_err(audio_error)?,
+        hound::SampleFormat::Int => {
+            #[allow(clippy::cast_precision_loss)]
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .into_samples::<i32>()
+                .map(|s| {
+                    s.map(|s| {
+                        #[allow(clippy::cast_precision_loss)]
+                        let sample = s as f32 * scale;
+                        sample
+                    })
+                })
+                .collect::<std::result::Result<_, _>>()
+                .map_err(audio_error)?
+        }
+    };
+
+    let mono = downmix(&samples, usize::from(spec.channels));
+    let resampled = resample(&mono, spec.sample_rate)?;
+    Ok(resampled.into_iter().map(to_i16).collect())
+}
+
+/// An Ogg/Opus file split into its header fields and audio packets
+#[derive(Debug)]
+struct OggOpus {
+    channels: u8,
+    input_sample_rate: u32,
+    pre_skip: u16,
+    packets: Vec<Vec<u8>>,
+}
+
+/// Read the first Opus stream from an Ogg file
+fn read_ogg_opus(reader: impl Read + Seek) -> Result<OggOpus> {
+    let mut reader = ogg::PacketReader::new(reader);
+
+    let head = reader
+        .read_packet()
+        .map_err(audio_error)?
+        .ok_or_else(|| ZelloError::AudioError("Ogg file is empty".to_string()))?;
+    let data = &head.data;
+    if data.len() < 19 || &data[..8] != b"OpusHead" {
+        return Err(ZelloError::AudioError(
+            "Ogg file does not contain Opus audio".to_string(),
+        ));
+    }
+
+    let mut ogg = OggOpus {
+        channels: data[9],
+        pre_skip: u16::from_le_bytes([data[10], data[11]]),
+        input_sample_rate: u32::from_le_bytes([data[12], data[13], data[14], data[15]]),
+        packets: Vec::new(),
+    };
+
+    let serial = head.stream_serial();
+    while let Some(packet) = reader.read_packet().map_err(audio_error)? {
+        if packet.stream_serial() != serial || packet.data.starts_with(b"OpusTags") {
+            continue;
+        }
+        ogg.packets.push(packet.data);
+    }
+
+    Ok(ogg)
+}
+
+impl OggOpus {
+    /// The packets unchanged, if Zello can play them as they are
+    fn passthrough(&self) -> Option<EncodedAudio> {
+        if self.channels != 1 || self.input_sample_rate != CPAL_SAMPLE_RATE.0 {
+            return None;
+        }
+
+        let duration = packet_duration_us(self.packets.first()?)?;
+        if ![10_000, 20_000, 40_000, 60_000].contains(&duration)
+            || !self
+                .packets
+                .iter()
+                .all(|p| packet_duration_us(p) == Some(duration) && !is_stereo(p))
+        {
+            return None;
+        }
+
+        let frame_size_ms = u8::try_from(duration / 1000).ok()?;
+        Some(EncodedAudio {
+            codec_header: CodecHeader {
+                sample_rate_hz: OPUS_SAMPLE_RATE as u16,
+                frames_per_packet: 1,
+                frame_size_ms,
+            },
+            packet_duration: u32::from(frame_size_ms),
+            packets: self.packets.clone(),
+        })
+    }
+
+    /// Decode the packets to 16kHz mono PCM
+    fn decode(&self) -> Result<Vec<i16>> {
+        let channels = match self.channels {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            n => {
+                return Err(ZelloError::AudioError(format!(
+                    "Unsupported Opus channel count: {n}"
+                )));
+            }
+        };
+        let channel_count = usize::from(self.channels);
+
+        let mut decoder = Decoder::new(OPUS_SAMPLE_RATE, channels).map_err(audio_error)?;
+        let mut buffer = vec![0i16; MAX_DECODED_SAMPLES * channel_count];
+        let mut pcm = Vec::new();
+
+        for data in &self.packets {
+            let packet = Packet::try_from(data).map_err(audio_error)?;
+            let signals = MutSignals::try_from(&mut buffer).map_err(audio_error)?;
+            let samples = decoder
+                .decode(Some(packet), signals, false)
+                .map_err(audio_error)?;
+
+            let frames = &buffer[..samples * channel_count];
+            pcm.extend(frames.chunks(channel_count).map(|frame| {
+                let sum: i32 = frame.iter().copied().map(i32::from).sum();
+                // Truncation is impossible: the mean of i16 values fits an i16
+                #[allow(clippy::cast_possible_truncation)]
+                let mean = (sum / i32::from(self.channels)) as i16;
+                mean
+            }));
+        }
+
+        // Pre-skip is counted at 48kHz
+        let skip = usize::from(self.pre_skip) / 3;
+        pcm.drain(..skip.min(pcm.len()));
+        Ok(pcm)
+    }
+}
+
+/// Duration of an Opus packet in microseconds, from its TOC byte
+pub(crate) fn packet_duration_us(packet: &[u8]) -> Option<u32> {
+    let toc = *packet.first()?;
+    let config = toc >> 3;
+    let frame_us = match config {
+        0..=11 => [10_000, 20_000, 40_000, 60_000][usize::from(config % 4)],
+        12..=15 => [10_000, 20_000][usize::from(config % 2)],
+        _ => [2_500, 5_000, 10_000, 20_000][usize::from(config % 4)],
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => u32::from(*packet.get(1)? & 0x3f),
+    };
+    Some(frame_us * frames)
+}
+
+fn is_stereo(packet: &[u8]) -> bool {
+    packet.first().is_some_and(|toc| toc & 0x04 != 0)
+}
+
+/// Encode 16kHz mono PCM into packets
+fn encode_pcm(pcm: &[i16], config: &EncoderConfig) -> Result<EncodedAudio> {
+    let mut encoder = OpusEncoder::new(config.clone())?;
+    let mut packets = encoder.encode(pcm)?;
+    packets.extend(encoder.flush()?);
+
+    Ok(EncodedAudio {
+        codec_header: encoder.codec_header(),
+        packet_duration: encoder.packet_duration(),
+        packets,
+    })
+}
+
+/// Average interleaved frames down to one channel
+fn downmix(samples: &[f32], channels: usize) -> Vec<f32> {
+    if channels <= 1 {
+        return samples.to_vec();
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let scale = 1.0 / channels as f32;
+    samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() * scale)
+        .collect()
+}
+
+/// Resample mono audio to the Zello sample rate
+fn resample(input: &[f32], sample_rate: u32) -> Result<Vec<f32>> {
+    if sample_rate == CPAL_SAMPLE_RATE.0 {
+        return Ok(input.to_vec());
+    }
+
+    let parameters = SincInterpolationParameters {
+        sinc_len: 128,
+        f_cutoff: 0.95,
+        oversampling_factor: 256,
+        interpolation: SincInterpolationType::Linear,
+        window: WindowFunction::BlackmanHarris2,
+    };
+    let ratio = f64::from(CPAL_SAMPLE_RATE.0) / f64::from(sample_rate);
+    let mut resampler = SincFixedIn::<f32>::new(ratio, 1.0, parameters, RESAMPLE_CHUNK_SIZE, 1)
+        .map_err(audio_error)?;
+
+    let delay = resampler.output_delay();
+    // Truncation is intended: the length of the resampled audio
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    let expected = (input.len() as f64 * ratio).ceil() as usize;
+    let mut output = Vec::with_capacity(expected + delay);
+
+    let mut remaining = input;
+    while remaining.len() >= resampler.input_frames_next() {
+        let (chunk, rest) = remaining.split_at(resampler.input_frames_next());
+        let frames = resampler.process(&[chunk], None).map_err(audio_error)?;
+        output.extend_from_slice(&frames[0]);
+        remaining = rest;
+    }
+    if !remaining.is_empty() {
+        let frames = resampler
+            .process_partial(Some(&[remaining]), None)
+            .map_err(audio_error)?;
+        output.extend_from_slice(&frames[0]);
+    }
+    while output.len() < expected + delay {
+        let frames = resampler
+            .process_partial::<&[f32]>(None, None)
+            .map_err(audio_error)?;
+        output.extend_from_slice(&frames[0]);
+    }
+
+    output.drain(..delay);
+    output.truncate(expected);
+    Ok(output)
+}
+
+fn to_i16(value: f32) -> i16 {
+    // Truncation is intended: the value is clamped to the i16 range
+    #[allow(clippy::cast_possible_truncation)]
+    let sample = (value / PCM_I16_TO_F32).clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16;
+    sample
+}
+
+fn audio_error(e: impl std::fmt::Display) -> ZelloError {
+    ZelloError::AudioError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("zello-{}-{name}", std::process::id()))
+    }
+
+    fn write_ogg(path: &Path, input_sample_rate: u32, packets: &[Vec<u8>]) {
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, 1, 0, 0]);
+        head.extend_from_slice(&input_sample_rate.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+
+        let mut writer = ogg::PacketWriter::new(File::create(path).expect("create"));
+        writer
+            .write_packet(head, 1, ogg::PacketWriteEndInfo::EndPage, 0)
+            .expect("head");
+        writer
+            .write_packet(b"OpusTags".to_vec(), 1, ogg::PacketWriteEndInfo::EndPage, 0)
+            .expect("tags");
+        for (i, packet) in packets.iter().enumerate() {
+            let end = if i + 1 == packets.len() {
+                ogg::PacketWriteEndInfo::EndStream
+            } else {
+                ogg::PacketWriteEndInfo::NormalPacket
+            };
+            writer
+                .write_packet(packet.clone(), 1, end, (i as u64 + 1) * 960)
+                .expect("packet");
+        }
+    }
+
+    #[test]
+    fn test_packet_duration() {
+        // SILK 60ms, one frame
+        assert_eq!(packet_duration_us(&[0x18]), Some(60_000));
+        // CELT 20ms, two frames
+        assert_eq!(packet_duration_us(&[0xf9]), Some(40_000));
+        // CELT 2.5ms, code 3 with three frames
+        assert_eq!(packet_duration_us(&[0x83, 0x03]), Some(7_500));
+        assert_eq!(packet_duration_us(&[]), None);
+    }
+
+    #[test]
+    fn test_wav_is_resampled_and_encoded() {
+        let path = temp_path("stereo.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).expect("create");
+        for _ in 0..48000 * 2 {
+            writer.write_sample(0i16).expect("sample");
+        }
+        writer.finalize().expect("finalize");
+
+        let audio = load_audio_file(&path, &EncoderConfig::default()).expect("load");
+        let _ = std::fs::remove_file(path);
+
+        assert_eq!(audio.packet_duration, 60);
+        assert_eq!(audio.codec_header.sample_rate_hz, 16000);
+        // One second is 16.7 packets of 60ms, padded to 17
+        assert_eq!(audio.packets.len(), 17);
+    }
+
+    #[test]
+    fn test_ogg_opus_passthrough_and_reencode() {
+        let mut encoder = OpusEncoder::new(EncoderConfig {
+            frame_size_ms: 20,
+            ..EncoderConfig::default()
+        })
+        .expect("encoder");
+        let packets = encoder.encode(&vec![0i16; 16000]).expect("encode");
+
+        let path = temp_path("mono16k.opus");
+        write_ogg(&path, 16000, &packets);
+        let audio = load_audio_file(&path, &EncoderConfig::default()).expect("load");
+        assert_eq!(audio.packet_duration, 20);
+        assert_eq!(audio.packets, packets);
+
+        write_ogg(&path, 48000, &packets);
+        let audio = load_audio_file(&path, &EncoderConfig::default()).expect("load");
+        let _ = std::fs::remove_file(path);
+        assert_eq!(audio.packet_duration, 60);
+        assert_eq!(audio.packets.len(), 17);
+    }
+
+    #[test]
+    fn test_load_audio_bytes() {
+        let mut encoder = OpusEncoder::new(EncoderConfig::default()).expect("encoder");
+        let packets = encoder.encode(&vec![0i16; 16000]).expect("encode");
+
+        let path = temp_path("bytes.opus");
+        write_ogg(&path, 16000, &packets);
+        let data = std::fs::read(&path).expect("read");
+        let _ = std::fs::remove_file(path);
+
+        let audio = load_audio_bytes(&data, &EncoderConfig::default()).expect("load");
+        assert_eq!(audio.packets, packets);
+        assert!(matches!(
+            load_audio_bytes(b"neither WAV nor Ogg", &EncoderConfig::default()),
+            Err(ZelloError::AudioError(message)) if message.contains("not a WAV")
+        ));
+    }
+}
@@ -12,13 +12,27 @@
 //! Example Zello client application
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use crossbeam_channel::bounded;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{Mutex, mpsc};
+use tracing::{error, info};
 use zello_client::{
-    PCM_CHANNEL_CAPACITY, connect_to_zello, create_decoder, initialize_logging, load_credentials,
-    load_dotenv, setup_audio_output,
+    AgcConfig, AudioInput, BridgeConfig, BridgeEnd, Credentials, Dashboard, DeviceSelector,
+    EncoderConfig, FloorPolicy, Gateway, GatewayConfig, IrcBridge, IrcConfig, MqttBridge,
+    MqttConfig, OggStreamConfig, PCM_CHANNEL_CAPACITY, ParrotConfig, PcmEndpoint, PlaybackConfig,
+    PttControl, RtpConfig, TransmitLimits, VolumeControl, VoxConfig, WebhookConfig, ZelloClient,
+    connect_to_zello, create_decoder, initialize_logging, list_input_devices, list_output_devices,
+    load_bridge_credentials, load_credentials, load_dotenv, load_gateway_token, load_irc_password,
+    load_mqtt_credentials, load_webhook_secret, read_pcm, receive_rtp, run_bridge, run_irc_bridge,
+    run_mqtt_bridge, run_parrot, run_webhooks, send_rtp, serve_dashboard, serve_gateway,
+    serve_ogg_stream, setup_audio_input, setup_audio_output_with_config, transmit_with_ptt,
+    transmit_with_vox, utilities::format_device_list, write_pcm,
 };
 
 #[derive(Parser, Debug)]
@@ -46,6 +60,7 @@ This requirement may change in the future when the API is made public."
 )]
 #[command(version = env!("CARGO_PKG_VERSION"))]
 #[command(long_version = concat!(env!("CARGO_PKG_VERSION"), " / ", env!("GIT_VERSION")))]
+#[allow(clippy::struct_excessive_bools)]
 struct Args {
     /// Message to send as text message
     #[arg(short = 'm', long)]
@@ -54,39 +69,641 @@ struct Args {
     /// Destination callsign of message (requires --message)
     #[arg(short = 'c', long, requires = "message")]
     callsign: Option<String>,
+
+    /// Audio output device, by name or by index from the `devices` command
+    #[arg(
+        short = 'o',
+        long,
+        value_name = "NAME|INDEX",
+        default_value = "default"
+    )]
+    output_device: DeviceSelector,
+
+    /// Audio input device for --ptt or --vox, by name or by index from the `devices` command
+    #[arg(long, value_name = "NAME|INDEX", default_value = "default")]
+    input_device: DeviceSelector,
+
+    /// Transmit from the microphone while push-to-talk is held: press Enter to
+    /// start and stop talking, or send SIGUSR1 to start and SIGUSR2 to stop
+    #[arg(long)]
+    ptt: bool,
+
+    /// Transmit from the input device whenever its level crosses --vox-threshold
+    #[arg(long, conflicts_with = "ptt")]
+    vox: bool,
+
+    /// Level that keys --vox transmission, in dBFS
+    #[arg(long, value_name = "DBFS", default_value_t = -40.0, allow_negative_numbers = true)]
+    vox_threshold: f32,
+
+    /// Quiet time before --vox transmission stops, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 1500)]
+    vox_hang: u64,
+
+    /// Repeat each transmission back into the channel after it ends
+    #[arg(long)]
+    parrot: bool,
+
+    /// Only repeat transmissions from these callsigns with --parrot
+    #[arg(
+        long,
+        value_name = "CALLSIGN",
+        value_delimiter = ',',
+        requires = "parrot"
+    )]
+    parrot_allow: Vec<String>,
+
+    /// Longest clip repeated by --parrot, in seconds
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    parrot_max_clip: u64,
+
+    /// Send each transmission as an RTP Opus stream to this address
+    #[arg(long, value_name = "HOST:PORT")]
+    rtp_send: Option<SocketAddr>,
+
+    /// Transmit RTP Opus or PCMU audio received on this address
+    #[arg(long, value_name = "ADDR:PORT")]
+    rtp_listen: Option<SocketAddr>,
+
+    /// RTP payload type for Opus
+    #[arg(long, value_name = "PT", default_value_t = 111)]
+    rtp_payload_type: u8,
+
+    /// Write channel audio as raw 16kHz PCM to a named pipe, `-` for standard
+    /// output or `udp://HOST:PORT`
+    #[arg(long, value_name = "TARGET")]
+    pcm_out: Option<PcmEndpoint>,
+
+    /// Transmit raw PCM read from a named pipe, `-` for standard input or
+    /// `udp://ADDR:PORT`, keyed by --vox-threshold
+    #[arg(long, value_name = "SOURCE", conflicts_with_all = ["ptt", "vox"])]
+    pcm_in: Option<PcmEndpoint>,
+
+    /// Sample rate of --pcm-in audio, in Hz
+    #[arg(long, value_name = "HZ", default_value_t = 16000)]
+    pcm_in_rate: u32,
+
+    /// Serve channel audio as a live Ogg/Opus stream over HTTP on this address
+    #[arg(long, value_name = "ADDR:PORT")]
+    http_stream: Option<SocketAddr>,
+
+    /// Serve a monitoring dashboard for web browsers on this address
+    #[arg(long, value_name = "ADDR:PORT")]
+    dashboard: Option<SocketAddr>,
+
+    /// POST text, transmission, presence and channel status events as JSON to
+    /// these URLs, signed with `ZELLO_WEBHOOK_SECRET` if it is set
+    #[arg(long, value_name = "URL", value_delimiter = ',')]
+    webhook: Vec<String>,
+
+    /// Attach each transmission's audio to its --webhook event
+    #[arg(long, requires = "webhook")]
+    webhook_audio: bool,
+
+    /// Directory holding --webhook deliveries waiting to be retried
+    #[arg(long, value_name = "DIR", default_value = "webhook-queue")]
+    webhook_queue: PathBuf,
+
+    /// Publish events to and take commands from the MQTT broker on this host,
+    /// logging in with `ZELLO_MQTT_USERNAME` and `ZELLO_MQTT_PASSWORD` if set
+    #[arg(long, value_name = "HOST")]
+    mqtt: Option<String>,
+
+    /// Port of the --mqtt broker
+    #[arg(long, value_name = "PORT", default_value_t = 1883)]
+    mqtt_port: u16,
+
+    /// Prefix of every --mqtt topic
+    #[arg(long, value_name = "PREFIX", default_value = "zello")]
+    mqtt_prefix: String,
+
+    /// Quality of service for --mqtt messages
+    #[arg(long, value_name = "QOS", default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=2))]
+    mqtt_qos: u8,
+
+    /// Mirror channel text to and from an IRC channel on this server, using
+    /// `ZELLO_IRC_PASSWORD` as the server password if set
+    #[arg(long, value_name = "HOST")]
+    irc: Option<String>,
+
+    /// Port of the --irc server
+    #[arg(long, value_name = "PORT", default_value_t = 6667)]
+    irc_port: u16,
+
+    /// IRC channel to mirror with --irc
+    #[arg(long, value_name = "CHANNEL", default_value = "#zello")]
+    irc_channel: String,
+
+    /// Nick of the --irc bridge
+    #[arg(long, value_name = "NICK", default_value = "zello")]
+    irc_nick: String,
+
+    /// Longest a transmission may last before it is stopped, in seconds
+    #[arg(long, value_name = "SECS")]
+    max_transmit: Option<u64>,
+
+    /// Time after a transmission times out before the next is allowed, in seconds
+    #[arg(long, value_name = "SECS", requires = "max_transmit")]
+    transmit_lockout: Option<u64>,
+
+    /// What a transmission does while someone else is talking
+    #[arg(long, value_enum, default_value_t = FloorMode::Wait)]
+    floor: FloorMode,
+
+    /// Longest to wait for the channel to clear with --floor wait, in seconds
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    floor_wait: u64,
+
+    /// Audio buffered before a transmission starts playing, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 120)]
+    target_latency: u64,
+
+    /// File holding per-user volume, mute and solo settings
+    #[arg(long, value_name = "PATH")]
+    volume_config: Option<PathBuf>,
+
+    /// Normalize the loudness of received audio
+    #[arg(long)]
+    agc: bool,
+
+    /// Loudness targeted by --agc, in dBFS
+    #[arg(long, value_name = "DBFS", default_value_t = -20.0, allow_negative_numbers = true)]
+    agc_target: f32,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum FloorMode {
+    /// Wait for the channel to clear
+    Wait,
+    /// Transmit anyway
+    Preempt,
+    /// Give up at once
+    FailFast,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the available audio input and output devices
+    Devices,
+    /// Transmit a WAV or Ogg/Opus file to the channel
+    SendAudio {
+        /// Audio file to transmit
+        file: PathBuf,
+    },
+    /// Relay audio and text between this channel and another
+    ///
+    /// The far side uses the same account and server unless the bridge
+    /// environment variables described in the README are set.
+    Bridge {
+        /// Channel to bridge to
+        channel: String,
+        /// Callsigns never to relay, such as other bridges
+        #[arg(long, value_name = "CALLSIGN", value_delimiter = ',')]
+        ignore: Vec<String>,
+        /// Relay audio only
+        #[arg(long, conflicts_with = "no_audio")]
+        no_text: bool,
+        /// Relay text only
+        #[arg(long)]
+        no_audio: bool,
+    },
+    /// Run as a gateway daemon that other services drive over HTTP
+    ///
+    /// Requests must carry the bearer token set in `ZELLO_GATEWAY_TOKEN`.
+    Gateway {
+        /// Address to serve the gateway API on
+        #[arg(long, value_name = "ADDR:PORT", default_value = "127.0.0.1:8088")]
+        listen: SocketAddr,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
+    if let Some(Command::Devices) = args.command {
+        println!("Audio output devices (* = default):");
+        println!("{}", format_device_list(&list_output_devices()?));
+        println!("Audio input devices (* = default):");
+        println!("{}", format_device_list(&list_input_devices()?));
+        return Ok(());
+    }
+
     load_dotenv()?;
     initialize_logging()?;
 
     let credentials = load_credentials()?;
+
+    let limits = TransmitLimits {
+        max_duration: args.max_transmit.map(Duration::from_secs),
+        lockout: args.transmit_lockout.map(Duration::from_secs),
+    };
+    let floor_policy = match args.floor {
+        FloorMode::Wait => FloorPolicy::WaitForClear(Duration::from_secs(args.floor_wait)),
+        FloorMode::Preempt => FloorPolicy::Preempt,
+        FloorMode::FailFast => FloorPolicy::FailFast,
+    };
+
+    if let Some(Command::Bridge {
+        channel,
+        ignore,
+        no_text,
+        no_audio,
+    }) = args.command
+    {
+        let far = load_bridge_credentials(&credentials, channel);
+        let config = BridgeConfig {
+            relay_text: !no_text,
+            relay_audio: !no_audio,
+            ignore: ignore.into_iter().collect(),
+            ..BridgeConfig::default()
+        };
+        return bridge(&credentials, &far, config, &limits).await;
+    }
+
+    if let Some(Command::Gateway { listen }) = args.command {
+        let config = GatewayConfig::new(load_gateway_token()?);
+        let mut client = connect_to_zello(&credentials).await?;
+        client.set_transmit_limits(limits);
+        client.set_floor_policy(floor_policy);
+        return gateway(client, listen, config).await;
+    }
+
+    if let Some(Command::SendAudio { file }) = args.command {
+        let mut client = connect_to_zello(&credentials).await?;
+        client.set_transmit_limits(limits);
+        client.set_floor_policy(floor_policy);
+        client.transmit_file(&file).await?;
+        client.close().await?;
+        return Ok(());
+    }
     let decoder = create_decoder()?;
 
     let (pcm_tx, pcm_rx) = bounded::<Vec<i16>>(PCM_CHANNEL_CAPACITY);
     let pcm_rx = Arc::new(Mutex::new(pcm_rx));
-    let _stream = setup_audio_output(pcm_rx)?;
+    let playback = PlaybackConfig {
+        target_latency: Duration::from_millis(args.target_latency),
+        ..PlaybackConfig::default()
+    };
+    let output = setup_audio_output_with_config(pcm_rx, &args.output_device, playback)?;
 
     let mut client = connect_to_zello(&credentials).await?;
+    client.set_transmit_limits(limits);
+    client.set_floor_policy(floor_policy);
+    client.set_playback_stats(Some(output.stats()));
+    if let Some(path) = &args.volume_config {
+        client.set_volume(VolumeControl::with_config_file(path)?);
+    }
+    if args.agc {
+        client.set_agc(Some(AgcConfig {
+            target_level_dbfs: args.agc_target,
+            ..AgcConfig::default()
+        }));
+    }
 
-    match (args.message, args.callsign) {
+    match (&args.message, &args.callsign) {
         (Some(msg), Some(callsign)) => {
-            client
-                .send_text_message_to_callsign(&msg, &callsign)
-                .await?;
+            client.send_text_message_to_callsign(msg, callsign).await?;
         }
         (Some(msg), None) => {
-            client.send_text_message(&msg).await?;
+            client.send_text_message(msg).await?;
         }
         (None, _) => {
+            let _input = start_transmitters(&mut client, &args).await?;
             client.run_message_loop(decoder, &pcm_tx).await?;
         }
     }
 
     client.close().await?;
 
+    let stats = output.stats();
+    info!(
+        "Playback: {} underrun(s), {} overrun(s)",
+        stats.underruns(),
+        stats.overruns()
+    );
+
+    Ok(())
+}
+
+/// Connect to both channels and relay between them until either disconnects
+async fn bridge(
+    near: &Credentials,
+    far: &Credentials,
+    config: BridgeConfig,
+    limits: &TransmitLimits,
+) -> Result<()> {
+    let mut near = connect_to_zello(near).await?;
+    let mut far = connect_to_zello(far).await?;
+
+    // Audio is relayed as Opus packets, so neither side needs to decode it
+    for client in [&mut near, &mut far] {
+        client.set_transmit_limits(limits.clone());
+        let muted = VolumeControl::default();
+        muted.set_master_gain(0.0)?;
+        client.set_volume(muted);
+    }
+    let near_end = BridgeEnd::new(&mut near);
+    let far_end = BridgeEnd::new(&mut far);
+
+    let decoder = create_decoder()?;
+    // Without a receiver, any audio handed to playback is discarded at once
+    let (pcm_tx, pcm_rx) = bounded::<Vec<i16>>(0);
+    drop(pcm_rx);
+    tokio::select! {
+        result = near.run_message_loop(decoder.clone(), &pcm_tx) => result?,
+        result = far.run_message_loop(decoder, &pcm_tx) => result?,
+        result = run_bridge(near_end, far_end, config) => result?,
+    }
+
+    near.close().await?;
+    far.close().await?;
+    Ok(())
+}
+
+/// Serve the gateway API until the client disconnects
+async fn gateway(mut client: ZelloClient, listen: SocketAddr, config: GatewayConfig) -> Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    let gateway = Gateway::new(&mut client);
+
+    // Nothing is played, so there is no need to decode received audio
+    let muted = VolumeControl::default();
+    muted.set_master_gain(0.0)?;
+    client.set_volume(muted);
+
+    let decoder = create_decoder()?;
+    // Without a receiver, any audio handed to playback is discarded at once
+    let (pcm_tx, pcm_rx) = bounded::<Vec<i16>>(0);
+    drop(pcm_rx);
+    tokio::select! {
+        result = client.run_message_loop(decoder, &pcm_tx) => result?,
+        result = serve_gateway(listener, gateway, config) => result?,
+    }
+
+    client.close().await?;
+    Ok(())
+}
+
+/// Start whatever transmits into the channel while the message loop runs
+///
+/// Returns the audio input, if any, which must be kept alive.
+async fn start_transmitters(client: &mut ZelloClient, args: &Args) -> Result<Option<AudioInput>> {
+    let vox = VoxConfig {
+        threshold_dbfs: args.vox_threshold,
+        hang_time: Duration::from_millis(args.vox_hang),
+        ..VoxConfig::default()
+    };
+    let input = if args.ptt {
+        Some(start_push_to_talk(client, &args.input_device)?)
+    } else if args.vox {
+        Some(start_vox(client, &args.input_device, vox.clone())?)
+    } else {
+        None
+    };
+
+    if args.parrot {
+        start_parrot(client, args.parrot_allow.clone(), args.parrot_max_clip);
+    }
+
+    let rtp = RtpConfig {
+        opus_payload_type: args.rtp_payload_type,
+        ..RtpConfig::default()
+    };
+    start_rtp(client, args.rtp_send, args.rtp_listen, &rtp).await?;
+
+    start_servers(client, args).await?;
+    if let Some(target) = &args.pcm_out {
+        start_pcm_out(client, target.clone());
+    }
+    if let Some(source) = &args.pcm_in {
+        start_pcm_in(client, source.clone(), args.pcm_in_rate, vox);
+    }
+
+    Ok(input)
+}
+
+/// Start microphone capture and the push-to-talk controls
+fn start_push_to_talk(client: &ZelloClient, device: &DeviceSelector) -> Result<AudioInput> {
+    let (capture_tx, capture_rx) = mpsc::channel(PCM_CHANNEL_CAPACITY);
+    let input = setup_audio_input(device, capture_tx)?;
+    let ptt = PttControl::new();
+
+    tokio::spawn(ptt_from_keyboard(ptt.clone()));
+    #[cfg(unix)]
+    tokio::spawn(ptt_from_signals(ptt.clone()));
+
+    let handle = client.handle();
+    tokio::spawn(async move {
+        if let Err(e) = transmit_with_ptt(handle, ptt, capture_rx, EncoderConfig::default()).await {
+            error!("Push-to-talk stopped: {e}");
+        }
+    });
+
+    info!("Push-to-talk ready: press Enter to start and stop talking");
+    Ok(input)
+}
+
+/// Start capture and voice-operated transmission
+fn start_vox(client: &ZelloClient, device: &DeviceSelector, vox: VoxConfig) -> Result<AudioInput> {
+    let (capture_tx, capture_rx) = mpsc::channel(PCM_CHANNEL_CAPACITY);
+    let input = setup_audio_input(device, capture_tx)?;
+
+    let handle = client.handle();
+    tokio::spawn(async move {
+        if let Err(e) = transmit_with_vox(handle, capture_rx, vox, EncoderConfig::default()).await {
+            error!("VOX stopped: {e}");
+        }
+    });
+
+    info!("VOX ready: transmitting whenever the input is loud enough");
+    Ok(input)
+}
+
+/// Repeat transmissions back into the channel while the message loop runs
+fn start_parrot(client: &mut ZelloClient, allowlist: Vec<String>, max_clip: u64) {
+    let config = ParrotConfig {
+        allowlist: allowlist.into_iter().collect(),
+        max_clip: Duration::from_secs(max_clip),
+        ..ParrotConfig::default()
+    };
+    let transmissions = client.inbound_transmissions();
+    let handle = client.handle();
+    tokio::spawn(async move {
+        if let Err(e) = run_parrot(handle, transmissions, config).await {
+            error!("Parrot stopped: {e}");
+        }
+    });
+
+    info!("Parrot ready: repeating each transmission after it ends");
+}
+
+/// Bridge the channel to RTP equipment while the message loop runs
+async fn start_rtp(
+    client: &mut ZelloClient,
+    send: Option<SocketAddr>,
+    listen: Option<SocketAddr>,
+    config: &RtpConfig,
+) -> Result<()> {
+    if let Some(destination) = send {
+        let local: SocketAddr = if destination.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        let transmissions = client.inbound_transmissions();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = send_rtp(transmissions, socket, destination, &config).await {
+                error!("RTP sender stopped: {e}");
+            }
+        });
+        info!("Sending transmissions to {destination} as RTP");
+    }
+
+    if let Some(address) = listen {
+        let socket = UdpSocket::bind(address).await?;
+        let handle = client.handle();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = receive_rtp(handle, socket, config).await {
+                error!("RTP receiver stopped: {e}");
+            }
+        });
+        info!("Transmitting RTP audio received on {address}");
+    }
+    Ok(())
+}
+
+/// Start the HTTP audio stream, dashboard, webhooks and MQTT and IRC bridges, if enabled
+async fn start_servers(client: &mut ZelloClient, args: &Args) -> Result<()> {
+    if let Some(address) = args.http_stream {
+        let listener = TcpListener::bind(address).await?;
+        let transmissions = client.inbound_transmissions();
+        let config = OggStreamConfig {
+            name: client.channel().to_string(),
+            ..OggStreamConfig::default()
+        };
+        tokio::spawn(async move {
+            if let Err(e) = serve_ogg_stream(listener, transmissions, config).await {
+                error!("HTTP stream stopped: {e}");
+            }
+        });
+    }
+
+    if let Some(address) = args.dashboard {
+        let listener = TcpListener::bind(address).await?;
+        let dashboard = Dashboard::new(client);
+        tokio::spawn(async move {
+            if let Err(e) = serve_dashboard(listener, dashboard).await {
+                error!("Dashboard stopped: {e}");
+            }
+        });
+    }
+
+    if !args.webhook.is_empty() {
+        let config = WebhookConfig {
+            secret: load_webhook_secret(),
+            attach_audio: args.webhook_audio,
+            ..WebhookConfig::new(args.webhook.clone(), &args.webhook_queue)
+        };
+        let events = client.channel_events();
+        let transmissions = args.webhook_audio.then(|| client.inbound_transmissions());
+        info!("Sending events to {} webhooks", args.webhook.len());
+        tokio::spawn(async move {
+            if let Err(e) = run_webhooks(events, transmissions, config).await {
+                error!("Webhooks stopped: {e}");
+            }
+        });
+    }
+
+    if let Some(host) = &args.mqtt {
+        let config = MqttConfig {
+            credentials: load_mqtt_credentials(),
+            topic_prefix: args.mqtt_prefix.clone(),
+            qos: rumqttc::qos(args.mqtt_qos)?,
+            ..MqttConfig::new(host, args.mqtt_port)
+        };
+        let bridge = MqttBridge::new(client);
+        tokio::spawn(async move {
+            if let Err(e) = run_mqtt_bridge(bridge, config).await {
+                error!("MQTT bridge stopped: {e}");
+            }
+        });
+    }
+
+    if let Some(host) = &args.irc {
+        let config = IrcConfig {
+            password: load_irc_password(),
+            ..IrcConfig::new(host, args.irc_port, &args.irc_nick, &args.irc_channel)
+        };
+        let bridge = IrcBridge::new(client);
+        tokio::spawn(async move {
+            if let Err(e) = run_irc_bridge(bridge, config).await {
+                error!("IRC bridge stopped: {e}");
+            }
+        });
+    }
     Ok(())
 }
+
+/// Write channel audio as raw PCM while the message loop runs
+fn start_pcm_out(client: &mut ZelloClient, target: PcmEndpoint) {
+    let transmissions = client.inbound_transmissions();
+    info!("Writing channel audio to {target}");
+    tokio::spawn(async move {
+        if let Err(e) = write_pcm(transmissions, target).await {
+            error!("PCM output stopped: {e}");
+        }
+    });
+}
+
+/// Transmit raw PCM, starting and stopping on silence
+fn start_pcm_in(client: &ZelloClient, source: PcmEndpoint, sample_rate: u32, vox: VoxConfig) {
+    let (pcm_tx, pcm_rx) = mpsc::channel(PCM_CHANNEL_CAPACITY);
+    tokio::spawn(async move {
+        if let Err(e) = read_pcm(source, sample_rate, pcm_tx).await {
+            error!("PCM input stopped: {e}");
+        }
+    });
+
+    let handle = client.handle();
+    tokio::spawn(async move {
+        if let Err(e) = transmit_with_vox(handle, pcm_rx, vox, EncoderConfig::default()).await {
+            error!("PCM transmitter stopped: {e}");
+        }
+    });
+}
+
+/// Toggle push-to-talk each time Enter is pressed
+async fn ptt_from_keyboard(ptt: PttControl) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(_)) = lines.next_line().await {
+        ptt.toggle();
+    }
+}
+
+/// Press push-to-talk on SIGUSR1 and release it on SIGUSR2
+#[cfg(unix)]
+async fn ptt_from_signals(ptt: PttControl) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let (Ok(mut press), Ok(mut release)) = (
+        signal(SignalKind::user_defined1()),
+        signal(SignalKind::user_defined2()),
+    ) else {
+        error!("Push-to-talk signals are unavailable");
+        return;
+    };
+
+    loop {
+        tokio::select! {
+            Some(()) = press.recv() => ptt.press(),
+            Some(()) = release.recv() => ptt.release(),
+            else => break,
+        }
+    }
+}
@@ -0,0 +1,297 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Bridge that relays audio and text between two client sessions
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::client::{ClientHandle, ZelloClient};
+use crate::error::{Result, ZelloError};
+use crate::floor::FloorPolicy;
+use crate::inbound::{InboundText, InboundTransmission};
+use crate::pacing::PacedPackets;
+use crate::parrot::record_transmission;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Settings for a bridge
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    /// Relay text messages
+    pub relay_text: bool,
+    /// Relay audio transmissions
+    pub relay_audio: bool,
+    /// Callsigns that are never relayed, such as other bridges; the bridge's
+    /// own accounts are always ignored
+    pub ignore: HashSet<String>,
+    /// Longest transmission held while the far side is busy
+    pub max_queued_clip: Duration,
+    /// Longest a held transmission waits for the far side to clear
+    pub max_wait: Duration,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        Self {
+            relay_text: true,
+            relay_audio: true,
+            ignore: HashSet::new(),
+            max_queued_clip: Duration::from_mins(1),
+            max_wait: Duration::from_mins(2),
+        }
+    }
+}
+
+/// One side of a bridge, taken from a connected client
+#[derive(Debug)]
+pub struct BridgeEnd {
+    channel: String,
+    username: Option<String>,
+    handle: ClientHandle,
+    transmissions: mpsc::UnboundedReceiver<InboundTransmission>,
+    texts: mpsc::UnboundedReceiver<InboundText>,
+}
+
+impl BridgeEnd {
+    /// Subscribe to `client`'s audio and text
+    ///
+    /// The client's message loop must be run for the bridge to work.
+    pub fn new(client: &mut ZelloClient) -> Self {
+        Self {
+            channel: client.channel().to_string(),
+            username: client.username().map(str::to_string),
+            handle: client.handle(),
+            transmissions: client.inbound_transmissions(),
+            texts: client.text_messages(),
+        }
+    }
+}
+
+/// Relay audio and text between two clients until either stops
+///
+/// Transmissions are forwarded packet by packet without transcoding. If the
+/// far side is busy a transmission is held until it clears. Text messages
+/// are prefixed with the original sender's name. Nothing sent by either of
+/// the bridge's accounts is relayed, so the bridge cannot feed itself.
+///
+/// # Errors
+///
+/// Returns an error if either client stops
+pub async fn run_bridge(a: BridgeEnd, b: BridgeEnd, config: BridgeConfig) -> Result<()> {
+    let mut ignore = config.ignore.clone();
+    ignore.extend(a.username.iter().chain(&b.username).cloned());
+    info!("Bridging {} and {}", a.channel, b.channel);
+
+    let a_to_b = Relay {
+        to: b.handle.clone(),
+        label: format!("{} → {}", a.channel, b.channel),
+        ignore: &ignore,
+        config: &config,
+    };
+    let b_to_a = Relay {
+        to: a.handle.clone(),
+        label: format!("{} → {}", b.channel, a.channel),
+        ignore: &ignore,
+        config: &config,
+    };
+
+    tokio::select! {
+        result = a_to_b.audio(a.transmissions) => result,
+        result = b_to_a.audio(b.transmissions) => result,
+        result = a_to_b.text(a.texts) => result,
+        result = b_to_a.text(b.texts) => result,
+    }
+}
+
+/// One direction of a bridge
+struct Relay<'a> {
+    to: ClientHandle,
+    label: String,
+    ignore: &'a HashSet<String>,
+    config: &'a BridgeConfig,
+}
+
+impl Relay<'_> {
+    async fn audio(
+        &self,
+        mut transmissions: mpsc::UnboundedReceiver<InboundTransmission>,
+    ) -> Result<()> {
+        if !self.config.relay_audio {
+            return std::future::pending().await;
+        }
+
+        while let Some(transmission) = transmissions.recv().await {
+            let from = transmission.info().from.clone();
+            if self.ignore.contains(&from) {
+                debug!("[{}] Not relaying audio from {from}", self.label);
+                continue;
+            }
+
+            match self.transmission(transmission).await {
+                Ok(()) => {}
+                Err(ZelloError::NotConnected) => return Err(ZelloError::NotConnected),
+                Err(e) => warn!("[{}] Failed to relay audio from {from}: {e}", self.label),
+            }
+        }
+        Err(ZelloError::NotConnected)
+    }
+
+    /// Relay a transmission live, or hold it until the far side is clear
+    async fn transmission(&self, mut transmission: InboundTransmission) -> Result<()> {
+        let info = transmission.info().clone();
+        let mut live = self.to.clone();
+        live.set_floor_policy(FloorPolicy::FailFast);
+
+        match live
+            .start_audio_stream(
+                &info.codec,
+                Some(info.codec_header.clone()),
+                info.packet_duration,
+            )
+            .await
+        {
+            Ok(mut stream) => {
+                info!("[{}] 🔁 Relaying {}", self.label, info.from);
+                // Packets may have queued up behind an earlier transmission
+                let mut packets =
+                    PacedPackets::live(stream.stream_id(), info.effective_packet_duration());
+                while let Some(packet) = transmission.next_packet().await {
+                    packets.tick().await;
+                    stream.send(packet.data)?;
+                }
+                packets.finish();
+                stream.finish().await
+            }
+            Err(ZelloError::ChannelBusy(talker)) => {
+                info!(
+                    "[{}] {talker} is talking, holding {} until the channel clears",
+                    self.label, info.from
+                );
+                let audio = record_transmission(transmission, self.config.max_queued_clip).await;
+                let mut queued = self.to.clone();
+                queued.set_floor_policy(FloorPolicy::WaitForClear(self.config.max_wait));
+                queued.transmit_audio(audio).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn text(&self, mut texts: mpsc::UnboundedReceiver<InboundText>) -> Result<()> {
+        if !self.config.relay_text {
+            return std::future::pending().await;
+        }
+
+        while let Some(text) = texts.recv().await {
+            let Some(relayed) = relayed_text(&text, self.ignore) else {
+                continue;
+            };
+            match self.to.send_text_message(&relayed).await {
+                Ok(()) => {}
+                Err(ZelloError::NotConnected) => return Err(ZelloError::NotConnected),
+                Err(e) => warn!("[{}] Failed to relay text: {e}", self.label),
+            }
+        }
+        Err(ZelloError::NotConnected)
+    }
+}
+
+/// The text to relay for a channel message, prefixed with the sender's name
+///
+/// Private messages and messages from ignored callsigns are not relayed.
+fn relayed_text(text: &InboundText, ignore: &HashSet<String>) -> Option<String> {
+    if text.for_user.is_some() || ignore.contains(&text.from) {
+        return None;
+    }
+    Some(format!("[{}] {}", text.display_name(), text.text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientCommand;
+    use crate::floor::FloorState;
+    use crate::inbound::InboundPacket;
+    use crate::inbound::tests::info;
+    use tokio::sync::{broadcast, watch};
+
+    fn text(from: &str, for_user: Option<&str>) -> InboundText {
+        InboundText {
+            channel: "channel".to_string(),
+            from: from.to_string(),
+            author: None,
+            for_user: for_user.map(str::to_string),
+            text: "hello".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_relayed_text() {
+        let ignore = HashSet::from(["bridge".to_string()]);
+
+        assert_eq!(
+            relayed_text(&text("alice", None), &ignore).as_deref(),
+            Some("[alice] hello")
+        );
+        assert_eq!(relayed_text(&text("bridge", None), &ignore), None);
+        assert_eq!(relayed_text(&text("alice", Some("bob")), &ignore), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_relays_packets_live() {
+        let (commands, mut received) = mpsc::unbounded_channel();
+        let (_floor, floor_rx) = watch::channel(FloorState::Clear);
+        let to = ClientHandle::new(commands, broadcast::channel(1).0, floor_rx);
+        let config = BridgeConfig::default();
+        let ignore = HashSet::new();
+        let relay = Relay {
+            to,
+            label: "a → b".to_string(),
+            ignore: &ignore,
+            config: &config,
+        };
+
+        let (packets_tx, transmission) = InboundTransmission::new(info());
+        for packet_id in 0..3 {
+            packets_tx
+                .send(InboundPacket {
+                    packet_id,
+                    data: vec![7],
+                })
+                .expect("send");
+        }
+        drop(packets_tx);
+
+        let far_side = tokio::spawn(async move {
+            let mut sent = Vec::new();
+            while let Some(command) = received.recv().await {
+                match command {
+                    ClientCommand::StartStream { reply, .. } => {
+                        reply.send(Ok(9)).expect("reply");
+                    }
+                    ClientCommand::SendAudioPacket {
+                        stream_id: 9,
+                        packet_id,
+                        data,
+                    } => sent.push((packet_id, data)),
+                    ClientCommand::StopStream {
+                        stream_id: 9,
+                        reply: Some(reply),
+                    } => {
+                        reply.send(Ok(())).expect("reply");
+                        return sent;
+                    }
+                    other => panic!("unexpected command {other:?}"),
+                }
+            }
+            sent
+        });
+
+        let start = tokio::time::Instant::now();
+        relay.transmission(transmission).await.expect("relay");
+        let sent = far_side.await.expect("join");
+        assert_eq!(sent, [(0, vec![7]), (1, vec![7]), (2, vec![7])]);
+        // The queued packets are sent at the rate they play, not in a burst
+        assert_eq!(start.elapsed(), Duration::from_millis(120));
+    }
+}
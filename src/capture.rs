@@ -0,0 +1,311 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Microphone capture, converting device audio to 16kHz mono PCM
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::utilities::{DeviceSelector, format_rank, get_input_device};
+use crate::{CPAL_SAMPLE_RATE, PCM_I16_TO_F32};
+use anyhow::{Result, anyhow};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{Device, FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
+use rtrb::{Consumer, Producer, RingBuffer};
+use rubato::{
+    Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+/// Samples of 16kHz PCM in each chunk delivered by the capture (20ms)
+pub const CAPTURE_CHUNK_SIZE: usize = 320;
+
+/// Input frames per resampler call
+const RESAMPLER_CHUNK_SIZE: usize = 480;
+
+/// Duration of device audio the capture ring buffer holds
+const CAPTURE_RING_DURATION: Duration = Duration::from_secs(1);
+
+/// How long the conversion thread sleeps when no audio is waiting
+const CAPTURE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Converts mono device audio to 16kHz PCM
+pub struct CaptureConverter {
+    resampler: Option<SincFixedIn<f32>>,
+    pending: Vec<f32>,
+    resampled: Vec<Vec<f32>>,
+}
+
+impl fmt::Debug for CaptureConverter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaptureConverter")
+            .field("resampling", &self.resampler.is_some())
+            .field("pending", &self.pending.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl CaptureConverter {
+    /// Create a converter for mono audio captured at `sample_rate`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the resampler cannot be created for this rate
+    pub fn new(sample_rate: u32) -> Result<Self> {
+        if sample_rate == CPAL_SAMPLE_RATE.0 {
+            return Ok(Self {
+                resampler: None,
+                pending: Vec::new(),
+                resampled: Vec::new(),
+            });
+        }
+
+        let parameters = SincInterpolationParameters {
+            sinc_len: 128,
+            f_cutoff: 0.95,
+            oversampling_factor: 256,
+            interpolation: SincInterpolationType::Linear,
+            window: WindowFunction::BlackmanHarris2,
+        };
+        let ratio = f64::from(CPAL_SAMPLE_RATE.0) / f64::from(sample_rate);
+        let resampler = SincFixedIn::<f32>::new(ratio, 1.0, parameters, RESAMPLER_CHUNK_SIZE, 1)?;
+        let frames = vec![vec![0.0; resampler.output_frames_max()]];
+
+        Ok(Self {
+            resampler: Some(resampler),
+            pending: Vec::with_capacity(RESAMPLER_CHUNK_SIZE * 4),
+            resampled: frames,
+        })
+    }
+
+    /// Convert captured samples, appending 16kHz PCM to `output`
+    ///
+    /// Input that does not fill a whole resampler chunk is held until the next call.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<i16>) {
+        let Some(resampler) = &mut self.resampler else {
+            output.extend(input.iter().copied().map(to_i16));
+            return;
+        };
+
+        self.pending.extend_from_slice(input);
+        let mut consumed = 0;
+        while self.pending.len() - consumed >= resampler.input_frames_next() {
+            let chunk = [&self.pending[consumed..]];
+            match resampler.process_into_buffer(&chunk, &mut self.resampled, None) {
+                Ok((frames_in, frames_out)) => {
+                    output.extend(self.resampled[0][..frames_out].iter().copied().map(to_i16));
+                    consumed += frames_in;
+                }
+                Err(_) => {
+                    consumed = self.pending.len();
+                }
+            }
+        }
+        self.pending.drain(..consumed);
+    }
+}
+
+fn to_i16(value: f32) -> i16 {
+    // Truncation is intended: the value is clamped to the i16 range
+    #[allow(clippy::cast_possible_truncation)]
+    let sample = (value / PCM_I16_TO_F32).clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16;
+    sample
+}
+
+/// Negotiate an input stream configuration supported by the device
+///
+/// 16kHz is used when the device supports it, otherwise the device's default
+/// configuration; the captured audio is resampled either way.
+///
+/// # Errors
+///
+/// Returns an error if the device configurations cannot be queried
+pub fn negotiate_input_config(device: &Device) -> Result<(StreamConfig, SampleFormat)> {
+    let supported = device
+        .supported_input_configs()?
+        .filter(|c| {
+            format_rank(c.sample_format()).is_some()
+                && c.min_sample_rate() <= CPAL_SAMPLE_RATE
+                && CPAL_SAMPLE_RATE <= c.max_sample_rate()
+        })
+        .min_by_key(|c| (c.channels(), format_rank(c.sample_format())))
+        .map(|c| c.with_sample_rate(CPAL_SAMPLE_RATE));
+
+    let supported = match supported {
+        Some(config) => config,
+        None => device.default_input_config()?,
+    };
+
+    if format_rank(supported.sample_format()).is_none() {
+        return Err(anyhow!(
+            "Unsupported input sample format: {}",
+            supported.sample_format()
+        ));
+    }
+
+    Ok((supported.config(), supported.sample_format()))
+}
+
+/// A running microphone capture stream
+pub struct AudioInput {
+    stream: Stream,
+}
+
+impl fmt::Debug for AudioInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AudioInput").finish_non_exhaustive()
+    }
+}
+
+impl AudioInput {
+    /// The underlying cpal stream
+    pub fn stream(&self) -> &Stream {
+        &self.stream
+    }
+}
+
+/// Setup microphone capture
+///
+/// Captured audio is delivered on `pcm_tx` as 20ms chunks of 16kHz mono PCM.
+/// Chunks are dropped if the receiver falls behind. Capture stops when the
+/// returned [`AudioInput`] is dropped.
+///
+/// # Errors
+///
+/// Returns an error if the device cannot be found or the stream cannot be started
+pub fn setup_audio_input(
+    device: &DeviceSelector,
+    pcm_tx: mpsc::Sender<Vec<i16>>,
+) -> Result<AudioInput> {
+    let device = get_input_device(device)?;
+    let (stream_config, sample_format) = negotiate_input_config(&device)?;
+
+    info!(
+        "Audio input: {} ({} Hz, {} channel(s), {sample_format})",
+        device.name().unwrap_or_default(),
+        stream_config.sample_rate.0,
+        stream_config.channels
+    );
+
+    let capacity =
+        usize::try_from(u64::from(stream_config.sample_rate.0) * CAPTURE_RING_DURATION.as_secs())?;
+    let (producer, consumer) = RingBuffer::<f32>::new(capacity);
+    let converter = CaptureConverter::new(stream_config.sample_rate.0)?;
+    spawn_capture_converter(consumer, converter, pcm_tx)?;
+
+    let stream = match sample_format {
+        SampleFormat::F32 => build_input_stream::<f32>(&device, &stream_config, producer)?,
+        SampleFormat::I16 => build_input_stream::<i16>(&device, &stream_config, producer)?,
+        SampleFormat::I32 => build_input_stream::<i32>(&device, &stream_config, producer)?,
+        SampleFormat::U16 => build_input_stream::<u16>(&device, &stream_config, producer)?,
+        SampleFormat::F64 => build_input_stream::<f64>(&device, &stream_config, producer)?,
+        SampleFormat::U8 => build_input_stream::<u8>(&device, &stream_config, producer)?,
+        SampleFormat::I8 => build_input_stream::<i8>(&device, &stream_config, producer)?,
+        format => return Err(anyhow!("Unsupported input sample format: {format}")),
+    };
+
+    stream.play()?;
+    Ok(AudioInput { stream })
+}
+
+/// Convert captured audio to 16kHz chunks on its own thread
+fn spawn_capture_converter(
+    mut consumer: Consumer<f32>,
+    mut converter: CaptureConverter,
+    pcm_tx: mpsc::Sender<Vec<i16>>,
+) -> Result<()> {
+    std::thread::Builder::new()
+        .name("zello-capture".to_string())
+        .spawn(move || {
+            let mut input = Vec::new();
+            let mut pcm = Vec::with_capacity(CAPTURE_CHUNK_SIZE * 4);
+
+            while !pcm_tx.is_closed() {
+                let available = consumer.slots();
+                if available == 0 {
+                    if consumer.is_abandoned() {
+                        break;
+                    }
+                    std::thread::sleep(CAPTURE_POLL_INTERVAL);
+                    continue;
+                }
+
+                input.clear();
+                if let Ok(chunk) = consumer.read_chunk(available) {
+                    input.extend(chunk);
+                }
+                converter.process(&input, &mut pcm);
+
+                while pcm.len() >= CAPTURE_CHUNK_SIZE {
+                    let chunk: Vec<i16> = pcm.drain(..CAPTURE_CHUNK_SIZE).collect();
+                    // Drop audio rather than block if the transmitter falls behind
+                    let _ = pcm_tx.try_send(chunk);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Build an input stream consuming samples of type `T`, mixed down to mono
+fn build_input_stream<T>(
+    device: &Device,
+    stream_config: &StreamConfig,
+    mut producer: Producer<f32>,
+) -> Result<Stream>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let err_fn = |err| error!("Stream error: {err:?}");
+    let channels = usize::from(stream_config.channels.max(1));
+    #[allow(clippy::cast_precision_loss)]
+    let scale = 1.0 / channels as f32;
+
+    let stream = device.build_input_stream(
+        stream_config,
+        move |input: &[T], _: &cpal::InputCallbackInfo| {
+            for frame in input.chunks_exact(channels) {
+                let sum: f32 = frame.iter().map(|&s| s.to_sample::<f32>()).sum();
+                // Drop audio rather than block if the converter falls behind
+                let _ = producer.push(sum * scale);
+            }
+        },
+        err_fn,
+        None,
+    )?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_at_zello_rate_is_unchanged() {
+        let mut converter = CaptureConverter::new(16000).expect("converter");
+        let mut output = Vec::new();
+        converter.process(&[0.5, -0.5, 0.0], &mut output);
+        assert_eq!(output, [16384, -16384, 0]);
+    }
+
+    #[test]
+    fn test_capture_is_resampled_to_zello_rate() {
+        let mut converter = CaptureConverter::new(48000).expect("converter");
+        let mut output = Vec::new();
+        for _ in 0..100 {
+            converter.process(&[0.0; 480], &mut output);
+        }
+        // One second at 48kHz becomes one second at 16kHz, less the resampler delay
+        let delay = converter
+            .resampler
+            .as_ref()
+            .expect("resampler")
+            .output_delay();
+        assert!(
+            (output.len() + delay).abs_diff(16000) <= 8,
+            "{}",
+            output.len()
+        );
+    }
+}
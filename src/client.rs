@@ -3,19 +3,36 @@
 
 //! Zello client implementation
 
+use crate::agc::{AgcConfig, AutomaticGainControl};
+use crate::audio_file::{EncodedAudio, load_audio_file};
+use crate::encoder::{EncoderConfig, OPUS_CODEC};
 use crate::error::{Result, ZelloError};
+use crate::floor::{FloorPolicy, FloorState, FloorTracker, acquire_floor, busy_error};
 use crate::handlers::handle_message;
+use crate::inbound::{InboundPacket, InboundText, InboundTransmission, TransmissionInfo};
+use crate::message::CodecHeader;
 use crate::message::IncomingMessage;
+use crate::message::Location;
 use crate::message::Message;
 use crate::message::Response;
+use crate::monitor::{ChannelEvent, ChannelMonitor, ChannelSnapshot};
+use crate::outbound::{OutboundStream, OutboundStreamHandle};
+use crate::pacing::PacedPackets;
+use crate::playback::PlaybackStats;
 use crate::protocol::Protocol;
+use crate::tot::{TransmitLimits, TransmitTimer};
+use crate::volume::VolumeControl;
 use audiopus::coder::Decoder;
 use crossbeam_channel::Sender;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::time::{Duration, timeout};
-use tracing::{debug, error, info};
+use tokio::sync::{Mutex, broadcast, mpsc, oneshot, watch};
+use tokio::time::{Duration, Instant, sleep_until, timeout, timeout_at};
+use tracing::{debug, error, info, warn};
+
+/// Transmit events held for slow subscribers
+const TRANSMIT_EVENT_CAPACITY: usize = 16;
 
 /// Configuration for Zello client
 #[derive(Debug, Clone)]
@@ -28,6 +45,8 @@ pub struct ZelloConfig {
     pub channel: String,
     /// Optional authentication token (alternative to username/password)
     pub auth_token: Option<String>,
+    /// WebSocket URL of the server, if not the default
+    pub server_url: Option<String>,
 }
 
 impl ZelloConfig {
@@ -39,9 +58,17 @@ impl ZelloConfig {
             password: Some(password),
             channel,
             auth_token: Some(auth_token),
+            server_url: None,
         }
     }
 
+    /// Connect to the server at `url` instead of the default
+    #[must_use]
+    pub fn with_server_url(mut self, url: impl Into<String>) -> Self {
+        self.server_url = Some(url.into());
+        self
+    }
+
     /// Validate the configuration
     ///
     /// #Errors
@@ -82,6 +109,21 @@ pub struct ZelloClient {
     active_streams: HashMap<u32, StreamInfo>,
     active_inbound_streams: HashMap<u32, StreamInfo>,
     refresh_token: String,
+    volume: VolumeControl,
+    agc: Option<AutomaticGainControl>,
+    playback: Option<Arc<PlaybackStats>>,
+    pending: VecDeque<IncomingMessage>,
+    commands_tx: mpsc::UnboundedSender<ClientCommand>,
+    commands_rx: Option<mpsc::UnboundedReceiver<ClientCommand>>,
+    transmit_timer: TransmitTimer,
+    transmit_events: broadcast::Sender<TransmitEvent>,
+    abandoned_streams: Vec<u32>,
+    floor: FloorTracker,
+    floor_policy: FloorPolicy,
+    monitor: ChannelMonitor,
+    transmission_subscribers: Vec<mpsc::UnboundedSender<InboundTransmission>>,
+    text_subscribers: Vec<mpsc::UnboundedSender<InboundText>>,
+    inbound_transmissions: HashMap<u32, Vec<mpsc::UnboundedSender<InboundPacket>>>,
 }
 
 /// Attributes of a Zello stream
@@ -90,6 +132,227 @@ pub struct StreamInfo {
     pub channel: String,
     pub codec: String,
     pub callsign: Option<String>,
+    /// Number of audio packets sent on an outbound stream
+    pub packets_sent: u32,
+}
+
+/// Requests made to a running client through a [`ClientHandle`]
+#[derive(Debug)]
+pub enum ClientCommand {
+    /// Start an outbound audio stream
+    StartStream {
+        codec: String,
+        codec_header: Option<CodecHeader>,
+        packet_duration: u32,
+        floor_policy: FloorPolicy,
+        reply: oneshot::Sender<Result<u32>>,
+    },
+    /// Send an audio packet on an outbound stream
+    SendAudioPacket {
+        stream_id: u32,
+        packet_id: u32,
+        data: Vec<u8>,
+    },
+    /// Send a text message to the channel, or to one user on it
+    SendText {
+        text: String,
+        for_user: Option<String>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Send a location to the channel, or to one user on it
+    SendLocation {
+        location: Location,
+        for_user: Option<String>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Stop an outbound audio stream
+    StopStream {
+        stream_id: u32,
+        reply: Option<oneshot::Sender<Result<()>>>,
+    },
+}
+
+/// Changes in the state of outbound streams
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransmitEvent {
+    /// An outbound stream was started
+    Started { stream_id: u32 },
+    /// An outbound stream was stopped
+    Stopped { stream_id: u32, reason: StopReason },
+}
+
+/// Why an outbound stream was stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The sender stopped it
+    Finished,
+    /// It was open longer than the maximum transmission duration
+    TimedOut,
+    /// Its [`OutboundStream`] was dropped without being finished
+    Dropped,
+}
+
+/// Cloneable handle for transmitting through a client while its message loop runs
+///
+/// Commands are carried out by [`ZelloClient::run_message_loop`].
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    commands: mpsc::UnboundedSender<ClientCommand>,
+    transmit_events: broadcast::Sender<TransmitEvent>,
+    floor: watch::Receiver<FloorState>,
+    floor_policy: FloorPolicy,
+}
+
+impl ClientHandle {
+    pub(crate) fn new(
+        commands: mpsc::UnboundedSender<ClientCommand>,
+        transmit_events: broadcast::Sender<TransmitEvent>,
+        floor: watch::Receiver<FloorState>,
+    ) -> Self {
+        Self {
+            commands,
+            transmit_events,
+            floor,
+            floor_policy: FloorPolicy::default(),
+        }
+    }
+
+    /// Watch who holds the channel floor
+    #[must_use]
+    pub fn floor(&self) -> watch::Receiver<FloorState> {
+        self.floor.clone()
+    }
+
+    /// Set what streams started through this handle do when the channel is busy
+    pub fn set_floor_policy(&mut self, policy: FloorPolicy) {
+        self.floor_policy = policy;
+    }
+
+    /// What streams started through this handle do when the channel is busy
+    #[must_use]
+    pub fn floor_policy(&self) -> &FloorPolicy {
+        &self.floor_policy
+    }
+
+    /// Subscribe to the starting and stopping of outbound streams
+    #[must_use]
+    pub fn transmit_events(&self) -> broadcast::Receiver<TransmitEvent> {
+        self.transmit_events.subscribe()
+    }
+
+    /// Start an audio stream, applying the floor policy if the channel is busy
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ZelloError::ChannelBusy`] if the floor policy refuses the
+    /// stream, or an error if the client has stopped or the stream cannot be started
+    pub async fn start_audio_stream(
+        &self,
+        codec: &str,
+        codec_header: Option<CodecHeader>,
+        packet_duration: u32,
+    ) -> Result<OutboundStreamHandle> {
+        // Wait here rather than in the message loop, which must keep running
+        // for the floor to clear
+        acquire_floor(&mut self.floor.clone(), &self.floor_policy).await?;
+        let floor_policy = match self.floor_policy {
+            FloorPolicy::Preempt => FloorPolicy::Preempt,
+            FloorPolicy::WaitForClear(_) | FloorPolicy::FailFast => FloorPolicy::FailFast,
+        };
+
+        let (reply, response) = oneshot::channel();
+        self.send(ClientCommand::StartStream {
+            codec: codec.to_string(),
+            codec_header,
+            packet_duration,
+            floor_policy,
+            reply,
+        })?;
+        let stream_id = response.await.map_err(|_| ZelloError::NotConnected)??;
+        Ok(OutboundStreamHandle::new(self.clone(), stream_id))
+    }
+
+    /// Transmit encoded audio in real time
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream cannot be started, is stopped by the
+    /// transmit time-out timer, or the client has stopped
+    pub async fn transmit_audio(&self, audio: EncodedAudio) -> Result<()> {
+        let mut events = self.transmit_events();
+        let mut stream = self
+            .start_audio_stream(OPUS_CODEC, Some(audio.codec_header), audio.packet_duration)
+            .await?;
+        let stream_id = stream.stream_id();
+
+        let mut packets = PacedPackets::new(stream_id, audio.packet_duration, audio.packets);
+        while let Some(packet) = packets.next().await {
+            while let Ok(event) = events.try_recv() {
+                if event
+                    == (TransmitEvent::Stopped {
+                        stream_id,
+                        reason: StopReason::TimedOut,
+                    })
+                {
+                    return Err(ZelloError::TransmitTimeout(stream_id));
+                }
+            }
+            stream.send(packet)?;
+        }
+
+        stream.finish().await
+    }
+
+    /// Send a text message to the channel
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the client has stopped or the message cannot be sent
+    pub async fn send_text_message(&self, text: &str) -> Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.send(ClientCommand::SendText {
+            text: text.to_string(),
+            for_user: None,
+            reply,
+        })?;
+        response.await.map_err(|_| ZelloError::NotConnected)?
+    }
+
+    /// Send a text message to one user on the channel
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message cannot be sent or the client has stopped
+    pub async fn send_text_message_to_callsign(&self, text: &str, callsign: &str) -> Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.send(ClientCommand::SendText {
+            text: text.to_string(),
+            for_user: Some(callsign.to_string()),
+            reply,
+        })?;
+        response.await.map_err(|_| ZelloError::NotConnected)?
+    }
+
+    /// Send a location to the channel, or to `callsign` alone
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the location cannot be sent or the client has stopped
+    pub async fn send_location(&self, location: Location, callsign: Option<&str>) -> Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.send(ClientCommand::SendLocation {
+            location,
+            for_user: callsign.map(str::to_string),
+            reply,
+        })?;
+        response.await.map_err(|_| ZelloError::NotConnected)?
+    }
+
+    pub(crate) fn send(&self, command: ClientCommand) -> Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| ZelloError::NotConnected)
+    }
 }
 
 /// Zello client for interacting with the Zello API
@@ -102,7 +365,9 @@ impl ZelloClient {
     pub async fn new(config: ZelloConfig) -> Result<Self> {
         config.validate()?;
 
-        let protocol = Protocol::connect(None).await?;
+        let protocol = Protocol::connect(config.server_url.as_deref()).await?;
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let monitor = ChannelMonitor::new(&config.channel);
 
         let mut client = Self {
             protocol,
@@ -111,9 +376,25 @@ impl ZelloClient {
             active_streams: HashMap::new(),
             active_inbound_streams: HashMap::new(),
             refresh_token: String::new(),
+            volume: VolumeControl::default(),
+            agc: None,
+            playback: None,
+            pending: VecDeque::new(),
+            commands_tx,
+            commands_rx: Some(commands_rx),
+            transmit_timer: TransmitTimer::default(),
+            transmit_events: broadcast::channel(TRANSMIT_EVENT_CAPACITY).0,
+            abandoned_streams: Vec::new(),
+            floor: FloorTracker::default(),
+            floor_policy: FloorPolicy::default(),
+            monitor,
+            transmission_subscribers: Vec::new(),
+            text_subscribers: Vec::new(),
+            inbound_transmissions: HashMap::new(),
         };
 
         client.authenticate().await?;
+        client.monitor.set_connected(true);
 
         Ok(client)
     }
@@ -192,25 +473,197 @@ impl ZelloClient {
     ) -> Result<()> {
         info!("Listening for messages (press Ctrl+C to exit)...");
 
+        let Some(mut commands) = self.commands_rx.take() else {
+            return Err(ZelloError::ProtocolError(
+                "Message loop is already running".to_string(),
+            ));
+        };
+
         loop {
-            match self.receive_message().await {
-                Ok(Some(message)) => {
-                    handle_message(self, message, decoder.clone(), pcm_tx).await;
+            let deadline = self.transmit_timer.next_deadline();
+            let floor_expiry = self.floor.next_expiry();
+
+            tokio::select! {
+                received = self.receive_message() => match received {
+                    Ok(Some(message)) => {
+                        handle_message(self, message, decoder.clone(), pcm_tx).await;
+                    }
+                    Ok(None) => {
+                        info!("Connection closed");
+                        break;
+                    }
+                    Err(e) => {
+                        error!("Error receiving message: {e}");
+                        break;
+                    }
+                },
+                Some(command) = commands.recv() => {
+                    self.handle_command(command).await;
                 }
-                Ok(None) => {
-                    info!("Connection closed");
-                    break;
+                () = sleep_until_deadline(deadline) => {
+                    for stream_id in self.transmit_timer.expired(Instant::now()) {
+                        self.time_out_stream(stream_id).await;
+                    }
                 }
-                Err(e) => {
-                    error!("Error receiving message: {e}");
-                    break;
+                () = sleep_until_deadline(floor_expiry) => {
+                    self.floor.expire(Instant::now());
                 }
             }
         }
 
+        self.commands_rx = Some(commands);
+        self.monitor.set_connected(false);
         Ok(())
     }
 
+    /// Carry out a command from a [`ClientHandle`]
+    async fn handle_command(&mut self, command: ClientCommand) {
+        match command {
+            ClientCommand::StartStream {
+                codec,
+                codec_header,
+                packet_duration,
+                floor_policy,
+                reply,
+            } => {
+                let result = self
+                    .open_stream(
+                        &codec,
+                        codec_header.as_ref(),
+                        packet_duration,
+                        &floor_policy,
+                    )
+                    .await;
+                let _ = reply.send(result);
+            }
+            ClientCommand::SendAudioPacket {
+                stream_id,
+                packet_id,
+                data,
+            } => {
+                // The sender may have queued packets before the stream was stopped
+                if !self.active_streams.contains_key(&stream_id) {
+                    debug!("Dropping packet {packet_id} for stopped stream {stream_id}");
+                } else if let Err(e) = self.send_stream_packet(stream_id, packet_id, &data).await {
+                    if matches!(e, ZelloError::TransmitTimeout(_)) {
+                        debug!("Dropping packet {packet_id} for timed-out stream {stream_id}");
+                    } else {
+                        error!("Error sending audio on stream {stream_id}: {e}");
+                    }
+                }
+            }
+            ClientCommand::SendText {
+                text,
+                for_user,
+                reply,
+            } => {
+                let result = match for_user {
+                    Some(callsign) => self.send_text_message_to_callsign(&text, &callsign).await,
+                    None => self.send_text_message(&text).await,
+                };
+                let _ = reply.send(result);
+            }
+            ClientCommand::SendLocation {
+                location,
+                for_user,
+                reply,
+            } => {
+                let _ = reply.send(self.send_location(location, for_user.as_deref()).await);
+            }
+            ClientCommand::StopStream { stream_id, reply } => {
+                let result = self.stop_audio_stream(stream_id).await;
+                match reply {
+                    Some(reply) => {
+                        let _ = reply.send(result);
+                    }
+                    None => {
+                        // Sent when a stream handle is dropped, which may be after it was stopped
+                        if let Err(e) = result {
+                            debug!("Dropped stream {stream_id} was not stopped: {e}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// A handle for transmitting while the message loop runs
+    ///
+    /// The handle starts with the client's floor policy.
+    #[must_use]
+    pub fn handle(&self) -> ClientHandle {
+        let mut handle = ClientHandle::new(
+            self.commands_tx.clone(),
+            self.transmit_events.clone(),
+            self.floor.subscribe(),
+        );
+        handle.set_floor_policy(self.floor_policy.clone());
+        handle
+    }
+
+    /// Who holds the channel floor
+    pub fn floor_state(&self) -> FloorState {
+        self.floor.state()
+    }
+
+    /// Watch who holds the channel floor
+    #[must_use]
+    pub fn floor(&self) -> watch::Receiver<FloorState> {
+        self.floor.subscribe()
+    }
+
+    /// Set what outbound streams do when the channel is busy
+    pub fn set_floor_policy(&mut self, policy: FloorPolicy) {
+        self.floor_policy = policy;
+    }
+
+    /// What outbound streams do when the channel is busy
+    pub fn floor_policy(&self) -> &FloorPolicy {
+        &self.floor_policy
+    }
+
+    /// Watch the channel's connection, status, roster and talker
+    #[must_use]
+    pub fn channel_snapshot(&self) -> watch::Receiver<ChannelSnapshot> {
+        self.monitor.snapshot()
+    }
+
+    /// Subscribe to events on the channel
+    ///
+    /// Events are published while the message loop runs. A subscriber that
+    /// falls too far behind misses the oldest.
+    #[must_use]
+    pub fn channel_events(&self) -> broadcast::Receiver<ChannelEvent> {
+        self.monitor.events()
+    }
+
+    /// Subscribe to the starting and stopping of outbound streams
+    ///
+    /// A [`StopReason::TimedOut`] event tells the sender that the transmit
+    /// time-out timer stopped its stream.
+    #[must_use]
+    pub fn transmit_events(&self) -> broadcast::Receiver<TransmitEvent> {
+        self.transmit_events.subscribe()
+    }
+
+    /// Limit the duration of outbound streams, with an optional lockout after a time-out
+    pub fn set_transmit_limits(&mut self, limits: TransmitLimits) {
+        self.transmit_timer.set_limits(limits);
+    }
+
+    /// Limits applied to outbound streams
+    pub fn transmit_limits(&self) -> &TransmitLimits {
+        self.transmit_timer.limits()
+    }
+
+    /// Stop a stream that has been open longer than allowed
+    async fn time_out_stream(&mut self, stream_id: u32) {
+        warn!("Stream {stream_id} exceeded the maximum transmission duration, stopping it");
+        if let Err(e) = self.stop_stream(stream_id, StopReason::TimedOut).await {
+            error!("Error stopping stream {stream_id}: {e}");
+        }
+    }
+
     /// Send a text message to the channel
     ///
     /// # Errors
@@ -265,54 +718,203 @@ impl ZelloClient {
         Ok(())
     }
 
+    /// Send a location to the channel, or to `callsign` alone
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the client is not connected or the location cannot be sent
+    pub async fn send_location(
+        &mut self,
+        location: Location,
+        callsign: Option<&str>,
+    ) -> Result<()> {
+        if !self.authenticated {
+            return Err(ZelloError::NotConnected);
+        }
+
+        info!(
+            "Sending location {:.5}, {:.5} to {}",
+            location.latitude,
+            location.longitude,
+            callsign.unwrap_or(&self.config.channel)
+        );
+        let message = Message::send_location(
+            self.protocol.next_seq(),
+            self.config.channel.clone(),
+            location,
+            callsign.map(str::to_string),
+        );
+        self.protocol.send(message).await?;
+
+        Ok(())
+    }
+
     /// Start an audio stream
     ///
+    /// The stream is stopped when the returned [`OutboundStream`] is finished or dropped.
+    ///
     /// # Errors
     ///
     /// Returns an error if fail to start an audio stream
-    pub async fn start_audio_stream(&mut self, codec: &str, packet_duration: u32) -> Result<u32> {
+    pub async fn start_audio_stream(
+        &mut self,
+        codec: &str,
+        packet_duration: u32,
+    ) -> Result<OutboundStream<'_>> {
+        self.start_audio_stream_with_header(codec, None, packet_duration)
+            .await
+    }
+
+    /// Start an audio stream, describing the audio with a codec header
+    ///
+    /// The stream is stopped when the returned [`OutboundStream`] is finished or dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if fail to start an audio stream
+    pub async fn start_audio_stream_with_header(
+        &mut self,
+        codec: &str,
+        codec_header: Option<&CodecHeader>,
+        packet_duration: u32,
+    ) -> Result<OutboundStream<'_>> {
+        let floor_policy = self.floor_policy.clone();
+        let stream_id = self
+            .open_stream(codec, codec_header, packet_duration, &floor_policy)
+            .await?;
+        Ok(OutboundStream::new(self, stream_id))
+    }
+
+    /// Start an audio stream, returning its id
+    async fn open_stream(
+        &mut self,
+        codec: &str,
+        codec_header: Option<&CodecHeader>,
+        packet_duration: u32,
+        floor_policy: &FloorPolicy,
+    ) -> Result<u32> {
         if !self.authenticated {
             return Err(ZelloError::NotConnected);
         }
+        self.stop_abandoned_streams().await;
+        self.transmit_timer.check_start(Instant::now())?;
+        self.wait_for_floor(floor_policy).await?;
 
         let seq = self.protocol.next_seq();
-        let message = Message::start_stream(
-            seq,
-            self.config.channel.clone(),
-            codec.to_string(),
-            packet_duration,
-        );
+        let message = match codec_header {
+            Some(header) => Message::start_stream_with_header(
+                seq,
+                self.config.channel.clone(),
+                codec.to_string(),
+                header,
+                packet_duration,
+            ),
+            None => Message::start_stream(
+                seq,
+                self.config.channel.clone(),
+                codec.to_string(),
+                packet_duration,
+            ),
+        };
 
         self.protocol.send(message).await?;
 
-        // Wait for response
-        let response = timeout(Duration::from_secs(5), self.protocol.receive())
+        let response = timeout(Duration::from_secs(5), self.wait_for_response(seq))
             .await
-            .map_err(|_| ZelloError::Timeout)?;
+            .map_err(|_| ZelloError::Timeout)??;
 
-        match response? {
-            Some(IncomingMessage::Response(Response::Generic { success: true, .. })) => {
-                let stream_id = seq; // Use seq as stream_id for now
-                self.active_streams.insert(
-                    stream_id,
-                    StreamInfo {
-                        channel: self.config.channel.clone(),
-                        codec: codec.to_string(),
-                        ..Default::default()
-                    },
-                );
-                Ok(stream_id)
-            }
-            Some(IncomingMessage::Response(Response::Generic {
-                success: false,
-                error,
+        let stream_id = match response {
+            Response::StartStream {
+                success: true,
+                stream_id,
                 ..
-            })) => Err(ZelloError::AudioError(
-                error.unwrap_or_else(|| "Failed to start stream".to_string()),
-            )),
-            _ => Err(ZelloError::ProtocolError(
-                "Unexpected response to start_stream".to_string(),
-            )),
+            } => stream_id,
+            // Older servers do not return a stream id, so fall back to the sequence number
+            Response::Generic { success: true, .. } => seq,
+            // Refused while someone else is talking
+            _ if !self.floor.state().is_clear() => {
+                return Err(busy_error(&self.floor.state()));
+            }
+            Response::StartStream { error, .. }
+            | Response::Generic { error, .. }
+            | Response::Logon { error, .. } => {
+                return Err(ZelloError::AudioError(
+                    error.unwrap_or_else(|| "Failed to start stream".to_string()),
+                ));
+            }
+        };
+
+        self.active_streams.insert(
+            stream_id,
+            StreamInfo {
+                channel: self.config.channel.clone(),
+                codec: codec.to_string(),
+                ..Default::default()
+            },
+        );
+        self.transmit_timer.start(stream_id, Instant::now());
+        let _ = self
+            .transmit_events
+            .send(TransmitEvent::Started { stream_id });
+        Ok(stream_id)
+    }
+
+    /// Apply `policy` to the floor, reading messages while waiting for it to clear
+    ///
+    /// Messages read while waiting are queued for `receive_message`.
+    async fn wait_for_floor(&mut self, policy: &FloorPolicy) -> Result<()> {
+        let Some(wait) = policy.max_wait() else {
+            return Ok(());
+        };
+        let deadline = Instant::now() + wait;
+
+        loop {
+            self.floor.expire(Instant::now());
+            let state = self.floor.state();
+            if state.is_clear() {
+                return Ok(());
+            }
+            // Wake up in time to drop a talker that has gone silent
+            let wake = self
+                .floor
+                .next_expiry()
+                .map_or(deadline, |expiry| expiry.min(deadline));
+            match timeout_at(wake, self.read_message()).await {
+                Ok(Ok(Some(message))) => self.pending.push_back(message),
+                Ok(Ok(None)) => {
+                    return Err(ZelloError::ConnectionError("Connection closed".to_string()));
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) if Instant::now() < deadline => {}
+                Err(_) => return Err(busy_error(&state)),
+            }
+        }
+    }
+
+    /// Read a message from the server, following the channel floor
+    async fn read_message(&mut self) -> Result<Option<IncomingMessage>> {
+        let message = self.protocol.receive().await?;
+        if let Some(message) = &message {
+            self.floor.observe(message);
+            self.monitor.observe(message);
+        }
+        Ok(message)
+    }
+
+    /// Wait for the response to the request with sequence number `seq`
+    ///
+    /// Other messages that arrive in the meantime are queued for `receive_message`.
+    async fn wait_for_response(&mut self, seq: u32) -> Result<Response> {
+        loop {
+            match self.read_message().await? {
+                Some(IncomingMessage::Response(response)) if response.seq() == Some(seq) => {
+                    return Ok(response);
+                }
+                Some(message) => self.pending.push_back(message),
+                None => {
+                    return Err(ZelloError::ConnectionError("Connection closed".to_string()));
+                }
+            }
         }
     }
 
@@ -322,11 +924,35 @@ impl ZelloClient {
     ///
     /// Returns an error if fail to send audio data packet
     pub async fn send_audio_packet(&mut self, stream_id: u32, data: Vec<u8>) -> Result<()> {
-        if !self.active_streams.contains_key(&stream_id) {
+        let Some(stream) = self.active_streams.get(&stream_id) else {
             return Err(ZelloError::AudioError("Invalid stream ID".to_string()));
+        };
+        let packet_id = stream.packets_sent;
+        self.send_stream_packet(stream_id, packet_id, &data).await
+    }
+
+    /// Send an audio packet with the given packet id
+    pub(crate) async fn send_stream_packet(
+        &mut self,
+        stream_id: u32,
+        packet_id: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        self.stop_abandoned_streams().await;
+
+        if self.transmit_timer.is_expired(stream_id, Instant::now()) {
+            self.time_out_stream(stream_id).await;
+            return Err(ZelloError::TransmitTimeout(stream_id));
         }
 
-        self.protocol.send_audio_data(data).await?;
+        let Some(stream) = self.active_streams.get_mut(&stream_id) else {
+            return Err(ZelloError::AudioError("Invalid stream ID".to_string()));
+        };
+        stream.packets_sent = packet_id.wrapping_add(1);
+
+        self.protocol
+            .send_audio_packet(stream_id, packet_id, data)
+            .await?;
         Ok(())
     }
 
@@ -336,24 +962,115 @@ impl ZelloClient {
     ///
     /// Returns an error if fail to stop an audio stream
     pub async fn stop_audio_stream(&mut self, stream_id: u32) -> Result<()> {
-        if !self.active_streams.contains_key(&stream_id) {
+        self.stop_stream(stream_id, StopReason::Finished).await
+    }
+
+    async fn stop_stream(&mut self, stream_id: u32, reason: StopReason) -> Result<()> {
+        if !self.forget_stream(stream_id, reason) {
             return Err(ZelloError::AudioError("Invalid stream ID".to_string()));
         }
 
         let message = Message::stop_stream(self.protocol.next_seq(), stream_id);
         self.protocol.send(message).await?;
-
-        self.active_streams.remove(&stream_id);
         Ok(())
     }
 
+    /// Remove an outbound stream from the client, returning whether it was active
+    fn forget_stream(&mut self, stream_id: u32, reason: StopReason) -> bool {
+        if self.active_streams.remove(&stream_id).is_none() {
+            return false;
+        }
+
+        self.transmit_timer
+            .stop(stream_id, Instant::now(), reason == StopReason::TimedOut);
+        let _ = self
+            .transmit_events
+            .send(TransmitEvent::Stopped { stream_id, reason });
+        true
+    }
+
+    /// Forget a stream whose [`OutboundStream`] was dropped, and stop it on the next operation
+    pub(crate) fn abandon_stream(&mut self, stream_id: u32) {
+        if self.forget_stream(stream_id, StopReason::Dropped) {
+            self.abandoned_streams.push(stream_id);
+        }
+    }
+
+    /// Stop streams whose [`OutboundStream`] was dropped without being finished
+    async fn stop_abandoned_streams(&mut self) {
+        while let Some(stream_id) = self.abandoned_streams.pop() {
+            debug!("Stopping dropped stream {stream_id}");
+            let message = Message::stop_stream(self.protocol.next_seq(), stream_id);
+            if let Err(e) = self.protocol.send(message).await {
+                error!("Error stopping stream {stream_id}: {e}");
+            }
+        }
+    }
+
+    /// Transmit a WAV or Ogg/Opus file to the channel
+    ///
+    /// The whole file is sent as a single stream; see [`load_audio_file`] for
+    /// how the audio is converted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be loaded or the stream fails
+    pub async fn transmit_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let audio = load_audio_file(path, &EncoderConfig::default())?;
+        info!(
+            "Transmitting {} ({} packets, {}ms)",
+            path.display(),
+            audio.packets.len(),
+            audio.duration_ms()
+        );
+
+        self.transmit_audio(audio).await
+    }
+
+    /// Transmit encoded audio to the channel as a single stream
+    ///
+    /// Packets are released in real time, one every packet duration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream fails
+    pub async fn transmit_audio(&mut self, audio: EncodedAudio) -> Result<()> {
+        let result = self.send_paced(audio).await;
+        // Stop the stream now if sending failed part way through
+        self.stop_abandoned_streams().await;
+        result
+    }
+
+    async fn send_paced(&mut self, audio: EncodedAudio) -> Result<()> {
+        let mut stream = self
+            .start_audio_stream_with_header(
+                OPUS_CODEC,
+                Some(&audio.codec_header),
+                audio.packet_duration,
+            )
+            .await?;
+        let stream_id = stream.stream_id();
+
+        let mut packets = PacedPackets::new(stream_id, audio.packet_duration, audio.packets);
+        while let Some(packet) = packets.next().await {
+            stream.send(packet).await?;
+        }
+
+        stream.finish().await
+    }
+
     /// Receive the next message
     ///
     /// # Errors
     ///
     /// Returns an error if fail to receive the next message
     pub async fn receive_message(&mut self) -> Result<Option<IncomingMessage>> {
-        self.protocol.receive().await
+        self.stop_abandoned_streams().await;
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(Some(message));
+        }
+        self.read_message().await
     }
 
     /// Check if client is authenticated
@@ -366,12 +1083,51 @@ impl ZelloClient {
         &self.config.channel
     }
 
+    /// Get the username the client logged on with
+    pub fn username(&self) -> Option<&str> {
+        self.config.username.as_deref()
+    }
+
+    /// Per-user volume controls applied to received audio
+    ///
+    /// The returned controls are shared, so they can be changed from another
+    /// task while the message loop is running.
+    pub fn volume(&self) -> &VolumeControl {
+        &self.volume
+    }
+
+    /// Replace the volume controls, e.g. with ones persisted to a config file
+    pub fn set_volume(&mut self, volume: VolumeControl) {
+        self.volume = volume;
+    }
+
+    /// Enable automatic gain control of received audio, or disable it with `None`
+    pub fn set_agc(&mut self, config: Option<AgcConfig>) {
+        self.agc = config.map(AutomaticGainControl::new);
+    }
+
+    /// The automatic gain control stage, if enabled
+    pub fn agc_mut(&mut self) -> Option<&mut AutomaticGainControl> {
+        self.agc.as_mut()
+    }
+
+    /// Mark the end of each played transmission on this playback output
+    pub fn set_playback_stats(&mut self, stats: Option<Arc<PlaybackStats>>) {
+        self.playback = stats;
+    }
+
+    /// The playback output received audio is played on, if any
+    pub fn playback_stats(&self) -> Option<&PlaybackStats> {
+        self.playback.as_deref()
+    }
+
     /// Close the connection
     ///
     /// # Errors
     ///
     /// Returns an error if fail to close the connection
-    pub async fn close(self) -> Result<()> {
+    pub async fn close(mut self) -> Result<()> {
+        self.stop_abandoned_streams().await;
         self.protocol.close().await
     }
 
@@ -393,6 +1149,7 @@ impl ZelloClient {
                 channel,
                 codec,
                 callsign,
+                ..Default::default()
             },
         );
         Ok(())
@@ -410,8 +1167,76 @@ impl ZelloClient {
     /// Returns an error if fail to remove an inbound stream
     pub fn remove_inbound_stream(&mut self, stream_id: u32) -> Result<()> {
         self.active_inbound_streams.remove(&stream_id);
+        self.inbound_transmissions.remove(&stream_id);
         Ok(())
     }
+
+    /// Receive each inbound transmission as it starts
+    ///
+    /// Every call returns a new receiver that gets its own copy of each
+    /// transmission. Transmissions are delivered while the message loop runs.
+    pub fn inbound_transmissions(&mut self) -> mpsc::UnboundedReceiver<InboundTransmission> {
+        let (transmissions_tx, transmissions_rx) = mpsc::unbounded_channel();
+        self.transmission_subscribers.push(transmissions_tx);
+        transmissions_rx
+    }
+
+    /// Receive each text message sent to the channel
+    ///
+    /// Every call returns a new receiver that gets its own copy of each
+    /// message. Messages are delivered while the message loop runs.
+    pub fn text_messages(&mut self) -> mpsc::UnboundedReceiver<InboundText> {
+        let (texts_tx, texts_rx) = mpsc::unbounded_channel();
+        self.text_subscribers.push(texts_tx);
+        texts_rx
+    }
+
+    /// Hand a text message to every subscriber
+    pub(crate) fn publish_text(&mut self, text: &InboundText) {
+        self.text_subscribers
+            .retain(|subscriber| subscriber.send(text.clone()).is_ok());
+    }
+
+    /// Hand a starting transmission to every subscriber
+    pub(crate) fn announce_transmission(&mut self, info: &TransmissionInfo) {
+        let stream_id = info.stream_id;
+        let mut packet_senders = Vec::new();
+
+        self.transmission_subscribers.retain(|subscriber| {
+            let (packets_tx, transmission) = InboundTransmission::new(info.clone());
+            let delivered = subscriber.send(transmission).is_ok();
+            if delivered {
+                packet_senders.push(packets_tx);
+            }
+            delivered
+        });
+
+        if !packet_senders.is_empty() {
+            self.inbound_transmissions.insert(stream_id, packet_senders);
+        }
+    }
+
+    /// Pass an inbound packet on to the transmissions still being read
+    pub(crate) fn forward_inbound_packet(&mut self, stream_id: u32, packet_id: u32, data: &[u8]) {
+        if let Some(senders) = self.inbound_transmissions.get_mut(&stream_id) {
+            senders.retain(|sender| {
+                sender
+                    .send(InboundPacket {
+                        packet_id,
+                        data: data.to_vec(),
+                    })
+                    .is_ok()
+            });
+        }
+    }
+}
+
+/// Wait until `deadline`, or forever if there is none
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
 }
 
 /// Command line interface for Zello
@@ -421,6 +1246,8 @@ pub struct Credentials {
     pub password: String,
     pub token: String,
     pub channel: String,
+    /// WebSocket URL of the server, if not the default
+    pub server_url: Option<String>,
 }
 
 #[cfg(test)]
@@ -0,0 +1,241 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Browser dashboard showing the channel and playing its audio
+
+use std::sync::Arc;
+
+use crate::client::ZelloClient;
+use crate::error::Result;
+use crate::inbound::InboundTransmission;
+use crate::monitor::{ChannelEvent, ChannelSnapshot};
+use axum::Json;
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::response::{Html, Response};
+use axum::routing::get;
+use futures_util::StreamExt;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::{debug, info, warn};
+
+/// The dashboard page
+const PAGE: &str = include_str!("dashboard.html");
+
+/// 20ms audio chunks held for each browser before the oldest are dropped
+const AUDIO_CAPACITY: usize = 50;
+
+/// What the dashboard shows, taken from a connected client
+#[derive(Debug)]
+pub struct Dashboard {
+    snapshot: watch::Receiver<ChannelSnapshot>,
+    events: broadcast::Receiver<ChannelEvent>,
+    transmissions: mpsc::UnboundedReceiver<InboundTransmission>,
+}
+
+impl Dashboard {
+    /// Subscribe to `client`'s channel state, events and audio
+    ///
+    /// The client's message loop must be run for the dashboard to update.
+    pub fn new(client: &mut ZelloClient) -> Self {
+        Self {
+            snapshot: client.channel_snapshot(),
+            events: client.channel_events(),
+            transmissions: client.inbound_transmissions(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct DashboardState {
+    snapshot: watch::Receiver<ChannelSnapshot>,
+    events: Arc<broadcast::Receiver<ChannelEvent>>,
+    audio: broadcast::Sender<Bytes>,
+}
+
+/// Serve the dashboard on `listener`
+///
+/// `/` is the page, `/status` the current [`ChannelSnapshot`] as JSON,
+/// `/events` a WebSocket of the snapshot followed by each [`ChannelEvent`] as
+/// JSON, and `/audio` a WebSocket of decoded channel audio as 16kHz 16-bit
+/// little-endian PCM. Returns when the client stops delivering transmissions.
+///
+/// # Errors
+///
+/// Returns an error if the server fails
+pub async fn serve_dashboard(listener: TcpListener, dashboard: Dashboard) -> Result<()> {
+    let state = DashboardState {
+        snapshot: dashboard.snapshot,
+        events: Arc::new(dashboard.events),
+        audio: broadcast::channel(AUDIO_CAPACITY).0,
+    };
+    if let Ok(address) = listener.local_addr() {
+        info!("Dashboard at http://{address}/");
+    }
+
+    let app = Router::new()
+        .route("/", get(|| async { Html(PAGE) }))
+        .route("/status", get(status))
+        .route("/events", get(events))
+        .route("/audio", get(audio))
+        .with_state(state.clone());
+
+    tokio::select! {
+        result = axum::serve(listener, app) => Ok(result?),
+        () = relay_audio(dashboard.transmissions, &state.audio) => Ok(()),
+    }
+}
+
+async fn status(State(state): State<DashboardState>) -> Json<ChannelSnapshot> {
+    Json(state.snapshot.borrow().clone())
+}
+
+async fn events(upgrade: WebSocketUpgrade, State(state): State<DashboardState>) -> Response {
+    upgrade.on_upgrade(move |socket| send_events(socket, state))
+}
+
+async fn audio(upgrade: WebSocketUpgrade, State(state): State<DashboardState>) -> Response {
+    let chunks = state.audio.subscribe();
+    upgrade.on_upgrade(move |socket| send_audio(socket, chunks))
+}
+
+/// The snapshot as a JSON message, marked so the page can tell it from events
+fn snapshot_message(snapshot: &watch::Receiver<ChannelSnapshot>) -> Message {
+    let json = serde_json::json!({ "type": "snapshot", "snapshot": *snapshot.borrow() });
+    Message::Text(json.to_string().into())
+}
+
+/// Send the snapshot, then each event, until the browser goes away
+async fn send_events(mut socket: WebSocket, state: DashboardState) {
+    let mut events = state.events.resubscribe();
+    if socket
+        .send(snapshot_message(&state.snapshot))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    loop {
+        let message = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => match serde_json::to_string(&event) {
+                    Ok(json) => Message::Text(json.into()),
+                    Err(e) => {
+                        warn!("Failed to encode event: {e}");
+                        continue;
+                    }
+                },
+                // Missed events are covered by a fresh snapshot
+                Err(broadcast::error::RecvError::Lagged(_)) => snapshot_message(&state.snapshot),
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            received = socket.next() => match received {
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => return,
+            },
+        };
+        if socket.send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Send decoded audio until the browser goes away
+async fn send_audio(mut socket: WebSocket, mut chunks: broadcast::Receiver<Bytes>) {
+    loop {
+        tokio::select! {
+            chunk = chunks.recv() => match chunk {
+                Ok(chunk) => {
+                    if socket.send(Message::Binary(chunk)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    debug!("Browser is behind, skipped {missed} audio chunks");
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            received = socket.next() => match received {
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => return,
+            },
+        }
+    }
+}
+
+/// Decode each transmission once and hand the audio to every browser
+async fn relay_audio(
+    mut transmissions: mpsc::UnboundedReceiver<InboundTransmission>,
+    audio: &broadcast::Sender<Bytes>,
+) {
+    while let Some(transmission) = transmissions.recv().await {
+        let pcm = match transmission.pcm() {
+            Ok(pcm) => pcm,
+            Err(e) => {
+                warn!("Cannot decode transmission for the dashboard: {e}");
+                continue;
+            }
+        };
+        let mut pcm = Box::pin(pcm);
+        while let Some(chunk) = pcm.next().await {
+            let bytes: Vec<u8> = chunk.iter().flat_map(|s| s.to_le_bytes()).collect();
+            // Nobody may be listening
+            let _ = audio.send(Bytes::from(bytes));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::{connect_async, tungstenite};
+
+    #[tokio::test]
+    async fn test_events_follow_snapshot() {
+        let (_snapshot_tx, snapshot) = watch::channel(ChannelSnapshot {
+            connected: true,
+            channel: "channel".to_string(),
+            ..ChannelSnapshot::default()
+        });
+        let (events_tx, events) = broadcast::channel(8);
+        let (_transmissions_tx, transmissions) = mpsc::unbounded_channel();
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let address = listener.local_addr().expect("address");
+        tokio::spawn(serve_dashboard(
+            listener,
+            Dashboard {
+                snapshot,
+                events,
+                transmissions,
+            },
+        ));
+
+        let (mut socket, _) = connect_async(format!("ws://{address}/events"))
+            .await
+            .expect("connect");
+        let Some(Ok(tungstenite::Message::Text(first))) = socket.next().await else {
+            panic!("no snapshot");
+        };
+        let first: serde_json::Value = serde_json::from_str(&first).expect("json");
+        assert_eq!(first["type"], "snapshot");
+        assert_eq!(first["snapshot"]["channel"], "channel");
+        assert_eq!(first["snapshot"]["connected"], true);
+
+        events_tx
+            .send(ChannelEvent::Presence {
+                channel: "channel".to_string(),
+                from: "alice".to_string(),
+                online: true,
+            })
+            .expect("send");
+        let Some(Ok(tungstenite::Message::Text(event))) = socket.next().await else {
+            panic!("no event");
+        };
+        let event: serde_json::Value = serde_json::from_str(&event).expect("json");
+        assert_eq!(event["type"], "presence");
+        assert_eq!(event["from"], "alice");
+    }
+}
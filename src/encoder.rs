@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Opus encoder for outbound Zello audio streams
+
+use crate::error::{Result, ZelloError};
+use crate::message::CodecHeader;
+use crate::{OPUS_CHANNELS, OPUS_SAMPLE_RATE};
+use audiopus::coder::Encoder;
+use audiopus::{Application, Bitrate};
+
+/// Codec name used by Zello for Opus streams
+pub const OPUS_CODEC: &str = "opus";
+
+/// Largest Opus packet the encoder will produce
+const MAX_PACKET_SIZE: usize = 4000;
+
+/// Settings for encoding outbound audio
+#[derive(Debug, Clone)]
+pub struct EncoderConfig {
+    /// Target bitrate in bits per second
+    pub bitrate: i32,
+    /// Encoder complexity, 0 (fastest) to 10 (best quality)
+    pub complexity: u8,
+    /// Whether to add in-band forward error correction
+    pub fec: bool,
+    /// Expected packet loss used to size the forward error correction, in percent
+    pub packet_loss_percent: u8,
+    /// Whether to use discontinuous transmission during silence
+    pub dtx: bool,
+    /// Duration of each Opus frame (and packet) in milliseconds
+    pub frame_size_ms: u8,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            bitrate: 16_000,
+            complexity: 8,
+            fec: true,
+            packet_loss_percent: 10,
+            dtx: false,
+            frame_size_ms: 60,
+        }
+    }
+}
+
+/// Opus encoder producing one packet per frame of 16kHz mono PCM
+#[derive(Debug)]
+pub struct OpusEncoder {
+    encoder: Encoder,
+    config: EncoderConfig,
+    frame_samples: usize,
+    pending: Vec<i16>,
+}
+
+impl OpusEncoder {
+    /// Create an encoder with these settings
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the settings are invalid or the encoder cannot be created
+    pub fn new(config: EncoderConfig) -> Result<Self> {
+        if ![10, 20, 40, 60].contains(&config.frame_size_ms) {
+            return Err(ZelloError::ConfigError(format!(
+                "Unsupported Opus frame size: {}ms (expected 10, 20, 40 or 60)",
+                config.frame_size_ms
+            )));
+        }
+        if config.complexity > 10 {
+            return Err(ZelloError::ConfigError(format!(
+                "Invalid Opus complexity: {} (expected 0 to 10)",
+                config.complexity
+            )));
+        }
+
+        let mut encoder = Encoder::new(OPUS_SAMPLE_RATE, OPUS_CHANNELS, Application::Voip)
+            .map_err(audio_error)?;
+        encoder
+            .set_bitrate(Bitrate::BitsPerSecond(config.bitrate))
+            .map_err(audio_error)?;
+        encoder
+            .set_complexity(config.complexity)
+            .map_err(audio_error)?;
+        encoder.set_inband_fec(config.fec).map_err(audio_error)?;
+        encoder
+            .set_packet_loss_perc(config.packet_loss_percent.min(100))
+            .map_err(audio_error)?;
+        encoder.set_dtx(config.dtx).map_err(audio_error)?;
+
+        let frame_samples = OPUS_SAMPLE_RATE as usize / 1000 * usize::from(config.frame_size_ms);
+
+        Ok(Self {
+            encoder,
+            config,
+            frame_samples,
+            pending: Vec::with_capacity(frame_samples),
+        })
+    }
+
+    /// Settings in use
+    #[must_use]
+    pub fn config(&self) -> &EncoderConfig {
+        &self.config
+    }
+
+    /// Codec header describing the packets, for `start_stream`
+    #[must_use]
+    pub fn codec_header(&self) -> CodecHeader {
+        CodecHeader {
+            sample_rate_hz: OPUS_SAMPLE_RATE as u16,
+            frames_per_packet: 1,
+            frame_size_ms: self.config.frame_size_ms,
+        }
+    }
+
+    /// Duration of each packet in milliseconds, for `start_stream`
+    #[must_use]
+    pub fn packet_duration(&self) -> u32 {
+        u32::from(self.config.frame_size_ms)
+    }
+
+    /// Number of PCM samples in each packet
+    #[must_use]
+    pub fn frame_samples(&self) -> usize {
+        self.frame_samples
+    }
+
+    /// Encode PCM, returning a packet for every whole frame
+    ///
+    /// Samples that do not fill a whole frame are held until the next call.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if encoding fails
+    pub fn encode(&mut self, pcm: &[i16]) -> Result<Vec<Vec<u8>>> {
+        let mut packets = Vec::new();
+        let mut input = pcm;
+
+        while !input.is_empty() {
+            let take = (self.frame_samples - self.pending.len()).min(input.len());
+            self.pending.extend_from_slice(&input[..take]);
+            input = &input[take..];
+
+            if self.pending.len() == self.frame_samples {
+                packets.push(self.encode_pending()?);
+            }
+        }
+
+        Ok(packets)
+    }
+
+    /// Encode any held samples as a final frame padded with silence
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if encoding fails
+    pub fn flush(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+        self.pending.resize(self.frame_samples, 0);
+        self.encode_pending().map(Some)
+    }
+
+    fn encode_pending(&mut self) -> Result<Vec<u8>> {
+        let mut packet = vec![0u8; MAX_PACKET_SIZE];
+        let size = self
+            .encoder
+            .encode(&self.pending, &mut packet)
+            .map_err(audio_error)?;
+        packet.truncate(size);
+        self.pending.clear();
+        Ok(packet)
+    }
+}
+
+fn audio_error(e: audiopus::Error) -> ZelloError {
+    ZelloError::AudioError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audiopus::coder::Decoder;
+    use audiopus::{MutSignals, packet::Packet};
+
+    #[test]
+    fn test_codec_header() {
+        let encoder = OpusEncoder::new(EncoderConfig {
+            frame_size_ms: 20,
+            ..EncoderConfig::default()
+        })
+        .expect("encoder");
+        let header = encoder.codec_header();
+        assert_eq!(header.sample_rate_hz, 16000);
+        assert_eq!(header.frames_per_packet, 1);
+        assert_eq!(header.frame_size_ms, 20);
+        assert_eq!(encoder.packet_duration(), 20);
+
+        assert!(
+            OpusEncoder::new(EncoderConfig {
+                frame_size_ms: 30,
+                ..EncoderConfig::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_encode_packets_decode() {
+        let mut encoder = OpusEncoder::new(EncoderConfig::default()).expect("encoder");
+        let pcm = vec![0i16; 16000];
+
+        let mut packets = encoder.encode(&pcm).expect("encode");
+        assert_eq!(packets.len(), 16);
+        packets.extend(encoder.flush().expect("flush"));
+        assert_eq!(packets.len(), 17);
+
+        let mut decoder = Decoder::new(OPUS_SAMPLE_RATE, OPUS_CHANNELS).expect("decoder");
+        let mut output = vec![0i16; 1920];
+        let packet = Packet::try_from(&packets[0]).expect("packet");
+        let signals = MutSignals::try_from(&mut output).expect("signals");
+        let samples = decoder
+            .decode(Some(packet), signals, false)
+            .expect("decode");
+        assert_eq!(samples, encoder.frame_samples());
+    }
+}
@@ -55,6 +55,18 @@ pub enum ZelloError {
     #[error("Operation timed out")]
     Timeout,
 
+    /// Outbound stream stopped by the transmit time-out timer
+    #[error("Transmission on stream {0} exceeded the maximum duration")]
+    TransmitTimeout(u32),
+
+    /// Transmission refused during the lockout after a time-out
+    #[error("Transmit locked out for another {0:?}")]
+    TransmitLockout(std::time::Duration),
+
+    /// Transmission refused because another user is talking
+    #[error("Channel is busy: {0} is talking")]
+    ChannelBusy(String),
+
     /// Channel error
     #[error("Channel error: {0}")]
     ChannelError(String),
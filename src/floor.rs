@@ -0,0 +1,312 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Channel floor tracking for half-duplex transmission
+
+use std::time::Duration;
+
+use crate::error::{Result, ZelloError};
+use crate::message::{Event, IncomingMessage};
+use tokio::sync::watch;
+use tokio::time::{Instant, timeout};
+use tracing::warn;
+
+/// Packet durations without audio after which a talker is taken to have stopped
+const SILENT_PACKETS: u32 = 5;
+
+/// Shortest silence after which a talker is taken to have stopped
+const MIN_SILENCE: Duration = Duration::from_secs(1);
+
+/// Whether someone else is talking on the channel
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum FloorState {
+    /// Nobody is talking
+    #[default]
+    Clear,
+    /// Another user holds the floor
+    Busy { stream_id: u32, from: String },
+}
+
+impl FloorState {
+    /// Whether the channel is free to transmit on
+    #[must_use]
+    pub fn is_clear(&self) -> bool {
+        matches!(self, Self::Clear)
+    }
+}
+
+/// What an outbound transmission does when the channel is busy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum FloorPolicy {
+    /// Wait up to this long for the channel to clear
+    WaitForClear(Duration),
+    /// Transmit anyway
+    Preempt,
+    /// Fail with [`ZelloError::ChannelBusy`]
+    #[default]
+    FailFast,
+}
+
+impl FloorPolicy {
+    /// Longest to wait for the channel to clear, or `None` to transmit anyway
+    pub(crate) fn max_wait(&self) -> Option<Duration> {
+        match self {
+            Self::WaitForClear(wait) => Some(*wait),
+            Self::Preempt => None,
+            Self::FailFast => Some(Duration::ZERO),
+        }
+    }
+}
+
+/// An inbound stream holding or waiting for the floor
+#[derive(Debug)]
+struct Talker {
+    stream_id: u32,
+    from: String,
+    /// How long the stream may go without audio before it is dropped
+    silence: Duration,
+    last_heard: Instant,
+}
+
+impl Talker {
+    fn expires_at(&self) -> Instant {
+        self.last_heard + self.silence
+    }
+}
+
+/// Follows inbound streams to work out who holds the floor
+///
+/// A stream that goes silent without a stop, because the sender dropped off
+/// or the stop was lost, gives up the floor after a few packet durations.
+#[derive(Debug)]
+pub(crate) struct FloorTracker {
+    talkers: Vec<Talker>,
+    state: watch::Sender<FloorState>,
+}
+
+impl Default for FloorTracker {
+    fn default() -> Self {
+        Self {
+            talkers: Vec::new(),
+            state: watch::Sender::new(FloorState::Clear),
+        }
+    }
+}
+
+impl FloorTracker {
+    pub(crate) fn state(&self) -> FloorState {
+        self.state.borrow().clone()
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<FloorState> {
+        self.state.subscribe()
+    }
+
+    /// Update the floor from an incoming message
+    pub(crate) fn observe(&mut self, message: &IncomingMessage) {
+        let now = Instant::now();
+        match message {
+            IncomingMessage::Event(Event::AudioStart {
+                stream_id,
+                from,
+                packet_duration,
+                ..
+            }) => self.talkers.push(Talker {
+                stream_id: *stream_id,
+                from: from.clone(),
+                silence: (Duration::from_millis(u64::from(*packet_duration)) * SILENT_PACKETS)
+                    .max(MIN_SILENCE),
+                last_heard: now,
+            }),
+            IncomingMessage::Event(Event::AudioStop { stream_id }) => {
+                self.talkers.retain(|talker| talker.stream_id != *stream_id);
+            }
+            IncomingMessage::Event(Event::AudioData { stream_id, .. }) => {
+                if let Some(talker) = self
+                    .talkers
+                    .iter_mut()
+                    .find(|talker| talker.stream_id == *stream_id)
+                {
+                    talker.last_heard = now;
+                }
+                return;
+            }
+            _ => return,
+        }
+        self.expire(now);
+    }
+
+    /// When the next silent talker is due to be dropped, if anyone is talking
+    pub(crate) fn next_expiry(&self) -> Option<Instant> {
+        self.talkers.iter().map(Talker::expires_at).min()
+    }
+
+    /// Drop talkers that have been silent too long and publish the floor
+    pub(crate) fn expire(&mut self, now: Instant) {
+        self.talkers.retain(|talker| {
+            let silent = talker.expires_at() <= now;
+            if silent {
+                warn!(
+                    "Stream {} from {} went silent without stopping, releasing the floor",
+                    talker.stream_id, talker.from
+                );
+            }
+            !silent
+        });
+
+        // The floor passes to whoever has been talking longest
+        let state = match self.talkers.first() {
+            Some(talker) => FloorState::Busy {
+                stream_id: talker.stream_id,
+                from: talker.from.clone(),
+            },
+            None => FloorState::Clear,
+        };
+        self.state.send_if_modified(|current| {
+            let changed = *current != state;
+            *current = state;
+            changed
+        });
+    }
+}
+
+/// Apply `policy` to the floor before starting a transmission
+///
+/// # Errors
+///
+/// Returns [`ZelloError::ChannelBusy`] if the channel is busy and the policy
+/// does not allow transmitting, or [`ZelloError::NotConnected`] if the client
+/// has stopped while waiting
+pub(crate) async fn acquire_floor(
+    floor: &mut watch::Receiver<FloorState>,
+    policy: &FloorPolicy,
+) -> Result<()> {
+    let Some(wait) = policy.max_wait() else {
+        return Ok(());
+    };
+
+    let cleared = timeout(wait, floor.wait_for(FloorState::is_clear))
+        .await
+        .map(|cleared| cleared.is_ok());
+    match cleared {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ZelloError::NotConnected),
+        Err(_) => Err(busy_error(&floor.borrow())),
+    }
+}
+
+/// The error for a transmission refused because of `state`
+pub(crate) fn busy_error(state: &FloorState) -> ZelloError {
+    match state {
+        FloorState::Busy { from, .. } => ZelloError::ChannelBusy(from.clone()),
+        FloorState::Clear => ZelloError::ChannelBusy("unknown".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(stream_id: u32, from: &str) -> IncomingMessage {
+        IncomingMessage::Event(Event::AudioStart {
+            stream_id,
+            channel: "channel".to_string(),
+            from: from.to_string(),
+            for_user: None,
+            codec: "opus".to_string(),
+            codec_header: None,
+            packet_duration: 60,
+        })
+    }
+
+    fn stop(stream_id: u32) -> IncomingMessage {
+        IncomingMessage::Event(Event::AudioStop { stream_id })
+    }
+
+    #[test]
+    fn test_floor_follows_inbound_streams() {
+        let mut floor = FloorTracker::default();
+        assert!(floor.state().is_clear());
+
+        floor.observe(&start(1, "alice"));
+        floor.observe(&start(2, "bob"));
+        assert_eq!(
+            floor.state(),
+            FloorState::Busy {
+                stream_id: 1,
+                from: "alice".to_string()
+            }
+        );
+
+        floor.observe(&stop(1));
+        assert!(matches!(
+            floor.state(),
+            FloorState::Busy { stream_id: 2, .. }
+        ));
+        floor.observe(&stop(2));
+        assert!(floor.state().is_clear());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_talker_releases_floor() {
+        let mut floor = FloorTracker::default();
+        floor.observe(&start(1, "alice"));
+        let data = IncomingMessage::Event(Event::AudioData {
+            stream_id: 1,
+            packet_id: 0,
+            data: Vec::new(),
+        });
+
+        // Audio keeps the floor held past the silence limit
+        for _ in 0..3 {
+            tokio::time::advance(Duration::from_millis(800)).await;
+            floor.observe(&data);
+            floor.expire(Instant::now());
+        }
+        assert!(!floor.state().is_clear());
+
+        // The stop never arrives
+        let expiry = floor.next_expiry().expect("expiry");
+        assert_eq!(expiry, Instant::now() + MIN_SILENCE);
+        tokio::time::advance(MIN_SILENCE).await;
+        floor.expire(Instant::now());
+        assert!(floor.state().is_clear());
+        assert_eq!(floor.next_expiry(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_floor_policies() {
+        let mut floor = FloorTracker::default();
+        let mut state = floor.subscribe();
+        floor.observe(&start(1, "alice"));
+
+        assert!(
+            acquire_floor(&mut state, &FloorPolicy::Preempt)
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            acquire_floor(&mut state, &FloorPolicy::FailFast).await,
+            Err(ZelloError::ChannelBusy(from)) if from == "alice"
+        ));
+        assert!(matches!(
+            acquire_floor(
+                &mut state,
+                &FloorPolicy::WaitForClear(Duration::from_secs(1))
+            )
+            .await,
+            Err(ZelloError::ChannelBusy(_))
+        ));
+
+        let waiting = tokio::spawn(async move {
+            acquire_floor(
+                &mut state,
+                &FloorPolicy::WaitForClear(Duration::from_secs(5)),
+            )
+            .await
+        });
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        floor.observe(&stop(1));
+        assert!(waiting.await.expect("join").is_ok());
+    }
+}
@@ -0,0 +1,386 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! HTTP gateway that lets other services use the client without linking Rust
+
+use std::sync::Arc;
+
+use crate::audio_file::load_audio_bytes;
+use crate::client::{ClientHandle, ZelloClient};
+use crate::encoder::EncoderConfig;
+use crate::error::{Result, ZelloError};
+use crate::message::Location;
+use crate::monitor::{ChannelEvent, ChannelSnapshot};
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures_util::{Stream, stream};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, watch};
+use tracing::{info, warn};
+
+/// Settings for the gateway
+#[derive(Debug, Clone)]
+pub struct GatewayConfig {
+    /// Bearer token every request must carry
+    pub token: String,
+    /// Largest audio upload accepted, in bytes
+    pub max_audio_size: usize,
+    /// Encoding for uploaded audio that cannot be sent as it is
+    pub encoder: EncoderConfig,
+}
+
+impl GatewayConfig {
+    /// Settings requiring `token`, with defaults for the rest
+    #[must_use]
+    pub fn new(token: impl Into<String>) -> Self {
+        Self {
+            token: token.into(),
+            max_audio_size: 16 * 1024 * 1024,
+            encoder: EncoderConfig::default(),
+        }
+    }
+}
+
+/// The client the gateway drives
+#[derive(Debug)]
+pub struct Gateway {
+    handle: ClientHandle,
+    snapshot: watch::Receiver<ChannelSnapshot>,
+    events: broadcast::Receiver<ChannelEvent>,
+}
+
+impl Gateway {
+    /// Take a handle and subscriptions from `client`
+    ///
+    /// The client's message loop must be run for the gateway to work.
+    pub fn new(client: &mut ZelloClient) -> Self {
+        Self {
+            handle: client.handle(),
+            snapshot: client.channel_snapshot(),
+            events: client.channel_events(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct GatewayState {
+    handle: ClientHandle,
+    snapshot: watch::Receiver<ChannelSnapshot>,
+    events: Arc<broadcast::Receiver<ChannelEvent>>,
+    config: Arc<GatewayConfig>,
+}
+
+/// Body of `POST /text`
+#[derive(Debug, Deserialize)]
+struct TextRequest {
+    text: String,
+    /// Callsign to send to instead of the whole channel
+    to: Option<String>,
+}
+
+/// Body of `POST /location`
+#[derive(Debug, Deserialize)]
+struct LocationRequest {
+    #[serde(flatten)]
+    location: Location,
+    /// Callsign to send to instead of the whole channel
+    to: Option<String>,
+}
+
+/// Body of `GET /roster`
+#[derive(Debug, Serialize)]
+struct Roster<'a> {
+    users_online: u32,
+    roster: Vec<&'a str>,
+}
+
+/// Serve the gateway API on `listener`
+///
+/// | Method and path  | Does                                                   |
+/// |------------------|--------------------------------------------------------|
+/// | `GET /status`    | Returns the [`ChannelSnapshot`]                        |
+/// | `GET /roster`    | Returns the users online                               |
+/// | `GET /events`    | Streams each [`ChannelEvent`] as JSON server-sent events |
+/// | `POST /text`     | Sends `{"text": ..., "to": ...}`; `to` is optional     |
+/// | `POST /location` | Sends a [`Location`], with an optional `to`            |
+/// | `POST /audio`    | Transmits a WAV or Ogg/Opus file sent as the body      |
+///
+/// Every request must carry `Authorization: Bearer <token>`.
+///
+/// # Errors
+///
+/// Returns an error if the server fails
+pub async fn serve_gateway(
+    listener: TcpListener,
+    gateway: Gateway,
+    config: GatewayConfig,
+) -> Result<()> {
+    let max_audio_size = config.max_audio_size;
+    let state = GatewayState {
+        handle: gateway.handle,
+        snapshot: gateway.snapshot,
+        events: Arc::new(gateway.events),
+        config: Arc::new(config),
+    };
+    if let Ok(address) = listener.local_addr() {
+        info!("Gateway listening on http://{address}/");
+    }
+
+    let app = Router::new()
+        .route("/status", get(status))
+        .route("/roster", get(roster))
+        .route("/events", get(events))
+        .route("/text", post(send_text))
+        .route("/location", post(send_location))
+        .route(
+            "/audio",
+            post(send_audio).layer(DefaultBodyLimit::max(max_audio_size)),
+        )
+        .layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state);
+
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Refuse requests without the gateway's bearer token
+async fn require_token(
+    State(state): State<GatewayState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match presented {
+        Some(token) if tokens_match(token, &state.config.token) => next.run(request).await,
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            Json(serde_json::json!({ "error": "Missing or wrong bearer token" })),
+        )
+            .into_response(),
+    }
+}
+
+/// Compare tokens in time that does not depend on where they differ
+fn tokens_match(presented: &str, expected: &str) -> bool {
+    presented.len() == expected.len()
+        && presented
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+async fn status(State(state): State<GatewayState>) -> Json<ChannelSnapshot> {
+    Json(state.snapshot.borrow().clone())
+}
+
+async fn roster(State(state): State<GatewayState>) -> Response {
+    let snapshot = state.snapshot.borrow();
+    Json(Roster {
+        users_online: snapshot.users_online,
+        roster: snapshot.roster.iter().map(String::as_str).collect(),
+    })
+    .into_response()
+}
+
+async fn events(
+    State(state): State<GatewayState>,
+) -> Sse<impl Stream<Item = std::result::Result<Event, axum::Error>>> {
+    let events = stream::unfold(state.events.resubscribe(), |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => return Some((Event::default().json_data(&event), events)),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Gateway event subscriber is behind, skipped {missed} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+async fn send_text(
+    State(state): State<GatewayState>,
+    Json(request): Json<TextRequest>,
+) -> std::result::Result<StatusCode, GatewayError> {
+    match &request.to {
+        Some(callsign) => {
+            state
+                .handle
+                .send_text_message_to_callsign(&request.text, callsign)
+                .await?;
+        }
+        None => state.handle.send_text_message(&request.text).await?,
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn send_location(
+    State(state): State<GatewayState>,
+    Json(request): Json<LocationRequest>,
+) -> std::result::Result<StatusCode, GatewayError> {
+    state
+        .handle
+        .send_location(request.location, request.to.as_deref())
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Transmit an uploaded file, replying once it has been sent
+async fn send_audio(
+    State(state): State<GatewayState>,
+    body: Bytes,
+) -> std::result::Result<StatusCode, GatewayError> {
+    let config = state.config.encoder.clone();
+    let audio = tokio::task::spawn_blocking(move || load_audio_bytes(&body, &config))
+        .await
+        .map_err(|e| ZelloError::Unknown(e.to_string()))??;
+    info!("Gateway transmitting {}ms of audio", audio.duration_ms());
+    state.handle.transmit_audio(audio).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A client error as an HTTP response
+struct GatewayError(ZelloError);
+
+impl From<ZelloError> for GatewayError {
+    fn from(error: ZelloError) -> Self {
+        Self(error)
+    }
+}
+
+impl IntoResponse for GatewayError {
+    fn into_response(self) -> Response {
+        let status = match &self.0 {
+            ZelloError::NotConnected => StatusCode::SERVICE_UNAVAILABLE,
+            ZelloError::ChannelBusy(_) => StatusCode::CONFLICT,
+            ZelloError::TransmitLockout(_) => StatusCode::TOO_MANY_REQUESTS,
+            ZelloError::AudioError(_) | ZelloError::ConfigError(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        (
+            status,
+            Json(serde_json::json!({ "error": self.0.to_string() })),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientCommand;
+    use crate::floor::FloorState;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc;
+
+    /// Make a request and return the status code and body
+    async fn request(
+        address: std::net::SocketAddr,
+        request_line: &str,
+        token: &str,
+        body: &str,
+    ) -> (u16, String) {
+        let mut socket = tokio::net::TcpStream::connect(address)
+            .await
+            .expect("connect");
+        let request = format!(
+            "{request_line} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {token}\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        socket.write_all(request.as_bytes()).await.expect("write");
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.expect("read");
+
+        let status = response[9..12].parse().expect("status");
+        let body = response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_string())
+            .unwrap_or_default();
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn test_gateway_requests() {
+        let (commands, mut received) = mpsc::unbounded_channel();
+        let (_floor, floor_rx) = watch::channel(FloorState::Clear);
+        let (_snapshot_tx, snapshot) = watch::channel(ChannelSnapshot {
+            channel: "channel".to_string(),
+            users_online: 2,
+            roster: ["alice".to_string()].into(),
+            ..ChannelSnapshot::default()
+        });
+        let gateway = Gateway {
+            handle: ClientHandle::new(commands, broadcast::channel(1).0, floor_rx),
+            snapshot,
+            events: broadcast::channel(1).1,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let address = listener.local_addr().expect("address");
+        tokio::spawn(serve_gateway(
+            listener,
+            gateway,
+            GatewayConfig::new("secret"),
+        ));
+
+        let (status, _) = request(address, "GET /status", "wrong", "").await;
+        assert_eq!(status, 401);
+
+        let (status, body) = request(address, "GET /roster", "secret", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"users_online":2,"roster":["alice"]}"#);
+
+        let client = tokio::spawn(async move {
+            let Some(ClientCommand::SendText {
+                text,
+                for_user,
+                reply,
+            }) = received.recv().await
+            else {
+                panic!("expected a text command");
+            };
+            reply.send(Ok(())).expect("reply");
+            (text, for_user)
+        });
+        let (status, _) = request(
+            address,
+            "POST /text",
+            "secret",
+            r#"{"text":"hello","to":"bob"}"#,
+        )
+        .await;
+        assert_eq!(status, 204);
+        assert_eq!(
+            client.await.expect("join"),
+            ("hello".to_string(), Some("bob".to_string()))
+        );
+
+        let (status, body) = request(address, "POST /audio", "secret", "this is not audio").await;
+        assert_eq!(status, 422);
+        assert!(body.contains("not a WAV"));
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secreT", "secret"));
+        assert!(!tokens_match("secret2", "secret"));
+    }
+}
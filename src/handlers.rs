@@ -3,46 +3,22 @@
 
 //! Handler functions for Zello client operations
 
-use std::collections::VecDeque;
 use std::sync::Arc;
+use std::time::SystemTime;
 
+use crate::agc::AutomaticGainControl;
+use crate::inbound::{InboundText, TransmissionInfo};
+use crate::volume::apply_gain;
 use crate::{CodecHeader, Error, Event, IncomingMessage, Response, ZelloClient};
-use crate::{OPUS_CHANNELS, PCM_BUFFER_SIZE, PCM_I16_TO_F32};
+use crate::{OPUS_CHANNELS, PCM_BUFFER_SIZE};
 use anyhow::Result;
 use audiopus::{Channels, MutSignals, coder::Decoder, packet::Packet};
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::Sender;
 use tokio::sync::Mutex;
 use tracing::{Level, debug, error, info, level_enabled, warn};
 
-/// Process audio output by filling the output buffer
-pub fn process_audio_output(
-    output: &mut [f32],
-    pcm_rx: &Arc<Mutex<Receiver<Vec<i16>>>>,
-    buffer: &mut VecDeque<f32>,
-) {
-    // Try to refill buffer from channel
-    if let Ok(rx) = pcm_rx.try_lock() {
-        while let Ok(pcm) = rx.try_recv() {
-            debug!("🎤 Received PCM chunk: {} samples", pcm.len());
-            for &sample in &pcm {
-                buffer.push_back(f32::from(sample) * PCM_I16_TO_F32);
-            }
-        }
-    }
-
-    debug!(
-        "🎤 Output buffer size: {}, internal buffer: {}",
-        output.len(),
-        buffer.len()
-    );
-
-    // Fill output from buffer
-    for out in output.iter_mut() {
-        *out = buffer.pop_front().unwrap_or(0.0);
-    }
-}
-
 /// Handle incoming message from Zello
+#[allow(clippy::too_many_lines)]
 pub async fn handle_message(
     client: &mut ZelloClient,
     message: IncomingMessage,
@@ -55,24 +31,33 @@ pub async fn handle_message(
             text,
             author,
             channel,
+            for_user,
             ..
         }) => {
             handle_text_message(&from, &text, author.as_deref(), &channel);
+            client.publish_text(&InboundText {
+                channel,
+                from,
+                author,
+                for_user,
+                text,
+            });
         }
 
         IncomingMessage::Event(Event::AudioStart {
             stream_id,
             from,
+            for_user,
             codec,
             codec_header,
             channel,
             packet_duration,
-            ..
         }) => {
             if let Err(e) = handle_audio_start(
                 client,
                 stream_id,
                 from,
+                for_user,
                 codec,
                 codec_header.as_deref(),
                 channel,
@@ -83,9 +68,16 @@ pub async fn handle_message(
         }
 
         IncomingMessage::Event(Event::AudioStop { stream_id }) => {
+            // Only a stream that was being played has an end to mark
+            let played = client
+                .get_inbound_stream(stream_id)
+                .is_some_and(|s| client.volume().gain_for(s.callsign.as_deref()) > 0.0);
             if let Err(e) = handle_audio_stop(client, stream_id) {
                 warn!("Failed to handle audio stop: {}", e);
             }
+            if let Some(playback) = client.playback_stats().filter(|_| played) {
+                playback.end_transmission();
+            }
         }
 
         IncomingMessage::Event(Event::AudioData {
@@ -93,7 +85,21 @@ pub async fn handle_message(
             packet_id,
             data,
         }) => {
-            handle_audio_data(stream_id, packet_id, data, decoder, pcm_tx).await;
+            client.forward_inbound_packet(stream_id, packet_id, &data);
+            let callsign = client
+                .get_inbound_stream(stream_id)
+                .and_then(|s| s.callsign.as_deref());
+            let gain = client.volume().gain_for(callsign);
+            handle_audio_data(
+                stream_id,
+                packet_id,
+                data,
+                gain,
+                client.agc_mut(),
+                decoder,
+                pcm_tx,
+            )
+            .await;
         }
 
         IncomingMessage::Event(Event::OnlineStatus {
@@ -123,6 +129,12 @@ pub async fn handle_message(
                 success,
                 error,
             }
+            | Response::StartStream {
+                seq,
+                success,
+                error,
+                ..
+            }
             | Response::Logon {
                 seq,
                 success,
@@ -146,10 +158,12 @@ pub fn handle_text_message(from: &str, text: &str, author: Option<&str>, channel
 /// #Errors
 ///
 /// Returns an error if fail to start audio stream
+#[allow(clippy::too_many_arguments)]
 pub fn handle_audio_start(
     client: &mut ZelloClient,
     stream_id: u32,
     from: String,
+    for_user: Option<String>,
     codec: String,
     codec_header: Option<&str>,
     channel: String,
@@ -175,8 +189,22 @@ pub fn handle_audio_start(
         info!("[{channel}] 🎤 {from} started speaking on stream {stream_id}");
     }
 
+    client.announce_transmission(&TransmissionInfo {
+        stream_id,
+        channel: channel.clone(),
+        from: from.clone(),
+        for_user,
+        codec: codec.clone(),
+        codec_header: header,
+        packet_duration: packet_duration.unwrap_or_default(),
+        started_at: SystemTime::now(),
+    });
     client.add_inbound_stream(stream_id, channel, codec, Some(from))?;
 
+    if let Some(agc) = client.agc_mut() {
+        agc.reset();
+    }
+
     Ok(())
 }
 
@@ -199,15 +227,24 @@ pub fn handle_audio_stop(client: &mut ZelloClient, stream_id: u32) -> Result<()>
 }
 
 /// Handle audio data packet
+///
+/// The decoded audio is normalized by `agc`, if enabled, then scaled by `gain`
+/// ahead of the AGC's limiter; nothing is played when the gain is zero.
 pub async fn handle_audio_data(
     stream_id: u32,
     packet_id: u32,
     data: Vec<u8>,
+    gain: f32,
+    agc: Option<&mut AutomaticGainControl>,
     decoder: Arc<Mutex<Decoder>>,
     pcm_tx: &Sender<Vec<i16>>,
 ) {
     debug!("🎤 Audio data {stream_id} {packet_id}");
 
+    if gain <= 0.0 {
+        return;
+    }
+
     let channel_count = match OPUS_CHANNELS {
         Channels::Mono | Channels::Auto => 1,
         Channels::Stereo => 2,
@@ -234,7 +271,12 @@ pub async fn handle_audio_data(
 
     if let Ok(samples) = decoder.decode(Some(packet), output, false) {
         let total_samples = samples * channel_count;
-        let _ = pcm_tx.try_send(pcm_buf[..total_samples].to_vec());
+        let mut pcm = pcm_buf[..total_samples].to_vec();
+        match agc {
+            Some(agc) => agc.process_with_gain(&mut pcm, gain),
+            None => apply_gain(&mut pcm, gain),
+        }
+        let _ = pcm_tx.try_send(pcm);
     }
 }
 
@@ -0,0 +1,219 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Inbound transmissions delivered one whole utterance at a time
+
+use std::time::SystemTime;
+
+use crate::error::{Result, ZelloError};
+use crate::message::CodecHeader;
+use crate::{OPUS_CHANNELS, OPUS_SAMPLE_RATE, PCM_BUFFER_SIZE};
+use audiopus::coder::Decoder;
+use audiopus::{MutSignals, packet::Packet};
+use futures_util::{Stream, stream};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Details of an inbound transmission from its `on_stream_start` event
+#[derive(Debug, Clone)]
+pub struct TransmissionInfo {
+    /// Id of the inbound stream
+    pub stream_id: u32,
+    /// Channel the transmission is on
+    pub channel: String,
+    /// Callsign of the sender
+    pub from: String,
+    /// Recipient, if the transmission was sent to a single user
+    pub for_user: Option<String>,
+    /// Codec name, normally "opus"
+    pub codec: String,
+    /// Parameters of the Opus packets
+    pub codec_header: CodecHeader,
+    /// Duration of each packet in milliseconds
+    pub packet_duration: u32,
+    /// When the transmission started
+    pub started_at: SystemTime,
+}
+
+impl TransmissionInfo {
+    /// Duration of each packet in milliseconds
+    ///
+    /// Falls back to the codec header, then to its default, when the stream
+    /// start did not give one.
+    #[must_use]
+    pub fn effective_packet_duration(&self) -> u32 {
+        let header = &self.codec_header;
+        [
+            self.packet_duration,
+            u32::from(header.frames_per_packet) * u32::from(header.frame_size_ms),
+        ]
+        .into_iter()
+        .find(|&duration| duration > 0)
+        .unwrap_or_else(|| u32::from(CodecHeader::default().frame_size_ms))
+    }
+}
+
+/// An Opus packet of an inbound transmission
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InboundPacket {
+    pub packet_id: u32,
+    pub data: Vec<u8>,
+}
+
+/// A text message received on the channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InboundText {
+    /// Channel the message was sent on
+    pub channel: String,
+    /// Callsign of the sender
+    pub from: String,
+    /// Display name of the sender, if it differs from the callsign
+    pub author: Option<String>,
+    /// Recipient, if the message was sent to a single user
+    pub for_user: Option<String>,
+    pub text: String,
+}
+
+impl InboundText {
+    /// The name to show for the sender
+    #[must_use]
+    pub fn display_name(&self) -> &str {
+        self.author.as_deref().unwrap_or(&self.from)
+    }
+}
+
+/// A transmission being received, from [`ZelloClient::inbound_transmissions`](crate::ZelloClient::inbound_transmissions)
+///
+/// Its packets arrive while the client's message loop runs and end when the
+/// sender stops talking.
+#[derive(Debug)]
+pub struct InboundTransmission {
+    info: TransmissionInfo,
+    packets: mpsc::UnboundedReceiver<InboundPacket>,
+}
+
+impl InboundTransmission {
+    /// Create a transmission and the sender that feeds it packets
+    pub(crate) fn new(info: TransmissionInfo) -> (mpsc::UnboundedSender<InboundPacket>, Self) {
+        let (packets_tx, packets) = mpsc::unbounded_channel();
+        (packets_tx, Self { info, packets })
+    }
+
+    /// Details of the transmission
+    #[must_use]
+    pub fn info(&self) -> &TransmissionInfo {
+        &self.info
+    }
+
+    /// Wait for the next Opus packet, or `None` once the transmission has ended
+    pub async fn next_packet(&mut self) -> Option<InboundPacket> {
+        self.packets.recv().await
+    }
+
+    /// The Opus packets as a stream that ends with the transmission
+    pub fn packets(self) -> impl Stream<Item = InboundPacket> + Send + 'static {
+        stream::unfold(self.packets, |mut packets| async move {
+            let packet = packets.recv().await?;
+            Some((packet, packets))
+        })
+    }
+
+    /// The audio decoded to 16kHz mono PCM, one chunk per packet, as a stream
+    /// that ends with the transmission
+    ///
+    /// Packets that cannot be decoded are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the decoder cannot be created
+    pub fn pcm(self) -> Result<impl Stream<Item = Vec<i16>> + Send + 'static> {
+        let decoder = Decoder::new(OPUS_SAMPLE_RATE, OPUS_CHANNELS)
+            .map_err(|e| ZelloError::AudioError(e.to_string()))?;
+        let stream_id = self.info.stream_id;
+
+        Ok(stream::unfold(
+            (self.packets, decoder),
+            move |(mut packets, mut decoder)| async move {
+                loop {
+                    let packet = packets.recv().await?;
+                    match decode(&mut decoder, &packet.data) {
+                        Ok(pcm) => return Some((pcm, (packets, decoder))),
+                        Err(e) => warn!(
+                            "Failed to decode packet {} of stream {stream_id}: {e}",
+                            packet.packet_id
+                        ),
+                    }
+                }
+            },
+        ))
+    }
+}
+
+fn decode(decoder: &mut Decoder, data: &[u8]) -> std::result::Result<Vec<i16>, audiopus::Error> {
+    let mut pcm = vec![0i16; PCM_BUFFER_SIZE];
+    let packet = Packet::try_from(data)?;
+    let output = MutSignals::try_from(&mut pcm)?;
+    let samples = decoder.decode(Some(packet), output, false)?;
+    pcm.truncate(samples);
+    Ok(pcm)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::encoder::{EncoderConfig, OpusEncoder};
+    use futures_util::StreamExt;
+
+    /// A transmission of 60ms Opus packets for tests
+    pub(crate) fn info() -> TransmissionInfo {
+        TransmissionInfo {
+            stream_id: 7,
+            channel: "channel".to_string(),
+            from: "sender".to_string(),
+            for_user: None,
+            codec: "opus".to_string(),
+            codec_header: CodecHeader::default(),
+            packet_duration: 60,
+            started_at: SystemTime::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pcm_stream_ends_with_transmission() {
+        let mut encoder = OpusEncoder::new(EncoderConfig::default()).expect("encoder");
+        let (packets_tx, transmission) = InboundTransmission::new(info());
+
+        for (packet_id, data) in (0..).zip(encoder.encode(&[0i16; 960 * 3]).expect("encode")) {
+            packets_tx
+                .send(InboundPacket { packet_id, data })
+                .expect("send");
+        }
+        packets_tx
+            .send(InboundPacket {
+                packet_id: 3,
+                data: Vec::new(),
+            })
+            .expect("send");
+        drop(packets_tx);
+
+        let pcm: Vec<Vec<i16>> = transmission.pcm().expect("decoder").collect().await;
+        assert_eq!(pcm.len(), 3);
+        assert!(pcm.iter().all(|chunk| chunk.len() == 960));
+    }
+
+    #[tokio::test]
+    async fn test_packet_stream() {
+        let (packets_tx, transmission) = InboundTransmission::new(info());
+        assert_eq!(transmission.info().from, "sender");
+
+        let packet = InboundPacket {
+            packet_id: 0,
+            data: vec![1, 2, 3],
+        };
+        packets_tx.send(packet.clone()).expect("send");
+        drop(packets_tx);
+
+        let packets: Vec<_> = transmission.packets().collect().await;
+        assert_eq!(packets, [packet]);
+    }
+}
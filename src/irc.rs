@@ -0,0 +1,519 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! IRC bridge mirroring text between a Zello channel and an IRC channel
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::client::{ClientHandle, ZelloClient};
+use crate::error::{Result, ZelloError};
+use crate::monitor::ChannelEvent;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+/// Wait before reconnecting to the server after the connection drops
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Longest text sent in one message, leaving room in the 512 byte line for
+/// the prefix the server adds when relaying it
+const MAX_TEXT: usize = 400;
+
+/// Settings for the IRC bridge
+#[derive(Debug, Clone)]
+pub struct IrcConfig {
+    /// Server host name or address
+    pub host: String,
+    pub port: u16,
+    /// Nick to use, with `_` appended while it is taken
+    pub nick: String,
+    /// Channel to mirror, such as `#zello`
+    pub channel: String,
+    /// Server password, sent with `PASS`
+    pub password: Option<String>,
+    pub realname: String,
+}
+
+impl IrcConfig {
+    /// Settings for joining `channel` on the server at `host` and `port` as `nick`
+    #[must_use]
+    pub fn new(
+        host: impl Into<String>,
+        port: u16,
+        nick: impl Into<String>,
+        channel: impl Into<String>,
+    ) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            nick: nick.into(),
+            channel: channel.into(),
+            password: None,
+            realname: "Zello bridge".to_string(),
+        }
+    }
+}
+
+/// The client the bridge drives
+#[derive(Debug)]
+pub struct IrcBridge {
+    handle: ClientHandle,
+    events: broadcast::Receiver<ChannelEvent>,
+}
+
+impl IrcBridge {
+    /// Take a handle and event subscription from `client`
+    ///
+    /// The client's message loop must be run for the bridge to work.
+    pub fn new(client: &mut ZelloClient) -> Self {
+        Self {
+            handle: client.handle(),
+            events: client.channel_events(),
+        }
+    }
+}
+
+/// A line received from the server
+#[derive(Debug, PartialEq, Eq)]
+struct Line<'a> {
+    /// Nick of the sender, if the line came from a user
+    nick: Option<&'a str>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+impl<'a> Line<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (prefix, rest) = match line.strip_prefix(':') {
+            Some(prefixed) => {
+                let (prefix, rest) = prefixed.split_once(' ')?;
+                (Some(prefix), rest)
+            }
+            None => (None, line),
+        };
+        let (middle, trailing) = match rest.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => (rest, None),
+        };
+        let mut words = middle.split(' ').filter(|word| !word.is_empty());
+        let command = words.next()?;
+
+        Some(Self {
+            nick: prefix.map(|prefix| prefix.split(['!', '@']).next().unwrap_or(prefix)),
+            command,
+            params: words.chain(trailing).collect(),
+        })
+    }
+}
+
+/// How a connection to the server ended
+enum SessionEnd {
+    /// The Zello client stopped
+    ClientStopped,
+    Disconnected(String),
+}
+
+/// State kept across reconnects
+struct Bridge {
+    handle: ClientHandle,
+    events: broadcast::Receiver<ChannelEvent>,
+    /// IRC nick to send each callsign's direct messages to
+    replies: HashMap<String, String>,
+}
+
+/// Mirror text between the Zello channel and an IRC channel until the client stops
+///
+/// Channel messages go both ways, prefixed with the sender's nick or callsign.
+/// A direct message to the bridge of the form `callsign: text` is sent to that
+/// callsign alone, and its direct replies come back to the IRC user who wrote.
+/// Transmissions starting and ending appear in IRC as actions. The bridge
+/// reconnects to the server whenever the connection drops.
+///
+/// # Errors
+///
+/// Does not currently fail; connection errors are logged and retried
+pub async fn run_irc_bridge(bridge: IrcBridge, config: IrcConfig) -> Result<()> {
+    let mut bridge = Bridge {
+        handle: bridge.handle,
+        events: bridge.events,
+        replies: HashMap::new(),
+    };
+
+    loop {
+        let reason = match session(&mut bridge, &config).await {
+            Ok(SessionEnd::ClientStopped) => return Ok(()),
+            Ok(SessionEnd::Disconnected(reason)) => reason,
+            Err(e) => e.to_string(),
+        };
+        warn!("IRC connection lost ({reason}), reconnecting in {RECONNECT_DELAY:?}");
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Connect, register and relay until the connection drops or the client stops
+async fn session(bridge: &mut Bridge, config: &IrcConfig) -> Result<SessionEnd> {
+    let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    info!("IRC bridge connected to {}:{}", config.host, config.port);
+
+    let mut nick = config.nick.clone();
+    if let Some(password) = &config.password {
+        send(&mut writer, &format!("PASS {password}")).await?;
+    }
+    send(&mut writer, &format!("NICK {nick}")).await?;
+    send(
+        &mut writer,
+        &format!("USER {nick} 0 * :{}", config.realname),
+    )
+    .await?;
+
+    let mut registered = false;
+    let mut buffer = Vec::new();
+    loop {
+        tokio::select! {
+            read = reader.read_until(b'\n', &mut buffer) => {
+                if read? == 0 {
+                    return Ok(SessionEnd::Disconnected("closed by server".to_string()));
+                }
+                let text = String::from_utf8_lossy(&buffer).into_owned();
+                buffer.clear();
+                let Some(line) = Line::parse(&text) else {
+                    continue;
+                };
+                match (line.command, line.params.as_slice()) {
+                    ("PING", params) => {
+                        send(&mut writer, &format!("PONG :{}", params.first().unwrap_or(&""))).await?;
+                    }
+                    // Welcome
+                    ("001", _) => {
+                        registered = true;
+                        info!("IRC bridge joining {} as {nick}", config.channel);
+                        send(&mut writer, &format!("JOIN {}", config.channel)).await?;
+                    }
+                    // Nick in use
+                    ("433", _) if !registered => {
+                        nick.push('_');
+                        send(&mut writer, &format!("NICK {nick}")).await?;
+                    }
+                    ("PRIVMSG", [target, text]) => {
+                        if let Some(from) = line.nick {
+                            relay_to_zello(bridge, &mut writer, config, &nick, from, target, text).await?;
+                        }
+                    }
+                    ("ERROR", params) => {
+                        return Ok(SessionEnd::Disconnected(params.join(" ")));
+                    }
+                    _ => {}
+                }
+            }
+            event = bridge.events.recv() => match event {
+                Ok(event) if registered => relay_to_irc(bridge, &mut writer, config, &event).await?,
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("IRC bridge fell behind and missed {missed} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    let _ = send(&mut writer, "QUIT :Zello client stopped").await;
+                    return Ok(SessionEnd::ClientStopped);
+                }
+            },
+        }
+    }
+}
+
+/// Send an IRC message on to Zello
+async fn relay_to_zello(
+    bridge: &mut Bridge,
+    writer: &mut OwnedWriteHalf,
+    config: &IrcConfig,
+    nick: &str,
+    from: &str,
+    target: &str,
+    text: &str,
+) -> Result<()> {
+    if target.eq_ignore_ascii_case(&config.channel) {
+        let text = match text
+            .strip_prefix("\u{1}ACTION ")
+            .and_then(|action| action.strip_suffix('\u{1}'))
+        {
+            Some(action) => format!("* {from} {action}"),
+            // Other CTCP requests are not for people
+            None if text.starts_with('\u{1}') => return Ok(()),
+            None => format!("<{from}> {text}"),
+        };
+        // Sent in turn, so the channel sees lines in the order they were said
+        if let Err(e) = bridge.handle.send_text_message(&text).await {
+            warn!("Failed to send IRC message to Zello: {e}");
+        }
+    } else if target.eq_ignore_ascii_case(nick) && !text.starts_with('\u{1}') {
+        // Direct messages name the callsign they are for
+        let Some((callsign, message)) = text
+            .split_once(':')
+            .map(|(callsign, message)| (callsign.trim(), message.trim()))
+            .filter(|(callsign, message)| {
+                !callsign.is_empty() && !callsign.contains(' ') && !message.is_empty()
+            })
+        else {
+            let usage = "Send direct messages as \"callsign: text\"";
+            return send(writer, &format!("NOTICE {from} :{usage}")).await;
+        };
+        bridge
+            .replies
+            .insert(callsign.to_string(), from.to_string());
+        let text = format!("<{from}> {message}");
+        if let Err(e) = bridge
+            .handle
+            .send_text_message_to_callsign(&text, callsign)
+            .await
+        {
+            warn!("Failed to send IRC direct message to {callsign}: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// Show a Zello channel event in IRC
+async fn relay_to_irc(
+    bridge: &Bridge,
+    writer: &mut OwnedWriteHalf,
+    config: &IrcConfig,
+    event: &ChannelEvent,
+) -> Result<()> {
+    match event {
+        ChannelEvent::Text {
+            from,
+            for_user: None,
+            text,
+            ..
+        } => privmsg(writer, &config.channel, &format!("<{from}> {text}")).await,
+        ChannelEvent::Text {
+            from,
+            for_user: Some(_),
+            text,
+            ..
+        } => {
+            if let Some(irc_nick) = bridge.replies.get(from) {
+                privmsg(writer, irc_nick, &format!("<{from}> {text}")).await
+            } else {
+                debug!("No IRC user to pass {from}'s direct message to");
+                Ok(())
+            }
+        }
+        ChannelEvent::TransmissionStart { from, .. } => {
+            action(writer, &config.channel, &format!("{from} started talking")).await
+        }
+        ChannelEvent::TransmissionEnd {
+            from, duration_ms, ..
+        } => {
+            // Tenths of a second, without a lossy float conversion
+            let tenths = duration_ms / 100;
+            let talked = format!(
+                "{from} stopped talking after {}.{}s",
+                tenths / 10,
+                tenths % 10
+            );
+            action(writer, &config.channel, &talked).await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Send text as one message per line, split to fit the line length limit
+async fn privmsg(writer: &mut OwnedWriteHalf, target: &str, text: &str) -> Result<()> {
+    for chunk in split_text(text, MAX_TEXT) {
+        send(writer, &format!("PRIVMSG {target} :{chunk}")).await?;
+    }
+    Ok(())
+}
+
+async fn action(writer: &mut OwnedWriteHalf, target: &str, text: &str) -> Result<()> {
+    send(
+        writer,
+        &format!("PRIVMSG {target} :\u{1}ACTION {text}\u{1}"),
+    )
+    .await
+}
+
+/// Split text at line breaks and then into pieces of at most `max` bytes
+fn split_text(text: &str, max: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    for mut line in text.lines().filter(|line| !line.trim().is_empty()) {
+        while line.len() > max {
+            let mut end = max;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            // Prefer to break between words
+            if let Some(space) = line[..end].rfind(' ').filter(|&space| space > 0) {
+                end = space;
+            }
+            chunks.push(&line[..end]);
+            line = line[end..].trim_start();
+        }
+        chunks.push(line);
+    }
+    chunks
+}
+
+async fn send(writer: &mut OwnedWriteHalf, line: &str) -> Result<()> {
+    debug!("IRC > {line}");
+    // A line break would let the rest be read as another command
+    let line: String = line.chars().filter(|c| !matches!(c, '\r' | '\n')).collect();
+    writer
+        .write_all(format!("{line}\r\n").as_bytes())
+        .await
+        .map_err(|e| ZelloError::ConnectionError(format!("IRC write failed: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientCommand;
+    use crate::floor::FloorState;
+    use tokio::io::Lines;
+    use tokio::net::TcpListener;
+    use tokio::net::tcp::OwnedReadHalf;
+    use tokio::sync::{mpsc, watch};
+
+    async fn expect_line(lines: &mut Lines<BufReader<OwnedReadHalf>>, expected: &str) {
+        let line = lines.next_line().await.expect("read").expect("line");
+        assert_eq!(line, expected);
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            Line::parse(":bob!b@host PRIVMSG #zello :hello there\r\n"),
+            Some(Line {
+                nick: Some("bob"),
+                command: "PRIVMSG",
+                params: vec!["#zello", "hello there"],
+            })
+        );
+        assert_eq!(
+            Line::parse("PING :irc.example.com"),
+            Some(Line {
+                nick: None,
+                command: "PING",
+                params: vec!["irc.example.com"],
+            })
+        );
+        assert_eq!(Line::parse(""), None);
+    }
+
+    #[test]
+    fn test_split_text() {
+        assert_eq!(split_text("one\ntwo\n\n", 400), vec!["one", "two"]);
+        assert_eq!(split_text("aaaa bbbb cccc", 9), vec!["aaaa", "bbbb cccc"]);
+        assert_eq!(split_text("ééé", 3), vec!["é", "é", "é"]);
+    }
+
+    #[tokio::test]
+    async fn test_bridge_against_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let port = listener.local_addr().expect("address").port();
+        let (commands, mut commands_rx) = mpsc::unbounded_channel();
+        let (_floor, floor_rx) = watch::channel(FloorState::Clear);
+        let (events_tx, events) = broadcast::channel(8);
+        let bridge = IrcBridge {
+            handle: ClientHandle::new(commands, broadcast::channel(1).0, floor_rx),
+            events,
+        };
+        tokio::spawn(run_irc_bridge(
+            bridge,
+            IrcConfig::new("127.0.0.1", port, "zello", "#zello"),
+        ));
+
+        let (socket, _) = listener.accept().await.expect("accept");
+        let (reader, mut server) = socket.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        expect_line(&mut lines, "NICK zello").await;
+        expect_line(&mut lines, "USER zello 0 * :Zello bridge").await;
+        server
+            .write_all(b":irc.test 433 * zello :Nickname is already in use\r\n")
+            .await
+            .expect("write");
+        expect_line(&mut lines, "NICK zello_").await;
+        server
+            .write_all(b":irc.test 001 zello_ :Welcome\r\nPING :irc.test\r\n")
+            .await
+            .expect("write");
+        expect_line(&mut lines, "JOIN #zello").await;
+        expect_line(&mut lines, "PONG :irc.test").await;
+
+        server
+            .write_all(b":bob!b@host PRIVMSG #zello :hello everyone\r\n")
+            .await
+            .expect("write");
+        let Some(ClientCommand::SendText {
+            text,
+            for_user,
+            reply,
+        }) = commands_rx.recv().await
+        else {
+            panic!("expected a text command");
+        };
+        reply.send(Ok(())).expect("reply");
+        assert_eq!(text, "<bob> hello everyone");
+        assert_eq!(for_user, None);
+
+        // Lines reach the channel in the order they were said
+        server
+            .write_all(b":bob!b@host PRIVMSG #zello :one\r\n:bob!b@host PRIVMSG #zello :two\r\n")
+            .await
+            .expect("write");
+        for expected in ["<bob> one", "<bob> two"] {
+            let Some(ClientCommand::SendText { text, reply, .. }) = commands_rx.recv().await else {
+                panic!("expected a text command");
+            };
+            assert_eq!(text, expected);
+            reply.send(Ok(())).expect("reply");
+        }
+
+        server
+            .write_all(b":bob!b@host PRIVMSG zello_ :alice: are you there?\r\n")
+            .await
+            .expect("write");
+        let Some(ClientCommand::SendText {
+            text,
+            for_user,
+            reply,
+        }) = commands_rx.recv().await
+        else {
+            panic!("expected a text command");
+        };
+        reply.send(Ok(())).expect("reply");
+        assert_eq!(text, "<bob> are you there?");
+        assert_eq!(for_user.as_deref(), Some("alice"));
+
+        events_tx
+            .send(ChannelEvent::Text {
+                channel: "channel".to_string(),
+                from: "alice".to_string(),
+                author: None,
+                for_user: Some("zello".to_string()),
+                text: "yes".to_string(),
+            })
+            .expect("send");
+        expect_line(&mut lines, "PRIVMSG bob :<alice> yes").await;
+
+        events_tx
+            .send(ChannelEvent::TransmissionEnd {
+                stream_id: 7,
+                channel: "channel".to_string(),
+                from: "alice".to_string(),
+                duration_ms: 4250,
+            })
+            .expect("send");
+        expect_line(
+            &mut lines,
+            "PRIVMSG #zello :\u{1}ACTION alice stopped talking after 4.2s\u{1}",
+        )
+        .await;
+    }
+}
@@ -10,24 +10,74 @@
 )]
 #![doc = include_str!("../README.md")]
 
+pub mod agc;
+pub mod audio_file;
+pub mod bridge;
+pub mod capture;
 pub mod client;
+pub mod dashboard;
+pub mod encoder;
 pub mod error;
+pub mod floor;
+pub mod gateway;
 pub mod handlers;
+pub mod inbound;
+pub mod irc;
 pub mod message;
+pub mod monitor;
+pub mod mqtt;
+pub mod ogg_stream;
+pub mod outbound;
+pub mod pacing;
+pub mod parrot;
+pub mod pcm_io;
+pub mod playback;
 pub mod protocol;
+pub mod ptt;
+pub mod rtp;
+pub mod tot;
 pub mod utilities;
+pub mod volume;
+pub mod vox;
+pub mod webhook;
 
 // Re-exports for convenience
+pub use agc::{AgcConfig, AutomaticGainControl};
+pub use audio_file::{EncodedAudio, load_audio_bytes, load_audio_file};
 use audiopus::{Channels, SampleRate};
+pub use bridge::{BridgeConfig, BridgeEnd, run_bridge};
+pub use capture::{AudioInput, setup_audio_input};
 pub use client::*;
+pub use dashboard::{Dashboard, serve_dashboard};
+pub use encoder::{EncoderConfig, OPUS_CODEC, OpusEncoder};
 pub use error::{Result, ZelloError};
-pub use handlers::{handle_message, process_audio_output};
-pub use message::{CodecHeader, Error, Event, IncomingMessage, Message, Response};
+pub use floor::{FloorPolicy, FloorState};
+pub use gateway::{Gateway, GatewayConfig, serve_gateway};
+pub use handlers::handle_message;
+pub use inbound::{InboundPacket, InboundText, InboundTransmission, TransmissionInfo};
+pub use irc::{IrcBridge, IrcConfig, run_irc_bridge};
+pub use message::{CodecHeader, Error, Event, IncomingMessage, Location, Message, Response};
+pub use monitor::{ChannelEvent, ChannelSnapshot};
+pub use mqtt::{MqttBridge, MqttConfig, run_mqtt_bridge};
+pub use ogg_stream::{OggStreamConfig, serve_ogg_stream};
+pub use outbound::{OutboundStream, OutboundStreamHandle};
+pub use pacing::PacketPacer;
+pub use parrot::{ParrotConfig, record_transmission, run_parrot};
+pub use pcm_io::{PcmEndpoint, read_pcm, write_pcm};
+pub use playback::{PlaybackConfig, PlaybackStats, process_audio_output};
 pub use protocol::Protocol;
+pub use ptt::{PttControl, transmit_with_ptt};
+pub use rtp::{RtpConfig, RtpPacket, receive_rtp, send_rtp};
+pub use tot::TransmitLimits;
 pub use utilities::{
-    connect_to_zello, create_decoder, initialize_logging, load_credentials, load_dotenv,
-    setup_audio_output,
+    AudioDeviceInfo, AudioOutput, DeviceSelector, connect_to_zello, create_decoder, create_encoder,
+    initialize_logging, list_input_devices, list_output_devices, load_bridge_credentials,
+    load_credentials, load_dotenv, load_gateway_token, load_irc_password, load_mqtt_credentials,
+    load_webhook_secret, setup_audio_output, setup_audio_output_with_config,
 };
+pub use volume::{VolumeControl, VolumeSettings};
+pub use vox::{VoxAction, VoxConfig, VoxDetector, transmit_with_vox};
+pub use webhook::{WebhookConfig, run_webhooks};
 
 /// Library version
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -51,6 +51,30 @@ pub enum Message {
     /// Stop outgoing audio stream
     #[serde(rename = "stop_stream")]
     StopStream { seq: u32, stream_id: u32 },
+
+    /// Send a location
+    #[serde(rename = "send_location")]
+    SendLocation {
+        seq: u32,
+        channel: String,
+        #[serde(rename = "for")]
+        for_user: Option<String>,
+        #[serde(flatten)]
+        location: Location,
+    },
+}
+
+/// A position shared with the channel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Accuracy in meters
+    #[serde(default)]
+    pub accuracy: f64,
+    /// Human-readable address or place name
+    #[serde(default)]
+    pub formatted_address: String,
 }
 
 impl Message {
@@ -124,12 +148,47 @@ impl Message {
         }
     }
 
+    /// Create a start stream message with a codec header describing the audio
+    #[must_use]
+    pub fn start_stream_with_header(
+        seq: u32,
+        channel: String,
+        codec: String,
+        codec_header: &CodecHeader,
+        packet_duration: u32,
+    ) -> Self {
+        Self::StartStream {
+            seq,
+            channel,
+            for_user: None,
+            codec,
+            codec_header: Some(codec_header.to_base64()),
+            packet_duration,
+        }
+    }
+
     /// Create a stop stream message
     #[must_use]
     pub fn stop_stream(seq: u32, stream_id: u32) -> Self {
         Self::StopStream { seq, stream_id }
     }
 
+    /// Create a location message for the channel or a specific callsign
+    #[must_use]
+    pub fn send_location(
+        seq: u32,
+        channel: String,
+        location: Location,
+        for_user: Option<String>,
+    ) -> Self {
+        Self::SendLocation {
+            seq,
+            channel,
+            for_user,
+            location,
+        }
+    }
+
     /// Get the sequence number if present
     #[must_use]
     pub fn seq(&self) -> Option<u32> {
@@ -137,7 +196,8 @@ impl Message {
             Self::Logon { seq, .. }
             | Self::SendTextMessage { seq, .. }
             | Self::StartStream { seq, .. }
-            | Self::StopStream { seq, .. } => Some(*seq),
+            | Self::StopStream { seq, .. }
+            | Self::SendLocation { seq, .. } => Some(*seq),
         }
     }
 }
@@ -155,6 +215,15 @@ pub enum Response {
         error: Option<String>,
     },
 
+    /// Response to `start_stream`, carrying the id of the new stream
+    StartStream {
+        seq: u32,
+        success: bool,
+        stream_id: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+
     /// Generic response
     Generic {
         seq: u32,
@@ -169,7 +238,9 @@ impl Response {
     #[must_use]
     pub fn seq(&self) -> Option<u32> {
         match self {
-            Self::Logon { seq, .. } | Self::Generic { seq, .. } => Some(*seq),
+            Self::Logon { seq, .. } | Self::StartStream { seq, .. } | Self::Generic { seq, .. } => {
+                Some(*seq)
+            }
         }
     }
 
@@ -177,7 +248,9 @@ impl Response {
     #[must_use]
     pub fn is_success(&self) -> bool {
         match self {
-            Self::Logon { success, .. } | Self::Generic { success, .. } => *success,
+            Self::Logon { success, .. }
+            | Self::StartStream { success, .. }
+            | Self::Generic { success, .. } => *success,
         }
     }
 
@@ -185,7 +258,9 @@ impl Response {
     #[must_use]
     pub fn error(&self) -> Option<&str> {
         match self {
-            Self::Logon { error, .. } | Self::Generic { error, .. } => error.as_deref(),
+            Self::Logon { error, .. }
+            | Self::StartStream { error, .. }
+            | Self::Generic { error, .. } => error.as_deref(),
         }
     }
 }
@@ -369,6 +444,54 @@ mod tests {
         assert!(json.contains("Hello"));
     }
 
+    #[test]
+    fn test_start_stream_codec_header() {
+        let header = CodecHeader::default();
+        let msg = Message::start_stream_with_header(
+            7,
+            "channel".to_string(),
+            "opus".to_string(),
+            &header,
+            60,
+        );
+        let json = serde_json::to_string(&msg).expect("Failed to serialize");
+        assert!(json.contains(&format!("\"codec_header\":\"{}\"", header.to_base64())));
+
+        let decoded = CodecHeader::from_base64(&header.to_base64()).expect("Failed to decode");
+        assert_eq!(decoded.sample_rate_hz, 16000);
+        assert_eq!(decoded.frame_size_ms, 60);
+    }
+
+    #[test]
+    fn test_start_stream_response() {
+        let json = r#"{"seq":3,"success":true,"stream_id":4242}"#;
+        let msg: IncomingMessage = serde_json::from_str(json).expect("Failed to parse");
+        assert!(matches!(
+            msg,
+            IncomingMessage::Response(Response::StartStream {
+                seq: 3,
+                stream_id: 4242,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_location_serialization() {
+        let location = Location {
+            latitude: 51.5,
+            longitude: -0.12,
+            accuracy: 10.0,
+            formatted_address: "London".to_string(),
+        };
+        let msg = Message::send_location(5, "channel".to_string(), location, None);
+        let json: serde_json::Value = serde_json::to_value(&msg).expect("Failed to serialize");
+        assert_eq!(json["command"], "send_location");
+        assert_eq!(json["latitude"], 51.5);
+        assert_eq!(json["formatted_address"], "London");
+        assert_eq!(msg.seq(), Some(5));
+    }
+
     #[test]
     fn test_message_seq() {
         let msg = Message::send_text(42, "channel".to_string(), "test".to_string());
@@ -0,0 +1,379 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Channel state and events for dashboards and other services
+
+use std::collections::BTreeSet;
+use std::time::Instant;
+
+use crate::message::{Event, IncomingMessage};
+use serde::Serialize;
+use tokio::sync::{broadcast, watch};
+
+/// Events held for each subscriber before the oldest are dropped
+const EVENT_CAPACITY: usize = 256;
+
+/// The channel as last seen by the client
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ChannelSnapshot {
+    /// Whether the client is logged on
+    pub connected: bool,
+    pub channel: String,
+    /// Status from the last `on_channel_status`, such as "online"
+    pub status: Option<String>,
+    pub users_online: u32,
+    /// Callsigns last reported online by `on_online_status`
+    pub roster: BTreeSet<String>,
+    /// Callsign of whoever is talking
+    pub talker: Option<String>,
+}
+
+/// Something that happened on the channel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChannelEvent {
+    /// The client logged on or disconnected
+    Connection { connected: bool },
+    /// The channel's status changed
+    ChannelStatus {
+        channel: String,
+        status: String,
+        users_online: u32,
+    },
+    /// A user came online or went offline
+    Presence {
+        channel: String,
+        from: String,
+        online: bool,
+    },
+    /// A text message was received
+    Text {
+        channel: String,
+        from: String,
+        author: Option<String>,
+        for_user: Option<String>,
+        text: String,
+    },
+    /// Someone started talking
+    TransmissionStart {
+        stream_id: u32,
+        channel: String,
+        from: String,
+        for_user: Option<String>,
+    },
+    /// Someone stopped talking
+    TransmissionEnd {
+        stream_id: u32,
+        channel: String,
+        from: String,
+        duration_ms: u64,
+    },
+}
+
+/// An inbound transmission that has not yet stopped
+#[derive(Debug)]
+struct Talk {
+    stream_id: u32,
+    channel: String,
+    from: String,
+    started: Instant,
+}
+
+/// Follows incoming messages to keep a [`ChannelSnapshot`] and publish [`ChannelEvent`]s
+#[derive(Debug)]
+pub(crate) struct ChannelMonitor {
+    talks: Vec<Talk>,
+    snapshot: watch::Sender<ChannelSnapshot>,
+    events: broadcast::Sender<ChannelEvent>,
+}
+
+impl ChannelMonitor {
+    pub(crate) fn new(channel: &str) -> Self {
+        Self {
+            talks: Vec::new(),
+            snapshot: watch::Sender::new(ChannelSnapshot {
+                channel: channel.to_string(),
+                ..ChannelSnapshot::default()
+            }),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> watch::Receiver<ChannelSnapshot> {
+        self.snapshot.subscribe()
+    }
+
+    pub(crate) fn events(&self) -> broadcast::Receiver<ChannelEvent> {
+        self.events.subscribe()
+    }
+
+    /// Follow the connection, forgetting the channel's state when it is lost
+    pub(crate) fn set_connected(&mut self, connected: bool) {
+        if !connected {
+            self.talks.clear();
+        }
+        self.update(|snapshot| {
+            if !connected {
+                *snapshot = ChannelSnapshot {
+                    channel: std::mem::take(&mut snapshot.channel),
+                    ..ChannelSnapshot::default()
+                };
+            }
+            snapshot.connected = connected;
+        });
+        let _ = self.events.send(ChannelEvent::Connection { connected });
+    }
+
+    /// Update the snapshot and publish an event for an incoming message
+    pub(crate) fn observe(&mut self, message: &IncomingMessage) {
+        let IncomingMessage::Event(event) = message else {
+            return;
+        };
+
+        let event = match event {
+            Event::TextMessage {
+                channel,
+                from,
+                for_user,
+                text,
+                author,
+                ..
+            } => ChannelEvent::Text {
+                channel: channel.clone(),
+                from: from.clone(),
+                author: author.clone(),
+                for_user: for_user.clone(),
+                text: text.clone(),
+            },
+            Event::AudioStart {
+                stream_id,
+                channel,
+                from,
+                for_user,
+                ..
+            } => {
+                self.talks.push(Talk {
+                    stream_id: *stream_id,
+                    channel: channel.clone(),
+                    from: from.clone(),
+                    started: Instant::now(),
+                });
+                self.update_talker();
+                ChannelEvent::TransmissionStart {
+                    stream_id: *stream_id,
+                    channel: channel.clone(),
+                    from: from.clone(),
+                    for_user: for_user.clone(),
+                }
+            }
+            Event::AudioStop { stream_id } => {
+                let Some(index) = self.talks.iter().position(|t| t.stream_id == *stream_id) else {
+                    return;
+                };
+                let talk = self.talks.remove(index);
+                self.update_talker();
+                ChannelEvent::TransmissionEnd {
+                    stream_id: talk.stream_id,
+                    channel: talk.channel,
+                    from: talk.from,
+                    duration_ms: u64::try_from(talk.started.elapsed().as_millis())
+                        .unwrap_or(u64::MAX),
+                }
+            }
+            Event::AudioData { .. } => return,
+            Event::ChannelStatus {
+                channel,
+                status,
+                users_online,
+                ..
+            } => {
+                self.update(|snapshot| {
+                    snapshot.status = Some(status.clone());
+                    snapshot.users_online = *users_online;
+                });
+                ChannelEvent::ChannelStatus {
+                    channel: channel.clone(),
+                    status: status.clone(),
+                    users_online: *users_online,
+                }
+            }
+            Event::OnlineStatus {
+                channel,
+                from,
+                online,
+            } => {
+                self.update(|snapshot| {
+                    if *online {
+                        snapshot.roster.insert(from.clone());
+                    } else {
+                        snapshot.roster.remove(from);
+                    }
+                });
+                ChannelEvent::Presence {
+                    channel: channel.clone(),
+                    from: from.clone(),
+                    online: *online,
+                }
+            }
+        };
+
+        // Nobody may be listening
+        let _ = self.events.send(event);
+    }
+
+    /// The floor passes to whoever has been talking longest
+    fn update_talker(&mut self) {
+        let talker = self.talks.first().map(|t| t.from.clone());
+        self.update(|snapshot| snapshot.talker = talker);
+    }
+
+    fn update(&self, change: impl FnOnce(&mut ChannelSnapshot)) {
+        self.snapshot.send_if_modified(|snapshot| {
+            let before = snapshot.clone();
+            change(snapshot);
+            *snapshot != before
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event: Event) -> IncomingMessage {
+        IncomingMessage::Event(event)
+    }
+
+    #[test]
+    fn test_snapshot_follows_events() {
+        let mut monitor = ChannelMonitor::new("channel");
+        let snapshot = monitor.snapshot();
+        monitor.set_connected(true);
+
+        monitor.observe(&event(Event::ChannelStatus {
+            channel: "channel".to_string(),
+            status: "online".to_string(),
+            users_online: 3,
+            images: None,
+        }));
+        for (from, online) in [("alice", true), ("bob", true), ("alice", false)] {
+            monitor.observe(&event(Event::OnlineStatus {
+                channel: "channel".to_string(),
+                from: from.to_string(),
+                online,
+            }));
+        }
+        monitor.observe(&event(Event::AudioStart {
+            stream_id: 7,
+            channel: "channel".to_string(),
+            from: "bob".to_string(),
+            for_user: None,
+            codec: "opus".to_string(),
+            codec_header: None,
+            packet_duration: 60,
+        }));
+
+        assert_eq!(
+            *snapshot.borrow(),
+            ChannelSnapshot {
+                connected: true,
+                channel: "channel".to_string(),
+                status: Some("online".to_string()),
+                users_online: 3,
+                roster: BTreeSet::from(["bob".to_string()]),
+                talker: Some("bob".to_string()),
+            }
+        );
+
+        monitor.observe(&event(Event::AudioStop { stream_id: 7 }));
+        assert_eq!(snapshot.borrow().talker, None);
+    }
+
+    #[test]
+    fn test_disconnect_clears_state() {
+        let mut monitor = ChannelMonitor::new("channel");
+        let snapshot = monitor.snapshot();
+        monitor.set_connected(true);
+        monitor.observe(&event(Event::ChannelStatus {
+            channel: "channel".to_string(),
+            status: "online".to_string(),
+            users_online: 2,
+            images: None,
+        }));
+        monitor.observe(&event(Event::OnlineStatus {
+            channel: "channel".to_string(),
+            from: "alice".to_string(),
+            online: true,
+        }));
+        monitor.observe(&event(Event::AudioStart {
+            stream_id: 7,
+            channel: "channel".to_string(),
+            from: "alice".to_string(),
+            for_user: None,
+            codec: "opus".to_string(),
+            codec_header: None,
+            packet_duration: 60,
+        }));
+
+        monitor.set_connected(false);
+        assert_eq!(
+            *snapshot.borrow(),
+            ChannelSnapshot {
+                channel: "channel".to_string(),
+                ..ChannelSnapshot::default()
+            }
+        );
+
+        // The talk is not resumed after reconnecting
+        monitor.set_connected(true);
+        monitor.observe(&event(Event::AudioStart {
+            stream_id: 8,
+            channel: "channel".to_string(),
+            from: "bob".to_string(),
+            for_user: None,
+            codec: "opus".to_string(),
+            codec_header: None,
+            packet_duration: 60,
+        }));
+        assert_eq!(snapshot.borrow().talker.as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn test_events_are_published() {
+        let mut monitor = ChannelMonitor::new("channel");
+        let mut events = monitor.events();
+
+        monitor.observe(&event(Event::TextMessage {
+            message_id: 1,
+            channel: "channel".to_string(),
+            from: "alice".to_string(),
+            for_user: None,
+            text: "hello".to_string(),
+            author: None,
+        }));
+        monitor.observe(&event(Event::AudioStart {
+            stream_id: 7,
+            channel: "channel".to_string(),
+            from: "alice".to_string(),
+            for_user: None,
+            codec: "opus".to_string(),
+            codec_header: None,
+            packet_duration: 60,
+        }));
+        monitor.observe(&event(Event::AudioStop { stream_id: 7 }));
+        // A stop for a stream that never started is ignored
+        monitor.observe(&event(Event::AudioStop { stream_id: 8 }));
+
+        let text = events.try_recv().expect("text");
+        assert_eq!(serde_json::to_value(&text).expect("json")["type"], "text");
+        assert!(matches!(
+            events.try_recv(),
+            Ok(ChannelEvent::TransmissionStart { stream_id: 7, .. })
+        ));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(ChannelEvent::TransmissionEnd { stream_id: 7, from, .. }) if from == "alice"
+        ));
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,490 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! MQTT bridge publishing channel events and taking commands
+
+use std::time::Duration;
+
+use crate::audio_file::load_audio_bytes;
+use crate::client::{ClientHandle, ZelloClient};
+use crate::encoder::EncoderConfig;
+use crate::error::{Result, ZelloError};
+use crate::monitor::ChannelEvent;
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, Publish, QoS};
+use serde::Deserialize;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info, warn};
+
+/// Requests queued for the broker before publishing waits
+const REQUEST_CAPACITY: usize = 64;
+
+/// Wait before reconnecting to the broker after the connection fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Settings for the MQTT bridge
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    /// Broker host name or address
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// Username and password for the broker
+    pub credentials: Option<(String, String)>,
+    /// Every topic starts with this
+    pub topic_prefix: String,
+    /// Quality of service for publishing and subscribing
+    pub qos: QoS,
+    pub keep_alive: Duration,
+    /// Largest audio clip accepted on the audio command topic, in bytes
+    pub max_audio_size: usize,
+    /// Encoding for audio clips that cannot be sent as they are
+    pub encoder: EncoderConfig,
+}
+
+impl MqttConfig {
+    /// Settings for the broker at `host` and `port`, with defaults for the rest
+    #[must_use]
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            client_id: "zello-client".to_string(),
+            credentials: None,
+            topic_prefix: "zello".to_string(),
+            qos: QoS::AtLeastOnce,
+            keep_alive: Duration::from_secs(30),
+            max_audio_size: 16 * 1024 * 1024,
+            encoder: EncoderConfig::default(),
+        }
+    }
+
+    fn options(&self) -> MqttOptions {
+        let mut options = MqttOptions::new(&self.client_id, &self.host, self.port);
+        options
+            .set_keep_alive(self.keep_alive)
+            .set_max_packet_size(self.max_audio_size, self.max_audio_size)
+            .set_last_will(LastWill::new(
+                self.topic("status"),
+                "offline",
+                self.qos,
+                true,
+            ));
+        if let Some((username, password)) = &self.credentials {
+            options.set_credentials(username, password);
+        }
+        options
+    }
+
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{name}", self.topic_prefix)
+    }
+}
+
+/// The client the bridge drives
+#[derive(Debug)]
+pub struct MqttBridge {
+    handle: ClientHandle,
+    events: broadcast::Receiver<ChannelEvent>,
+}
+
+impl MqttBridge {
+    /// Take a handle and event subscription from `client`
+    ///
+    /// The client's message loop must be run for the bridge to work.
+    pub fn new(client: &mut ZelloClient) -> Self {
+        Self {
+            handle: client.handle(),
+            events: client.channel_events(),
+        }
+    }
+}
+
+/// Payload of the text command topic when it is JSON
+#[derive(Debug, Deserialize)]
+struct TextCommand {
+    text: String,
+    /// Callsign to send to instead of the whole channel
+    to: Option<String>,
+}
+
+/// Bridge the client to an MQTT broker until the client stops
+///
+/// With the default prefix of `zello`:
+///
+/// | Topic                  | Carries                                                 |
+/// |------------------------|---------------------------------------------------------|
+/// | `zello/status`         | `online`, or `offline` as the retained last will        |
+/// | `zello/event/<type>`   | Each [`ChannelEvent`] as JSON, by its `type`            |
+/// | `zello/command/text`   | Text to send, or `{"text": ..., "to": ...}`             |
+/// | `zello/command/audio`  | A WAV or Ogg/Opus clip to transmit                      |
+///
+/// Connection and channel status events are retained so new subscribers see
+/// the current state. The connection to the broker is retried until it succeeds.
+///
+/// # Errors
+///
+/// Returns an error if an event cannot be published
+pub async fn run_mqtt_bridge(bridge: MqttBridge, config: MqttConfig) -> Result<()> {
+    let (client, eventloop) = AsyncClient::new(config.options(), REQUEST_CAPACITY);
+    info!(
+        "MQTT bridge connecting to {}:{} as {}",
+        config.host, config.port, config.client_id
+    );
+
+    let (texts_tx, texts) = mpsc::unbounded_channel();
+    tokio::select! {
+        result = publish_events(&client, bridge.events, &config) => result,
+        () = poll_broker(&client, eventloop, &bridge.handle, &texts_tx, &config) => Ok(()),
+        () = send_texts(&bridge.handle, texts) => Ok(()),
+    }
+}
+
+/// Publish each channel event until the client stops
+async fn publish_events(
+    client: &AsyncClient,
+    mut events: broadcast::Receiver<ChannelEvent>,
+    config: &MqttConfig,
+) -> Result<()> {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("MQTT bridge fell behind and missed {missed} events");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+
+        let payload = serde_json::to_value(&event)?;
+        let Some(kind) = payload["type"].as_str() else {
+            continue;
+        };
+        let retain = matches!(
+            event,
+            ChannelEvent::Connection { .. } | ChannelEvent::ChannelStatus { .. }
+        );
+        client
+            .publish(
+                config.topic(&format!("event/{kind}")),
+                config.qos,
+                retain,
+                payload.to_string(),
+            )
+            .await
+            .map_err(|e| ZelloError::ConnectionError(format!("MQTT publish failed: {e}")))?;
+    }
+}
+
+/// Drive the broker connection, subscribing on each connect and running commands
+async fn poll_broker(
+    client: &AsyncClient,
+    mut eventloop: EventLoop,
+    handle: &ClientHandle,
+    texts: &mpsc::UnboundedSender<Publish>,
+    config: &MqttConfig,
+) {
+    let text_topic = config.topic("command/text");
+    let audio_topic = config.topic("command/audio");
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("MQTT bridge connected to {}:{}", config.host, config.port);
+                // Requests are only sent while this loop polls, so they must not wait
+                for topic in [&text_topic, &audio_topic] {
+                    if let Err(e) = client.try_subscribe(topic, config.qos) {
+                        warn!("Failed to subscribe to {topic}: {e}");
+                    }
+                }
+                if let Err(e) =
+                    client.try_publish(config.topic("status"), config.qos, true, "online")
+                {
+                    warn!("Failed to publish MQTT bridge status: {e}");
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                if publish.topic == text_topic {
+                    let _ = texts.send(publish);
+                } else if publish.topic == audio_topic {
+                    tokio::spawn(send_audio(handle.clone(), publish, config.encoder.clone()));
+                } else {
+                    debug!("Ignoring MQTT message on {}", publish.topic);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("MQTT connection failed, retrying in {RECONNECT_DELAY:?}: {e}");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Send text commands one at a time, so they reach the channel in order
+async fn send_texts(handle: &ClientHandle, mut texts: mpsc::UnboundedReceiver<Publish>) {
+    while let Some(publish) = texts.recv().await {
+        send_text(handle, publish).await;
+    }
+}
+
+async fn send_text(handle: &ClientHandle, publish: Publish) {
+    let Ok(payload) = std::str::from_utf8(&publish.payload) else {
+        warn!("Ignoring text command that is not UTF-8");
+        return;
+    };
+    // Plain text goes to the whole channel
+    let command = serde_json::from_str(payload).unwrap_or_else(|_| TextCommand {
+        text: payload.to_string(),
+        to: None,
+    });
+
+    let result = match &command.to {
+        Some(callsign) => {
+            handle
+                .send_text_message_to_callsign(&command.text, callsign)
+                .await
+        }
+        None => handle.send_text_message(&command.text).await,
+    };
+    if let Err(e) = result {
+        warn!("MQTT text command failed: {e}");
+    }
+}
+
+async fn send_audio(handle: ClientHandle, publish: Publish, config: EncoderConfig) {
+    let result = async {
+        let audio =
+            tokio::task::spawn_blocking(move || load_audio_bytes(&publish.payload, &config))
+                .await
+                .map_err(|e| ZelloError::Unknown(e.to_string()))??;
+        info!(
+            "MQTT bridge transmitting {}ms of audio",
+            audio.duration_ms()
+        );
+        handle.transmit_audio(audio).await
+    };
+    if let Err(e) = result.await {
+        warn!("MQTT audio command failed: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientCommand;
+    use crate::floor::FloorState;
+    use rumqttd::{Broker, Config, ConnectionSettings, RouterConfig, ServerSettings};
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use tokio::sync::watch;
+
+    /// Start an in-process broker, returning the port it listens on
+    fn start_broker() -> u16 {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("free port")
+            .port();
+        let server = ServerSettings {
+            name: "v4".to_string(),
+            listen: ([127, 0, 0, 1], port).into(),
+            tls: None,
+            next_connection_delay_ms: 1,
+            connections: ConnectionSettings {
+                connection_timeout_ms: 5000,
+                max_payload_size: 1024 * 1024,
+                max_inflight_count: 100,
+                auth: None,
+                external_auth: None,
+                dynamic_filters: true,
+            },
+        };
+        let config = Config {
+            router: RouterConfig {
+                max_connections: 10,
+                max_outgoing_packet_count: 200,
+                max_segment_size: 1024 * 1024,
+                max_segment_count: 10,
+                ..RouterConfig::default()
+            },
+            v4: Some(HashMap::from([("1".to_string(), server)])),
+            ..Config::default()
+        };
+        std::thread::spawn(move || {
+            Broker::new(config).start().expect("broker");
+        });
+
+        while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        port
+    }
+
+    /// Connect to the broker and subscribe to every bridge topic
+    async fn subscriber(port: u16, id: &str) -> (AsyncClient, mpsc::UnboundedReceiver<Publish>) {
+        let (client, mut eventloop) =
+            AsyncClient::new(MqttOptions::new(id, "127.0.0.1", port), REQUEST_CAPACITY);
+        client
+            .subscribe("zello/#", QoS::AtLeastOnce)
+            .await
+            .expect("subscribe");
+        let (received_tx, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(event) = eventloop.poll().await {
+                if let Event::Incoming(Packet::Publish(publish)) = event {
+                    let _ = received_tx.send(publish);
+                }
+            }
+        });
+        (client, received)
+    }
+
+    /// The next message received on `topic`
+    async fn next_on(received: &mut mpsc::UnboundedReceiver<Publish>, topic: &str) -> Publish {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let publish = received.recv().await.expect("subscriber stopped");
+                if publish.topic == topic {
+                    return publish;
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("nothing on {topic}"))
+    }
+
+    async fn next_command(commands: &mut mpsc::UnboundedReceiver<ClientCommand>) -> ClientCommand {
+        tokio::time::timeout(Duration::from_secs(5), commands.recv())
+            .await
+            .expect("no command in time")
+            .expect("bridge stopped")
+    }
+
+    /// A short WAV clip of silence
+    fn wav_clip() -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 16000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut wav = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut wav, spec).expect("writer");
+        // 180ms, three 60ms packets
+        for _ in 0..2880 {
+            writer.write_sample(0i16).expect("sample");
+        }
+        writer.finalize().expect("finalize");
+        wav.into_inner()
+    }
+
+    #[tokio::test]
+    async fn test_bridge_against_broker() {
+        let port = start_broker();
+        let (publisher, mut received) = subscriber(port, "publisher").await;
+
+        let (commands, mut commands_rx) = mpsc::unbounded_channel();
+        let (_floor, floor_rx) = watch::channel(FloorState::Clear);
+        let (events_tx, events) = broadcast::channel(8);
+        let bridge = MqttBridge {
+            handle: ClientHandle::new(commands, broadcast::channel(1).0, floor_rx),
+            events,
+        };
+        let running = tokio::spawn(run_mqtt_bridge(bridge, MqttConfig::new("127.0.0.1", port)));
+
+        let status = next_on(&mut received, "zello/status").await;
+        assert_eq!(&status.payload[..], b"online");
+
+        events_tx
+            .send(ChannelEvent::Connection { connected: true })
+            .expect("send");
+        events_tx
+            .send(ChannelEvent::Presence {
+                channel: "channel".to_string(),
+                from: "alice".to_string(),
+                online: true,
+            })
+            .expect("send");
+        next_on(&mut received, "zello/event/connection").await;
+        let presence = next_on(&mut received, "zello/event/presence").await;
+        assert_eq!(presence.qos, QoS::AtLeastOnce);
+        let payload: serde_json::Value = serde_json::from_slice(&presence.payload).expect("json");
+        assert_eq!(payload["from"], "alice");
+
+        // A late subscriber gets the retained state, in any order, but not past events
+        let (_late, mut late) = subscriber(port, "late").await;
+        let mut retained = HashMap::new();
+        while retained.len() < 2 {
+            let publish = tokio::time::timeout(Duration::from_secs(5), late.recv())
+                .await
+                .expect("retained state")
+                .expect("subscriber stopped");
+            assert!(publish.retain, "{} was not retained", publish.topic);
+            retained.insert(publish.topic, publish.payload);
+        }
+        assert_eq!(&retained["zello/status"][..], b"online");
+        assert!(retained.contains_key("zello/event/connection"));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), late.recv())
+                .await
+                .is_err()
+        );
+
+        for payload in [r#"{"text":"hello","to":"bob"}"#, "plain text"] {
+            publisher
+                .publish("zello/command/text", QoS::AtLeastOnce, false, payload)
+                .await
+                .expect("publish");
+        }
+        for (expected_text, expected_for) in [("hello", Some("bob")), ("plain text", None)] {
+            let ClientCommand::SendText {
+                text,
+                for_user,
+                reply,
+            } = next_command(&mut commands_rx).await
+            else {
+                panic!("expected a text command");
+            };
+            reply.send(Ok(())).expect("reply");
+            assert_eq!(text, expected_text);
+            assert_eq!(for_user.as_deref(), expected_for);
+        }
+
+        publisher
+            .publish("zello/command/audio", QoS::AtLeastOnce, false, wav_clip())
+            .await
+            .expect("publish");
+        let ClientCommand::StartStream {
+            codec,
+            packet_duration,
+            reply,
+            ..
+        } = next_command(&mut commands_rx).await
+        else {
+            panic!("expected a stream to start");
+        };
+        assert_eq!((codec.as_str(), packet_duration), ("opus", 60));
+        reply.send(Ok(5)).expect("reply");
+        let mut packets = 0;
+        loop {
+            match next_command(&mut commands_rx).await {
+                ClientCommand::SendAudioPacket { stream_id: 5, .. } => packets += 1,
+                ClientCommand::StopStream {
+                    stream_id: 5,
+                    reply,
+                } => {
+                    if let Some(reply) = reply {
+                        reply.send(Ok(())).expect("reply");
+                    }
+                    break;
+                }
+                other => panic!("unexpected command {other:?}"),
+            }
+        }
+        assert_eq!(packets, 3);
+
+        // Losing the bridge's connection publishes its last will
+        running.abort();
+        let status = next_on(&mut late, "zello/status").await;
+        assert_eq!(&status.payload[..], b"offline");
+    }
+}
@@ -0,0 +1,368 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Live channel audio served over HTTP as an Icecast-style Ogg/Opus stream
+
+use std::collections::hash_map::RandomState;
+use std::convert::Infallible;
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::audio_file::{EncodedAudio, packet_duration_us};
+use crate::error::Result;
+use crate::inbound::{InboundPacket, InboundTransmission};
+use axum::Router;
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use futures_util::stream;
+use ogg::{PacketWriteEndInfo, PacketWriter};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::{MissedTickBehavior, interval};
+use tracing::{debug, info};
+
+/// A 20ms Opus frame of silence, sent between transmissions
+const SILENCE_PACKET: [u8; 3] = [0xf8, 0xff, 0xfe];
+
+/// Duration of [`SILENCE_PACKET`]
+const SILENCE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Ogg/Opus granule positions count 48kHz samples whatever the audio bandwidth
+const GRANULE_RATE: u64 = 48_000;
+
+/// Settings for the HTTP audio stream
+#[derive(Debug, Clone)]
+pub struct OggStreamConfig {
+    /// Stream name sent to listeners in the `icy-name` header
+    pub name: String,
+    /// Opus packets held for each listener; a listener that falls further
+    /// behind misses audio
+    pub listener_buffer: usize,
+}
+
+impl Default for OggStreamConfig {
+    fn default() -> Self {
+        Self {
+            name: "Zello".to_string(),
+            listener_buffer: 250,
+        }
+    }
+}
+
+/// An Opus packet and its duration in 48kHz samples
+#[derive(Debug)]
+struct StreamPacket {
+    data: Vec<u8>,
+    samples: u64,
+}
+
+/// Queues of the connected listeners
+type Listeners = Arc<Mutex<Vec<mpsc::Sender<Arc<StreamPacket>>>>>;
+
+#[derive(Clone)]
+struct StreamState {
+    listeners: Listeners,
+    config: Arc<OggStreamConfig>,
+}
+
+/// Serve each transmission to HTTP listeners as one continuous Ogg/Opus stream
+///
+/// Any path on `listener` returns the stream. Received Opus packets are
+/// passed through unchanged and comfort silence fills the gaps between
+/// transmissions, so players stay connected. Returns when the client stops
+/// delivering transmissions.
+///
+/// # Errors
+///
+/// Returns an error if the server fails
+pub async fn serve_ogg_stream(
+    listener: TcpListener,
+    transmissions: mpsc::UnboundedReceiver<InboundTransmission>,
+    config: OggStreamConfig,
+) -> Result<()> {
+    let state = StreamState {
+        listeners: Arc::default(),
+        config: Arc::new(config),
+    };
+    if let Ok(address) = listener.local_addr() {
+        info!("Serving channel audio at http://{address}/");
+    }
+
+    let app = Router::new()
+        .fallback(get(listen))
+        .with_state(state.clone());
+    tokio::select! {
+        result = axum::serve(listener, app) => Ok(result?),
+        () = broadcast(transmissions, &state.listeners) => Ok(()),
+    }
+}
+
+/// Start a listener's stream with the Ogg/Opus headers
+async fn listen(State(state): State<StreamState>) -> Response {
+    let (packet_tx, packet_rx) = mpsc::channel(state.config.listener_buffer.max(1));
+    state
+        .listeners
+        .lock()
+        .expect("listeners lock poisoned")
+        .push(packet_tx);
+
+    let mut muxer = OggMuxer::new();
+    let headers = muxer.headers(&state.config.name);
+    let pages = stream::unfold(
+        (Some(headers), packet_rx, muxer),
+        |(headers, mut packet_rx, mut muxer)| async move {
+            let page = if let Some(headers) = headers {
+                headers
+            } else {
+                let packet = packet_rx.recv().await?;
+                muxer.packet(&packet)
+            };
+            Some((
+                Ok::<_, Infallible>(Bytes::from(page)),
+                (None, packet_rx, muxer),
+            ))
+        },
+    );
+
+    (
+        [
+            (header::CONTENT_TYPE, "audio/ogg".to_string()),
+            (header::CACHE_CONTROL, "no-cache, no-store".to_string()),
+            (
+                header::HeaderName::from_static("icy-name"),
+                state.config.name.clone(),
+            ),
+        ],
+        Body::from_stream(pages),
+    )
+        .into_response()
+}
+
+/// Send transmissions to every listener in real time, with silence between them
+async fn broadcast(
+    mut transmissions: mpsc::UnboundedReceiver<InboundTransmission>,
+    listeners: &Listeners,
+) {
+    let mut current: Option<InboundTransmission> = None;
+    let mut silence = interval(SILENCE_INTERVAL);
+    silence.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            transmission = transmissions.recv(), if current.is_none() => {
+                let Some(transmission) = transmission else {
+                    return;
+                };
+                debug!(
+                    "Streaming {} from {}",
+                    transmission.info().stream_id,
+                    transmission.info().from
+                );
+                current = Some(transmission);
+            }
+            packet = next_packet(&mut current) => {
+                if let Some(packet) = packet {
+                    let fallback = current
+                        .as_ref()
+                        .map_or(0, |t| t.info().packet_duration);
+                    send_to_listeners(listeners, stream_packet(packet, fallback));
+                } else {
+                    current = None;
+                    silence.reset();
+                }
+            }
+            _ = silence.tick(), if current.is_none() => {
+                send_to_listeners(listeners, StreamPacket {
+                    data: SILENCE_PACKET.to_vec(),
+                    samples: GRANULE_RATE / 50,
+                });
+            }
+        }
+    }
+}
+
+/// The next packet of the current transmission, or `None` when it ends
+async fn next_packet(current: &mut Option<InboundTransmission>) -> Option<InboundPacket> {
+    match current {
+        Some(transmission) => transmission.next_packet().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn stream_packet(packet: InboundPacket, fallback_ms: u32) -> StreamPacket {
+    let duration_us = packet_duration_us(&packet.data).unwrap_or(fallback_ms * 1000);
+    StreamPacket {
+        samples: u64::from(duration_us) * GRANULE_RATE / 1_000_000,
+        data: packet.data,
+    }
+}
+
+/// Queue a packet for each listener, forgetting listeners that have gone
+fn send_to_listeners(listeners: &Listeners, packet: StreamPacket) {
+    let packet = Arc::new(packet);
+    listeners
+        .lock()
+        .expect("listeners lock poisoned")
+        .retain(|listener| match listener.try_send(packet.clone()) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                debug!("Listener is behind, dropping a packet");
+                true
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        });
+}
+
+/// An Ogg/Opus file holding `audio`
+pub(crate) fn ogg_opus_file(audio: &EncodedAudio, title: &str) -> Vec<u8> {
+    let mut muxer = OggMuxer::new();
+    let mut file = muxer.headers(title);
+    let samples = u64::from(audio.packet_duration) * GRANULE_RATE / 1000;
+    for data in &audio.packets {
+        file.extend(muxer.packet(&StreamPacket {
+            data: data.clone(),
+            samples,
+        }));
+    }
+    file
+}
+
+/// Wraps Opus packets in Ogg pages for one listener
+struct OggMuxer {
+    writer: PacketWriter<'static, Vec<u8>>,
+    serial: u32,
+    granule: u64,
+}
+
+impl OggMuxer {
+    fn new() -> Self {
+        let seed = RandomState::new().hash_one(SystemTime::now()).to_be_bytes();
+        Self {
+            writer: PacketWriter::new(Vec::new()),
+            serial: u32::from_be_bytes([seed[0], seed[1], seed[2], seed[3]]),
+            granule: 0,
+        }
+    }
+
+    /// The identification and comment header pages
+    fn headers(&mut self, name: &str) -> Vec<u8> {
+        let mut head = b"OpusHead".to_vec();
+        head.push(1); // Version
+        head.push(1); // Mono
+        head.extend_from_slice(&0u16.to_le_bytes()); // Pre-skip
+        head.extend_from_slice(&16_000u32.to_le_bytes()); // Input sample rate
+        head.extend_from_slice(&0i16.to_le_bytes()); // Output gain
+        head.push(0); // Channel mapping family
+
+        let vendor = concat!("zello-client ", env!("CARGO_PKG_VERSION"));
+        let title = format!("TITLE={name}");
+        let mut tags = b"OpusTags".to_vec();
+        push_string(&mut tags, vendor);
+        tags.extend_from_slice(&1u32.to_le_bytes()); // Comment count
+        push_string(&mut tags, &title);
+
+        self.write(head, 0);
+        self.write(tags, 0);
+        self.take()
+    }
+
+    /// A page holding one audio packet
+    fn packet(&mut self, packet: &StreamPacket) -> Vec<u8> {
+        self.granule += packet.samples;
+        self.write(packet.data.clone(), self.granule);
+        self.take()
+    }
+
+    fn write(&mut self, data: Vec<u8>, granule: u64) {
+        // Writing to a Vec cannot fail
+        let _ = self
+            .writer
+            .write_packet(data, self.serial, PacketWriteEndInfo::EndPage, granule);
+    }
+
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self.writer.inner_mut())
+    }
+}
+
+/// Append a length-prefixed string to a comment header
+fn push_string(header: &mut Vec<u8>, value: &str) {
+    let length = u32::try_from(value.len()).unwrap_or(u32::MAX);
+    header.extend_from_slice(&length.to_le_bytes());
+    header.extend_from_slice(value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_muxer_pages() {
+        let mut muxer = OggMuxer::new();
+        let mut bytes = muxer.headers("Test");
+        for _ in 0..2 {
+            bytes.extend(muxer.packet(&StreamPacket {
+                data: SILENCE_PACKET.to_vec(),
+                samples: 960,
+            }));
+        }
+
+        let mut reader = ogg::PacketReader::new(std::io::Cursor::new(bytes));
+        let head = reader.read_packet_expected().expect("head");
+        assert!(head.data.starts_with(b"OpusHead"));
+        let tags = reader.read_packet_expected().expect("tags");
+        assert!(tags.data.starts_with(b"OpusTags"));
+        assert!(tags.data.ends_with(b"TITLE=Test"));
+
+        for granule in [960, 1920] {
+            let audio = reader.read_packet_expected().expect("audio");
+            assert_eq!(audio.data, SILENCE_PACKET);
+            assert_eq!(audio.absgp_page(), granule);
+        }
+    }
+
+    #[test]
+    fn test_silence_packet_duration() {
+        assert_eq!(packet_duration_us(&SILENCE_PACKET), Some(20_000));
+    }
+
+    #[tokio::test]
+    async fn test_serves_stream_with_silence() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let address = listener.local_addr().expect("address");
+        let (_transmissions_tx, transmissions) = mpsc::unbounded_channel();
+        tokio::spawn(serve_ogg_stream(
+            listener,
+            transmissions,
+            OggStreamConfig::default(),
+        ));
+
+        let mut socket = tokio::net::TcpStream::connect(address)
+            .await
+            .expect("connect");
+        socket
+            .write_all(b"GET /stream.ogg HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .expect("request");
+
+        let mut response = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while response.windows(4).filter(|w| w == b"OggS").count() < 3 {
+            let size = socket.read(&mut buffer).await.expect("read");
+            assert!(size > 0, "stream ended early");
+            response.extend_from_slice(&buffer[..size]);
+        }
+
+        let text = String::from_utf8_lossy(&response).to_lowercase();
+        assert!(text.starts_with("http/1.1 200"));
+        assert!(text.contains("content-type: audio/ogg"));
+        assert!(text.contains("transfer-encoding: chunked"));
+        assert!(text.contains("icy-name: zello"));
+        assert!(text.contains("opushead"));
+    }
+}
@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Outbound audio streams that are stopped when dropped
+
+use crate::client::{ClientCommand, ClientHandle, ZelloClient};
+use crate::error::{Result, ZelloError};
+use tokio::sync::oneshot;
+
+/// An outbound audio stream started with [`ZelloClient::start_audio_stream`]
+///
+/// Packets are numbered in the order they are sent. Call [`finish`](Self::finish)
+/// to stop the stream; if it is dropped instead, e.g. because the sender
+/// returned early with an error, the stream is stopped on the client's next
+/// operation.
+#[derive(Debug)]
+pub struct OutboundStream<'a> {
+    client: &'a mut ZelloClient,
+    stream_id: u32,
+    next_packet_id: u32,
+    finished: bool,
+}
+
+impl<'a> OutboundStream<'a> {
+    pub(crate) fn new(client: &'a mut ZelloClient, stream_id: u32) -> Self {
+        Self {
+            client,
+            stream_id,
+            next_packet_id: 0,
+            finished: false,
+        }
+    }
+
+    /// Id of the stream
+    #[must_use]
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    /// Number of packets sent
+    #[must_use]
+    pub fn packets_sent(&self) -> u32 {
+        self.next_packet_id
+    }
+
+    /// Send an Opus packet
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream has been stopped by the transmit time-out
+    /// timer or the packet cannot be sent
+    pub async fn send(&mut self, opus_packet: Vec<u8>) -> Result<()> {
+        let packet_id = self.next_packet_id;
+        self.client
+            .send_stream_packet(self.stream_id, packet_id, &opus_packet)
+            .await?;
+        self.next_packet_id = self.next_packet_id.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Stop the stream
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stream has already been stopped or the stop cannot be sent
+    pub async fn finish(mut self) -> Result<()> {
+        self.finished = true;
+        self.client.stop_audio_stream(self.stream_id).await
+    }
+}
+
+impl Drop for OutboundStream<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.client.abandon_stream(self.stream_id);
+        }
+    }
+}
+
+/// An outbound audio stream started with [`ClientHandle::start_audio_stream`]
+///
+/// Packets are queued for the client's message loop in the order they are
+/// sent. The stream is stopped by [`finish`](Self::finish) or, failing that,
+/// when it is dropped.
+#[derive(Debug)]
+pub struct OutboundStreamHandle {
+    client: ClientHandle,
+    stream_id: u32,
+    next_packet_id: u32,
+    finished: bool,
+}
+
+impl OutboundStreamHandle {
+    pub(crate) fn new(client: ClientHandle, stream_id: u32) -> Self {
+        Self {
+            client,
+            stream_id,
+            next_packet_id: 0,
+            finished: false,
+        }
+    }
+
+    /// Id of the stream
+    #[must_use]
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    /// Number of packets sent
+    #[must_use]
+    pub fn packets_sent(&self) -> u32 {
+        self.next_packet_id
+    }
+
+    /// Queue an Opus packet
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the client has stopped
+    pub fn send(&mut self, opus_packet: Vec<u8>) -> Result<()> {
+        self.client.send(ClientCommand::SendAudioPacket {
+            stream_id: self.stream_id,
+            packet_id: self.next_packet_id,
+            data: opus_packet,
+        })?;
+        self.next_packet_id = self.next_packet_id.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Stop the stream once every queued packet has been sent
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the client has stopped or the stream has already been stopped
+    pub async fn finish(mut self) -> Result<()> {
+        self.finished = true;
+        let (reply, response) = oneshot::channel();
+        self.client.send(ClientCommand::StopStream {
+            stream_id: self.stream_id,
+            reply: Some(reply),
+        })?;
+        response.await.map_err(|_| ZelloError::NotConnected)?
+    }
+}
+
+impl Drop for OutboundStreamHandle {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.client.send(ClientCommand::StopStream {
+                stream_id: self.stream_id,
+                reply: None,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::floor::FloorState;
+    use tokio::sync::{broadcast, mpsc, watch};
+
+    #[test]
+    fn test_dropped_handle_stops_stream() {
+        let (commands, mut received) = mpsc::unbounded_channel();
+        let client = ClientHandle::new(
+            commands,
+            broadcast::channel(1).0,
+            watch::channel(FloorState::Clear).1,
+        );
+
+        let mut stream = OutboundStreamHandle::new(client, 42);
+        stream.send(vec![1]).expect("send");
+        stream.send(vec![2]).expect("send");
+        assert_eq!(stream.packets_sent(), 2);
+        drop(stream);
+
+        let mut packet_ids = Vec::new();
+        while let Ok(command) = received.try_recv() {
+            match command {
+                ClientCommand::SendAudioPacket {
+                    stream_id: 42,
+                    packet_id,
+                    ..
+                } => packet_ids.push(packet_id),
+                ClientCommand::StopStream {
+                    stream_id: 42,
+                    reply: None,
+                } => packet_ids.push(u32::MAX),
+                other => panic!("unexpected command {other:?}"),
+            }
+        }
+        assert_eq!(packet_ids, [0, 1, u32::MAX]);
+    }
+}
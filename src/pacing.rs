@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Real-time pacing of outbound audio packets
+
+use std::time::Duration;
+
+use tokio::time::{Instant, Interval, MissedTickBehavior, interval};
+use tracing::{debug, warn};
+
+/// Releases outbound packets at the rate they are played
+///
+/// Packets are scheduled at fixed offsets from the first one, so the pace does
+/// not drift however long the stream runs. A sender that falls behind catches
+/// up in a burst rather than shifting the schedule.
+#[derive(Debug)]
+pub struct PacketPacer {
+    interval: Interval,
+    packet_duration: Duration,
+    packets: u64,
+    lag: Duration,
+    max_lag: Duration,
+}
+
+impl PacketPacer {
+    /// Create a pacer releasing one packet every `packet_duration`
+    ///
+    /// The first packet is released immediately.
+    #[must_use]
+    pub fn new(packet_duration: Duration) -> Self {
+        let mut interval = interval(packet_duration.max(Duration::from_millis(1)));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
+
+        Self {
+            interval,
+            packet_duration,
+            packets: 0,
+            lag: Duration::ZERO,
+            max_lag: Duration::ZERO,
+        }
+    }
+
+    /// Wait until the next packet is due, returning how late it is
+    pub async fn tick(&mut self) -> Duration {
+        let scheduled = self.interval.tick().await;
+        self.packets += 1;
+        self.lag = Instant::now().saturating_duration_since(scheduled);
+        self.max_lag = self.max_lag.max(self.lag);
+        self.lag
+    }
+
+    /// Duration of each packet
+    #[must_use]
+    pub fn packet_duration(&self) -> Duration {
+        self.packet_duration
+    }
+
+    /// Number of packets released
+    #[must_use]
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    /// How far behind real time the last packet was released
+    #[must_use]
+    pub fn lag(&self) -> Duration {
+        self.lag
+    }
+
+    /// The furthest behind real time any packet was released
+    #[must_use]
+    pub fn max_lag(&self) -> Duration {
+        self.max_lag
+    }
+
+    /// Whether the sender is more than a packet behind real time
+    #[must_use]
+    pub fn is_behind(&self) -> bool {
+        self.lag > self.packet_duration
+    }
+}
+
+/// The packets of one stream, released in real time
+///
+/// Falling behind real time is logged against the stream.
+#[derive(Debug)]
+pub(crate) struct PacedPackets {
+    stream_id: u32,
+    pacer: PacketPacer,
+    packets: std::vec::IntoIter<Vec<u8>>,
+}
+
+impl PacedPackets {
+    pub(crate) fn new(stream_id: u32, packet_duration: u32, packets: Vec<Vec<u8>>) -> Self {
+        Self {
+            stream_id,
+            pacer: PacketPacer::new(Duration::from_millis(u64::from(packet_duration))),
+            packets: packets.into_iter(),
+        }
+    }
+
+    /// Pace packets that arrive while the stream runs, using [`Self::tick`]
+    pub(crate) fn live(stream_id: u32, packet_duration: u32) -> Self {
+        Self::new(stream_id, packet_duration, Vec::new())
+    }
+
+    /// Wait until the next packet is due and return it
+    pub(crate) async fn next(&mut self) -> Option<Vec<u8>> {
+        let Some(packet) = self.packets.next() else {
+            self.finish();
+            return None;
+        };
+        self.tick().await;
+        Some(packet)
+    }
+
+    /// Wait until the next packet is due
+    pub(crate) async fn tick(&mut self) {
+        let lag = self.pacer.tick().await;
+        if self.pacer.is_behind() {
+            debug!(
+                "Stream {} is {}ms behind real time",
+                self.stream_id,
+                lag.as_millis()
+            );
+        }
+    }
+
+    /// Report how far behind real time the stream fell
+    pub(crate) fn finish(&self) {
+        if self.pacer.max_lag() > self.pacer.packet_duration() {
+            warn!(
+                "Stream {} fell up to {}ms behind real time",
+                self.stream_id,
+                self.pacer.max_lag().as_millis()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_packets_are_released_in_real_time() {
+        let start = Instant::now();
+        let mut pacer = PacketPacer::new(Duration::from_millis(60));
+
+        for _ in 0..10 {
+            pacer.tick().await;
+        }
+
+        assert_eq!(pacer.packets(), 10);
+        assert_eq!(start.elapsed(), Duration::from_millis(540));
+        assert_eq!(pacer.max_lag(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_sender_catches_up_without_drift() {
+        let start = Instant::now();
+        let mut pacer = PacketPacer::new(Duration::from_millis(20));
+
+        pacer.tick().await;
+        tokio::time::advance(Duration::from_millis(70)).await;
+
+        assert_eq!(pacer.tick().await, Duration::from_millis(50));
+        assert!(pacer.is_behind());
+        pacer.tick().await;
+        pacer.tick().await;
+        assert_eq!(pacer.lag(), Duration::from_millis(10));
+        assert!(!pacer.is_behind());
+
+        assert_eq!(pacer.tick().await, Duration::ZERO);
+        assert_eq!(start.elapsed(), Duration::from_millis(80));
+        assert_eq!(pacer.max_lag(), Duration::from_millis(50));
+    }
+}
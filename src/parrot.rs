@@ -0,0 +1,144 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Parrot mode: repeat each inbound transmission back into the channel
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use crate::audio_file::EncodedAudio;
+use crate::client::ClientHandle;
+use crate::error::{Result, ZelloError};
+use crate::inbound::InboundTransmission;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Settings for parrot mode
+#[derive(Debug, Clone)]
+pub struct ParrotConfig {
+    /// Callsigns whose transmissions are repeated; empty repeats everyone
+    pub allowlist: HashSet<String>,
+    /// Longest clip recorded; the rest of a longer transmission is dropped
+    pub max_clip: Duration,
+    /// Pause between the end of a transmission and its playback
+    pub delay: Duration,
+}
+
+impl Default for ParrotConfig {
+    fn default() -> Self {
+        Self {
+            allowlist: HashSet::new(),
+            max_clip: Duration::from_secs(30),
+            delay: Duration::from_millis(500),
+        }
+    }
+}
+
+impl ParrotConfig {
+    /// Whether transmissions from `callsign` are repeated
+    #[must_use]
+    pub fn allows(&self, callsign: &str) -> bool {
+        self.allowlist.is_empty() || self.allowlist.contains(callsign)
+    }
+}
+
+/// Record a transmission's Opus packets until it ends, keeping at most `max_clip`
+pub async fn record_transmission(
+    mut transmission: InboundTransmission,
+    max_clip: Duration,
+) -> EncodedAudio {
+    let info = transmission.info();
+    let header = info.codec_header.clone();
+    let packet_duration = info.effective_packet_duration();
+    let max_packets = usize::try_from(max_clip.as_millis() / u128::from(packet_duration.max(1)))
+        .unwrap_or(usize::MAX);
+
+    let mut packets = Vec::new();
+    while let Some(packet) = transmission.next_packet().await {
+        if packets.len() < max_packets {
+            packets.push(packet.data);
+        }
+    }
+
+    EncodedAudio {
+        codec_header: header,
+        packet_duration,
+        packets,
+    }
+}
+
+/// Repeat each transmission back into the channel after it ends
+///
+/// Transmissions come from [`ZelloClient::inbound_transmissions`](crate::ZelloClient::inbound_transmissions)
+/// and are played back through `client` with their original Opus packets.
+/// Returns when the client stops delivering transmissions.
+///
+/// # Errors
+///
+/// Returns an error if the client has stopped
+pub async fn run_parrot(
+    client: ClientHandle,
+    mut transmissions: mpsc::UnboundedReceiver<InboundTransmission>,
+    config: ParrotConfig,
+) -> Result<()> {
+    while let Some(transmission) = transmissions.recv().await {
+        let from = transmission.info().from.clone();
+        if !config.allows(&from) {
+            debug!("Not repeating {from}, who is not on the allowlist");
+            continue;
+        }
+
+        let audio = record_transmission(transmission, config.max_clip).await;
+        if audio.packets.is_empty() {
+            continue;
+        }
+
+        tokio::time::sleep(config.delay).await;
+        info!("🦜 Repeating {}ms from {from}", audio.duration_ms());
+        match client.transmit_audio(audio).await {
+            Ok(()) => {}
+            Err(ZelloError::NotConnected) => return Err(ZelloError::NotConnected),
+            Err(e) => warn!("Failed to repeat {from}: {e}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inbound::tests::info;
+    use crate::inbound::{InboundPacket, TransmissionInfo};
+
+    #[tokio::test]
+    async fn test_record_keeps_max_clip() {
+        let (packets_tx, transmission) = InboundTransmission::new(TransmissionInfo {
+            // Falls back to the frame size in the codec header
+            packet_duration: 0,
+            ..info()
+        });
+        for packet_id in 0..10 {
+            packets_tx
+                .send(InboundPacket {
+                    packet_id,
+                    data: vec![u8::try_from(packet_id).expect("small id")],
+                })
+                .expect("send");
+        }
+        drop(packets_tx);
+
+        let audio = record_transmission(transmission, Duration::from_millis(300)).await;
+        assert_eq!(audio.packet_duration, 60);
+        assert_eq!(audio.packets, [[0], [1], [2], [3], [4]]);
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let mut config = ParrotConfig::default();
+        assert!(config.allows("anyone"));
+
+        config.allowlist.insert("alice".to_string());
+        assert!(config.allows("alice"));
+        assert!(!config.allows("bob"));
+    }
+}
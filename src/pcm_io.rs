@@ -0,0 +1,342 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Raw PCM audio over named pipes, standard I/O and UDP
+//!
+//! Audio is 16-bit little-endian mono. Channel audio is written at 16kHz.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::CPAL_SAMPLE_RATE;
+use crate::PCM_I16_TO_F32;
+use crate::capture::{CAPTURE_CHUNK_SIZE, CaptureConverter};
+use crate::error::{Result, ZelloError};
+use crate::inbound::InboundTransmission;
+use futures_util::StreamExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Largest datagram read from a UDP source
+const MAX_DATAGRAM_SIZE: usize = 65_536;
+
+/// Bytes read from a pipe at a time
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// Where raw PCM is read from or written to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PcmEndpoint {
+    /// Standard input for a source, standard output for a sink
+    Stdio,
+    /// A named pipe or file
+    Pipe(PathBuf),
+    /// A UDP address: bound by a source, sent to by a sink
+    Udp(SocketAddr),
+}
+
+impl FromStr for PcmEndpoint {
+    type Err = ZelloError;
+
+    /// Parse `-` for standard I/O, `udp://ADDR:PORT` for UDP or a path
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "-" {
+            return Ok(Self::Stdio);
+        }
+        if let Some(address) = s.strip_prefix("udp://") {
+            return address.parse().map(Self::Udp).map_err(|e| {
+                ZelloError::ConfigError(format!("Invalid UDP address {address}: {e}"))
+            });
+        }
+        Ok(Self::Pipe(PathBuf::from(s)))
+    }
+}
+
+impl fmt::Display for PcmEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stdio => write!(f, "standard I/O"),
+            Self::Pipe(path) => write!(f, "{}", path.display()),
+            Self::Udp(address) => write!(f, "udp://{address}"),
+        }
+    }
+}
+
+/// Write the decoded audio of each transmission to `endpoint`
+///
+/// Nothing is written between transmissions. A pipe is reopened if its
+/// reader goes away; UDP datagrams carry 20ms of audio each. Returns when the
+/// client stops delivering transmissions.
+///
+/// # Errors
+///
+/// Returns an error if the endpoint cannot be opened or written
+pub async fn write_pcm(
+    mut transmissions: mpsc::UnboundedReceiver<InboundTransmission>,
+    endpoint: PcmEndpoint,
+) -> Result<()> {
+    let mut sink = PcmSink::open(&endpoint).await?;
+
+    while let Some(transmission) = transmissions.recv().await {
+        debug!(
+            "Writing stream {} from {} to {endpoint}",
+            transmission.info().stream_id,
+            transmission.info().from
+        );
+        let mut pcm = Box::pin(transmission.pcm()?);
+        while let Some(chunk) = pcm.next().await {
+            let bytes: Vec<u8> = chunk.iter().flat_map(|s| s.to_le_bytes()).collect();
+            if let Err(e) = sink.write(&bytes).await {
+                if e.kind() != std::io::ErrorKind::BrokenPipe {
+                    return Err(e.into());
+                }
+                warn!("Reader of {endpoint} went away, reopening");
+                sink = PcmSink::open(&endpoint).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An open destination for raw PCM
+enum PcmSink {
+    Stream(Box<dyn AsyncWrite + Send + Unpin>),
+    Udp(UdpSocket, SocketAddr),
+}
+
+impl PcmSink {
+    /// Open `endpoint`, waiting for a reader if it is a named pipe
+    async fn open(endpoint: &PcmEndpoint) -> Result<Self> {
+        Ok(match endpoint {
+            PcmEndpoint::Stdio => Self::Stream(Box::new(tokio::io::stdout())),
+            PcmEndpoint::Pipe(path) => {
+                let file = tokio::fs::OpenOptions::new()
+                    .append(true)
+                    .open(path)
+                    .await?;
+                Self::Stream(Box::new(file))
+            }
+            PcmEndpoint::Udp(destination) => {
+                let local: SocketAddr = if destination.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+                };
+                Self::Udp(UdpSocket::bind(local).await?, *destination)
+            }
+        })
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        match self {
+            Self::Stream(stream) => {
+                stream.write_all(bytes).await?;
+                stream.flush().await
+            }
+            Self::Udp(socket, destination) => {
+                for datagram in bytes.chunks(CAPTURE_CHUNK_SIZE * 2) {
+                    socket.send_to(datagram, *destination).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Read raw PCM at `sample_rate` from `endpoint`, sending 16kHz chunks to `pcm_tx`
+///
+/// The audio must arrive in real time. A named pipe is reopened when its
+/// writer closes it; standard input ends the source. Feed `pcm_tx` to
+/// [`transmit_with_vox`](crate::transmit_with_vox) to start and stop
+/// transmissions on silence.
+///
+/// # Errors
+///
+/// Returns an error if the endpoint cannot be opened or read, or the sample
+/// rate is not supported
+pub async fn read_pcm(
+    endpoint: PcmEndpoint,
+    sample_rate: u32,
+    pcm_tx: mpsc::Sender<Vec<i16>>,
+) -> Result<()> {
+    let mut chunker = PcmChunker::new(sample_rate)?;
+    info!("Reading {sample_rate}Hz PCM from {endpoint}");
+
+    match &endpoint {
+        PcmEndpoint::Udp(address) => {
+            let socket = UdpSocket::bind(address).await?;
+            let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                let size = socket.recv(&mut buffer).await?;
+                if !chunker.push(&buffer[..size], &pcm_tx).await {
+                    return Ok(());
+                }
+            }
+        }
+        PcmEndpoint::Stdio => {
+            read_stream(tokio::io::stdin(), &mut chunker, &pcm_tx).await?;
+            Ok(())
+        }
+        PcmEndpoint::Pipe(path) => loop {
+            let pipe = tokio::fs::File::open(path).await?;
+            if !read_stream(pipe, &mut chunker, &pcm_tx).await? {
+                return Ok(());
+            }
+            debug!("Writer of {endpoint} closed it, reopening");
+        },
+    }
+}
+
+/// Read a stream to its end, returning `false` if the receiver has gone
+async fn read_stream(
+    mut stream: impl AsyncRead + Unpin,
+    chunker: &mut PcmChunker,
+    pcm_tx: &mpsc::Sender<Vec<i16>>,
+) -> Result<bool> {
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let size = stream.read(&mut buffer).await?;
+        if size == 0 {
+            return Ok(true);
+        }
+        if !chunker.push(&buffer[..size], pcm_tx).await {
+            return Ok(false);
+        }
+    }
+}
+
+/// Turns raw bytes into 20ms chunks of 16kHz PCM
+struct PcmChunker {
+    converter: Option<CaptureConverter>,
+    odd_byte: Option<u8>,
+    pcm: Vec<i16>,
+}
+
+impl PcmChunker {
+    fn new(sample_rate: u32) -> Result<Self> {
+        let converter = if sample_rate == CPAL_SAMPLE_RATE.0 {
+            None
+        } else {
+            Some(
+                CaptureConverter::new(sample_rate)
+                    .map_err(|e| ZelloError::ConfigError(format!("{sample_rate}Hz: {e}")))?,
+            )
+        };
+        Ok(Self {
+            converter,
+            odd_byte: None,
+            pcm: Vec::with_capacity(CAPTURE_CHUNK_SIZE * 4),
+        })
+    }
+
+    /// Convert bytes and take out every whole chunk
+    fn convert(&mut self, bytes: &[u8]) -> Vec<Vec<i16>> {
+        let mut bytes = bytes.iter().copied();
+        let mut samples = Vec::with_capacity(bytes.len() / 2 + 1);
+        if let Some(low) = self.odd_byte.take() {
+            match bytes.next() {
+                Some(high) => samples.push(i16::from_le_bytes([low, high])),
+                None => self.odd_byte = Some(low),
+            }
+        }
+        while let Some(low) = bytes.next() {
+            match bytes.next() {
+                Some(high) => samples.push(i16::from_le_bytes([low, high])),
+                None => self.odd_byte = Some(low),
+            }
+        }
+
+        match &mut self.converter {
+            Some(converter) => {
+                let input: Vec<f32> = samples
+                    .iter()
+                    .map(|&s| f32::from(s) * PCM_I16_TO_F32)
+                    .collect();
+                converter.process(&input, &mut self.pcm);
+            }
+            None => self.pcm.extend(samples),
+        }
+
+        let whole = self.pcm.len() / CAPTURE_CHUNK_SIZE * CAPTURE_CHUNK_SIZE;
+        self.pcm
+            .drain(..whole)
+            .collect::<Vec<_>>()
+            .chunks(CAPTURE_CHUNK_SIZE)
+            .map(<[i16]>::to_vec)
+            .collect()
+    }
+
+    /// Convert bytes and send every whole chunk, returning `false` if the receiver has gone
+    async fn push(&mut self, bytes: &[u8], pcm_tx: &mpsc::Sender<Vec<i16>>) -> bool {
+        for chunk in self.convert(bytes) {
+            if pcm_tx.send(chunk).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            "-".parse::<PcmEndpoint>().expect("stdio"),
+            PcmEndpoint::Stdio
+        );
+        assert_eq!(
+            "udp://127.0.0.1:7355".parse::<PcmEndpoint>().expect("udp"),
+            PcmEndpoint::Udp(([127, 0, 0, 1], 7355).into())
+        );
+        assert_eq!(
+            "/tmp/audio.fifo".parse::<PcmEndpoint>().expect("pipe"),
+            PcmEndpoint::Pipe(PathBuf::from("/tmp/audio.fifo"))
+        );
+        assert!("udp://nowhere".parse::<PcmEndpoint>().is_err());
+    }
+
+    #[test]
+    fn test_chunker_joins_split_samples() {
+        let mut chunker = PcmChunker::new(16000).expect("chunker");
+        let samples: Vec<i16> = (0..320).collect();
+        let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+
+        // Split in the middle of a sample
+        assert!(chunker.convert(&bytes[..301]).is_empty());
+        let chunks = chunker.convert(&bytes[301..]);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0], samples);
+    }
+
+    #[tokio::test]
+    async fn test_reads_udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.expect("bind");
+        let address = socket.local_addr().expect("address");
+        drop(socket);
+
+        let (pcm_tx, mut pcm_rx) = mpsc::channel(4);
+        let reader = tokio::spawn(read_pcm(PcmEndpoint::Udp(address), 16000, pcm_tx));
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await.expect("bind");
+        let datagram = vec![0x01; CAPTURE_CHUNK_SIZE * 2];
+        let chunk = loop {
+            sender.send_to(&datagram, address).await.expect("send");
+            if let Ok(Some(chunk)) =
+                tokio::time::timeout(std::time::Duration::from_millis(50), pcm_rx.recv()).await
+            {
+                break chunk;
+            }
+        };
+        assert_eq!(chunk, vec![0x0101; CAPTURE_CHUNK_SIZE]);
+
+        drop(pcm_rx);
+        sender.send_to(&datagram, address).await.expect("send");
+        assert!(reader.await.expect("join").is_ok());
+    }
+}
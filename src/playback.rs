@@ -0,0 +1,615 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Playback pipeline between the Opus decoder and the audio output device
+//!
+//! Decoded PCM is converted to the device format on a writer thread and handed to
+//! the realtime device callback through a preallocated lock-free ring buffer. Each
+//! transmission is pre-buffered up to a target latency that adapts to network
+//! jitter, and the resampling ratio is nudged so that the buffer level stays near
+//! that target while the network and device clocks drift apart.
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{CPAL_SAMPLE_RATE, PCM_I16_TO_F32};
+use anyhow::{Result, anyhow};
+use cpal::{FromSample, SizedSample};
+use rtrb::{Consumer, Producer, RingBuffer};
+use rubato::{
+    Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+
+/// Resampler input chunk size in frames (10ms at the Zello sample rate)
+pub const RESAMPLER_CHUNK_SIZE: usize = 160;
+
+/// Largest relative change allowed to the resampling ratio after creation
+pub const RESAMPLER_MAX_RELATIVE_RATIO: f64 = 1.1;
+
+/// Duration of device audio the playback ring buffer can hold
+pub const PLAYBACK_BUFFER_MS: u32 = 2000;
+
+/// Default pre-buffering latency at the start of a transmission
+pub const DEFAULT_TARGET_LATENCY: Duration = Duration::from_millis(120);
+
+/// Default upper limit for the adaptive pre-buffering latency
+pub const DEFAULT_MAX_LATENCY: Duration = Duration::from_millis(800);
+
+/// Multiple of the measured jitter added to the target latency
+const JITTER_MULTIPLIER: f64 = 3.0;
+
+/// Time into a transmission before drift correction starts
+const DRIFT_WARMUP: Duration = Duration::from_secs(2);
+
+/// Smoothing factor for the buffer level used by drift correction
+const DRIFT_SMOOTHING: f64 = 0.02;
+
+/// Gain applied to the relative buffer level error when correcting drift
+const DRIFT_GAIN: f64 = 0.005;
+
+/// Largest relative resampling correction applied for drift (0.5%)
+const DRIFT_MAX_CORRECTION: f64 = 0.005;
+
+/// Settings for pre-buffering and drift correction
+#[derive(Debug, Clone)]
+pub struct PlaybackConfig {
+    /// Latency buffered before a transmission starts playing
+    pub target_latency: Duration,
+    /// Upper limit for the target latency as it adapts to jitter
+    pub max_latency: Duration,
+    /// Whether to adjust the resampling ratio to hold the buffer at the target
+    pub drift_correction: bool,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        Self {
+            target_latency: DEFAULT_TARGET_LATENCY,
+            max_latency: DEFAULT_MAX_LATENCY,
+            drift_correction: true,
+        }
+    }
+}
+
+/// Converts decoded Zello PCM (16kHz mono) into the device's sample rate and channel layout
+pub struct PlaybackConverter {
+    resampler: SincFixedIn<f32>,
+    channels: usize,
+    pending: Vec<f32>,
+    resampled: Vec<Vec<f32>>,
+}
+
+impl fmt::Debug for PlaybackConverter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PlaybackConverter")
+            .field("channels", &self.channels)
+            .field("pending", &self.pending.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl PlaybackConverter {
+    /// Create a converter for a device running at `sample_rate` with `channels` channels
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the resampler cannot be created for these rates
+    pub fn new(sample_rate: u32, channels: u16) -> Result<Self> {
+        if channels == 0 {
+            return Err(anyhow!("Output device has no channels"));
+        }
+
+        // The resampler is used even when the rates match so that drift can be corrected
+        let parameters = SincInterpolationParameters {
+            sinc_len: 128,
+            f_cutoff: 0.95,
+            oversampling_factor: 256,
+            interpolation: SincInterpolationType::Linear,
+            window: WindowFunction::BlackmanHarris2,
+        };
+        let ratio = f64::from(sample_rate) / f64::from(CPAL_SAMPLE_RATE.0);
+        let resampler = SincFixedIn::<f32>::new(
+            ratio,
+            RESAMPLER_MAX_RELATIVE_RATIO,
+            parameters,
+            RESAMPLER_CHUNK_SIZE,
+            1,
+        )?;
+        let frames = vec![vec![0.0; resampler.output_frames_max()]];
+
+        Ok(Self {
+            resampler,
+            channels: usize::from(channels),
+            pending: Vec::with_capacity(RESAMPLER_CHUNK_SIZE * 16),
+            resampled: frames,
+        })
+    }
+
+    /// Convert a chunk of decoded PCM, appending interleaved device samples to `output`
+    ///
+    /// Input that does not fill a whole resampler chunk is held until the next call.
+    pub fn process(&mut self, pcm: &[i16], output: &mut Vec<f32>) {
+        self.pending
+            .extend(pcm.iter().map(|&s| f32::from(s) * PCM_I16_TO_F32));
+
+        let mut consumed = 0;
+        while self.pending.len() - consumed >= self.resampler.input_frames_next() {
+            let input = [&self.pending[consumed..]];
+            match self
+                .resampler
+                .process_into_buffer(&input, &mut self.resampled, None)
+            {
+                Ok((frames_in, frames_out)) => {
+                    upmix(&self.resampled[0][..frames_out], self.channels, output);
+                    consumed += frames_in;
+                }
+                Err(_) => {
+                    // Drop the unusable input rather than stalling playback
+                    consumed = self.pending.len();
+                }
+            }
+        }
+        self.pending.drain(..consumed);
+    }
+
+    /// Convert any held input, padding it with silence to a whole resampler chunk
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let padding = self
+            .resampler
+            .input_frames_next()
+            .saturating_sub(self.pending.len());
+        self.pending.extend(std::iter::repeat_n(0.0, padding));
+        self.process(&[], output);
+    }
+
+    /// Adjust the resampling ratio relative to the nominal device ratio
+    pub fn set_ratio_relative(&mut self, relative: f64) {
+        let _ = self.resampler.set_resample_ratio_relative(relative, true);
+    }
+}
+
+/// Duplicate mono samples into every channel of an interleaved frame
+fn upmix(mono: &[f32], channels: usize, output: &mut Vec<f32>) {
+    output.reserve(mono.len() * channels);
+    for &sample in mono {
+        output.extend(std::iter::repeat_n(sample, channels));
+    }
+}
+
+/// Interarrival jitter estimate for decoded audio chunks, as in RFC 3550
+#[derive(Debug, Default)]
+pub struct JitterEstimator {
+    last: Option<(Instant, Duration)>,
+    jitter: f64,
+}
+
+impl JitterEstimator {
+    /// Forget the previous arrival, as at the start of a new transmission
+    pub fn restart(&mut self) {
+        self.last = None;
+    }
+
+    /// Record a chunk of `duration` audio arriving at `arrival`
+    pub fn update(&mut self, arrival: Instant, duration: Duration) {
+        if let Some((last_arrival, last_duration)) = self.last {
+            let elapsed = arrival
+                .saturating_duration_since(last_arrival)
+                .as_secs_f64();
+            let deviation = (elapsed - last_duration.as_secs_f64()).abs();
+            self.jitter += (deviation - self.jitter) / 16.0;
+        }
+        self.last = Some((arrival, duration));
+    }
+
+    /// Current jitter estimate
+    #[must_use]
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter)
+    }
+}
+
+/// Counters and shared state describing the realtime playback path
+#[derive(Debug, Default)]
+pub struct PlaybackStats {
+    underruns: AtomicU64,
+    overruns: AtomicU64,
+    target_samples: AtomicUsize,
+    target_latency_us: AtomicU64,
+    jitter_us: AtomicU64,
+    end_of_transmission: AtomicBool,
+    ends_requested: AtomicU64,
+}
+
+impl PlaybackStats {
+    /// Number of times playback ran out of samples while audio was playing
+    #[must_use]
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    /// Number of times decoded audio was dropped because the ring buffer was full
+    #[must_use]
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    /// Current pre-buffering target latency
+    #[must_use]
+    pub fn target_latency(&self) -> Duration {
+        Duration::from_micros(self.target_latency_us.load(Ordering::Relaxed))
+    }
+
+    /// Current network jitter estimate
+    #[must_use]
+    pub fn jitter(&self) -> Duration {
+        Duration::from_micros(self.jitter_us.load(Ordering::Relaxed))
+    }
+
+    /// Mark the end of a transmission without queueing an empty chunk
+    ///
+    /// The writer ends the transmission once it has drained the audio already
+    /// queued, so the marker is never lost to a full queue.
+    pub fn end_transmission(&self) {
+        self.ends_requested.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Producer side of the playback ring buffer, fed from the decoder
+#[derive(Debug)]
+pub struct PlaybackWriter {
+    producer: Producer<f32>,
+    converter: PlaybackConverter,
+    config: PlaybackConfig,
+    samples_per_second: f64,
+    samples: Vec<f32>,
+    jitter: JitterEstimator,
+    transmission_start: Option<Instant>,
+    buffer_level: Option<f64>,
+    ends_handled: u64,
+    stats: Arc<PlaybackStats>,
+}
+
+/// Consumer side of the playback ring buffer, drained by the device callback
+#[derive(Debug)]
+pub struct PlaybackReader {
+    consumer: Consumer<f32>,
+    playing: bool,
+    stats: Arc<PlaybackStats>,
+}
+
+/// Create a preallocated playback ring buffer for a device format
+///
+/// # Errors
+///
+/// Returns an error if the converter cannot be created for the device format
+pub fn playback_buffer(
+    sample_rate: u32,
+    channels: u16,
+    config: PlaybackConfig,
+) -> Result<(PlaybackWriter, PlaybackReader, Arc<PlaybackStats>)> {
+    let converter = PlaybackConverter::new(sample_rate, channels)?;
+    let samples_per_second = f64::from(sample_rate) * f64::from(channels);
+
+    let buffer_ms = u128::from(PLAYBACK_BUFFER_MS).max(config.max_latency.as_millis() * 2);
+    let capacity =
+        usize::try_from(u128::from(sample_rate) * u128::from(channels) * buffer_ms / 1000)?;
+    let (producer, consumer) = RingBuffer::new(capacity);
+    let stats = Arc::new(PlaybackStats::default());
+
+    let mut writer = PlaybackWriter {
+        producer,
+        converter,
+        samples_per_second,
+        samples: Vec::with_capacity(capacity),
+        jitter: JitterEstimator::default(),
+        transmission_start: None,
+        buffer_level: None,
+        ends_handled: 0,
+        stats: stats.clone(),
+        config,
+    };
+    writer.set_target_latency(writer.config.target_latency);
+
+    let reader = PlaybackReader {
+        consumer,
+        playing: false,
+        stats: stats.clone(),
+    };
+
+    Ok((writer, reader, stats))
+}
+
+impl PlaybackWriter {
+    /// Convert decoded PCM and queue it for playback
+    ///
+    /// An empty chunk marks the end of a transmission.
+    pub fn write(&mut self, pcm: &[i16]) {
+        self.write_at(pcm, Instant::now());
+    }
+
+    /// Convert decoded PCM that arrived at `arrival` and queue it for playback
+    pub fn write_at(&mut self, pcm: &[i16], arrival: Instant) {
+        self.samples.clear();
+
+        if pcm.is_empty() {
+            self.converter.flush(&mut self.samples);
+            self.push_samples();
+            self.end_transmission();
+            return;
+        }
+
+        let started = *self.transmission_start.get_or_insert_with(|| {
+            self.jitter.restart();
+            self.buffer_level = None;
+            self.stats
+                .end_of_transmission
+                .store(false, Ordering::Release);
+            arrival
+        });
+
+        let duration = Duration::from_secs_f64(
+            f64::from(u32::try_from(pcm.len()).unwrap_or(u32::MAX)) / f64::from(CPAL_SAMPLE_RATE.0),
+        );
+        self.jitter.update(arrival, duration);
+        self.adapt_target_latency();
+
+        self.converter.process(pcm, &mut self.samples);
+        self.push_samples();
+
+        if self.config.drift_correction
+            && arrival.saturating_duration_since(started) >= DRIFT_WARMUP
+        {
+            self.correct_drift();
+        }
+    }
+
+    /// End the transmission if [`PlaybackStats::end_transmission`] has marked it
+    ///
+    /// Call this once the queue of decoded audio is empty.
+    pub fn write_requested_end(&mut self) {
+        let requested = self.stats.ends_requested.load(Ordering::Acquire);
+        if requested != self.ends_handled {
+            self.ends_handled = requested;
+            self.write(&[]);
+        }
+    }
+
+    /// Push converted samples into the ring buffer, counting any that do not fit
+    fn push_samples(&mut self) {
+        let (_, dropped) = self.producer.push_partial_slice(&self.samples);
+        if !dropped.is_empty() {
+            self.stats.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Let the reader drain the buffer without counting an underrun
+    fn end_transmission(&mut self) {
+        self.transmission_start = None;
+        self.buffer_level = None;
+        self.converter.set_ratio_relative(1.0);
+        self.stats
+            .end_of_transmission
+            .store(true, Ordering::Release);
+    }
+
+    /// Raise the target latency above the configured one by a multiple of the jitter
+    fn adapt_target_latency(&mut self) {
+        let jitter = self.jitter.jitter();
+        self.stats.jitter_us.store(
+            u64::try_from(jitter.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+
+        let target = (self.config.target_latency + jitter.mul_f64(JITTER_MULTIPLIER))
+            .min(self.config.max_latency.max(self.config.target_latency));
+        self.set_target_latency(target);
+    }
+
+    fn set_target_latency(&mut self, target: Duration) {
+        self.stats.target_latency_us.store(
+            u64::try_from(target.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        // Truncation is intended: this is a whole number of samples
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let samples = (target.as_secs_f64() * self.samples_per_second) as usize;
+        self.stats.target_samples.store(samples, Ordering::Relaxed);
+    }
+
+    /// Nudge the resampling ratio so the buffer level converges on the target
+    #[allow(clippy::cast_precision_loss)]
+    fn correct_drift(&mut self) {
+        let buffered = (self.producer.buffer().capacity() - self.producer.slots()) as f64;
+        let level = match self.buffer_level {
+            Some(level) => level + (buffered - level) * DRIFT_SMOOTHING,
+            None => buffered,
+        };
+        self.buffer_level = Some(level);
+
+        let target = self.stats.target_samples.load(Ordering::Relaxed).max(1) as f64;
+        let error = (level - target) / target;
+        let correction = (error * DRIFT_GAIN).clamp(-DRIFT_MAX_CORRECTION, DRIFT_MAX_CORRECTION);
+        self.converter.set_ratio_relative(1.0 - correction);
+    }
+}
+
+/// Process audio output by filling the output buffer from the playback ring buffer
+///
+/// Playback of a transmission starts once the target latency is buffered, or as
+/// soon as a short transmission has ended. Running dry before the end of a
+/// transmission counts as an underrun and buffers up to the target again.
+///
+/// This runs on the realtime audio thread so it must not block, allocate or log.
+pub fn process_audio_output<T>(output: &mut [T], reader: &mut PlaybackReader)
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let buffered = reader.consumer.slots();
+    let ended = reader.stats.end_of_transmission.load(Ordering::Acquire);
+
+    if !reader.playing {
+        let target = reader
+            .stats
+            .target_samples
+            .load(Ordering::Relaxed)
+            .max(output.len());
+        reader.playing = buffered >= target || (ended && buffered > 0);
+    }
+
+    let available = if reader.playing {
+        buffered.min(output.len())
+    } else {
+        0
+    };
+
+    if reader.playing && available < output.len() {
+        if !ended {
+            reader.stats.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        reader.playing = false;
+    }
+
+    let (head, tail) = output.split_at_mut(available);
+    if let Ok(chunk) = reader.consumer.read_chunk(available) {
+        let (first, second) = chunk.as_slices();
+        for (out, &sample) in head.iter_mut().zip(first.iter().chain(second)) {
+            *out = T::from_sample(sample);
+        }
+        chunk.commit_all();
+    }
+
+    for out in tail {
+        *out = T::EQUILIBRIUM;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_latency() -> PlaybackConfig {
+        PlaybackConfig {
+            target_latency: Duration::ZERO,
+            max_latency: Duration::ZERO,
+            drift_correction: false,
+        }
+    }
+
+    #[test]
+    fn test_upmix() {
+        let mut converter = PlaybackConverter::new(16000, 2).expect("converter");
+        let mut output = Vec::new();
+        converter.process(&[16384; 1600], &mut output);
+        assert!(output.len() > 2000);
+        assert!(
+            output
+                .chunks(2)
+                .all(|frame| frame[0].to_bits() == frame[1].to_bits())
+        );
+    }
+
+    #[test]
+    fn test_resample_to_48k() {
+        let mut converter = PlaybackConverter::new(48000, 1).expect("converter");
+        let delay = converter.resampler.output_delay();
+        let mut output = Vec::new();
+        converter.process(&[0; 1600], &mut output);
+        assert!((output.len() + delay).abs_diff(4800) <= 8);
+    }
+
+    #[test]
+    fn test_jitter_estimate() {
+        let mut jitter = JitterEstimator::default();
+        let start = Instant::now();
+        let chunk = Duration::from_millis(60);
+        for i in 0..50 {
+            jitter.update(start + chunk * i, chunk);
+        }
+        assert!(jitter.jitter() < Duration::from_millis(1));
+
+        for i in 50..100 {
+            let late = if i % 2 == 0 { chunk } else { Duration::ZERO };
+            jitter.update(start + chunk * i + late, chunk);
+        }
+        assert!(jitter.jitter() > Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_prebuffer_until_target() {
+        let config = PlaybackConfig {
+            target_latency: Duration::from_millis(100),
+            ..no_latency()
+        };
+        let (mut writer, mut reader, stats) = playback_buffer(16000, 1, config).expect("buffer");
+        let mut output = [0i16; 160];
+
+        writer.write(&[16384; 1000]);
+        process_audio_output(&mut output, &mut reader);
+        assert_eq!(output, [0; 160]);
+
+        writer.write(&[16384; 1000]);
+        process_audio_output(&mut output, &mut reader);
+        assert!(output.iter().any(|&s| s != 0));
+        assert_eq!(stats.underruns(), 0);
+    }
+
+    #[test]
+    fn test_playback_underrun_and_overrun() {
+        let (mut writer, mut reader, stats) =
+            playback_buffer(16000, 1, no_latency()).expect("buffer");
+        let mut output = [0i16; 160];
+
+        // Idle silence is not an underrun
+        process_audio_output(&mut output, &mut reader);
+        assert_eq!(stats.underruns(), 0);
+
+        writer.write(&[16384; 480]);
+        while reader.consumer.slots() >= output.len() {
+            process_audio_output(&mut output, &mut reader);
+        }
+        process_audio_output(&mut output, &mut reader);
+        assert_eq!(stats.underruns(), 1);
+
+        writer.write(&vec![0; 40000]);
+        assert_eq!(stats.overruns(), 1);
+    }
+
+    #[test]
+    fn test_end_of_transmission_is_not_underrun() {
+        let (mut writer, mut reader, stats) =
+            playback_buffer(16000, 1, no_latency()).expect("buffer");
+        let mut output = [0i16; 160];
+
+        writer.write(&[16384; 480]);
+        writer.write(&[]);
+        for _ in 0..10 {
+            process_audio_output(&mut output, &mut reader);
+        }
+        assert_eq!(reader.consumer.slots(), 0);
+        assert_eq!(stats.underruns(), 0);
+    }
+
+    #[test]
+    fn test_requested_end_of_transmission() {
+        let (mut writer, _reader, stats) = playback_buffer(16000, 1, no_latency()).expect("buffer");
+
+        writer.write(&[16384; 480]);
+        writer.write_requested_end();
+        assert!(!stats.end_of_transmission.load(Ordering::Acquire));
+
+        stats.end_transmission();
+        writer.write_requested_end();
+        assert!(stats.end_of_transmission.load(Ordering::Acquire));
+        assert!(writer.transmission_start.is_none());
+
+        // Each request ends one transmission
+        writer.write(&[16384; 480]);
+        writer.write_requested_end();
+        assert!(!stats.end_of_transmission.load(Ordering::Acquire));
+    }
+}
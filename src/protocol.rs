@@ -3,7 +3,7 @@
 
 //! Zello protocol implementation
 
-use bytes::Buf;
+use bytes::{Buf, BufMut, BytesMut};
 use futures_util::{SinkExt, StreamExt};
 use tokio::net::TcpStream;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
@@ -14,6 +14,12 @@ use crate::ZELLO_DEFAULT_URL;
 use crate::error::{Result, ZelloError};
 use crate::message::{Event, IncomingMessage, Message};
 
+/// Type byte of binary audio packets
+const AUDIO_PACKET_TYPE: u8 = 0x01;
+
+/// Bytes before the Opus data in a binary audio packet
+const AUDIO_PACKET_HEADER_SIZE: usize = 9;
+
 /// Zello protocol handler
 #[derive(Debug)]
 pub struct Protocol {
@@ -64,7 +70,8 @@ impl Protocol {
             Message::Logon { seq: s, .. }
             | Message::SendTextMessage { seq: s, .. }
             | Message::StartStream { seq: s, .. }
-            | Message::StopStream { seq: s, .. } => *s = seq,
+            | Message::StopStream { seq: s, .. }
+            | Message::SendLocation { seq: s, .. } => *s = seq,
         }
 
         self.send(message).await?;
@@ -157,4 +164,44 @@ impl Protocol {
             .map_err(|e| ZelloError::AudioError(e.to_string()))?;
         Ok(())
     }
+
+    /// Send an audio packet on an outbound stream
+    ///
+    /// The packet is framed as type 0x01, the stream id and the packet id
+    /// (both big-endian), followed by the Opus data.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if sending fails
+    pub async fn send_audio_packet(
+        &mut self,
+        stream_id: u32,
+        packet_id: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        self.send_audio_data(frame_audio_packet(stream_id, packet_id, data))
+            .await
+    }
+}
+
+/// Frame Opus data as a binary audio packet
+#[must_use]
+pub fn frame_audio_packet(stream_id: u32, packet_id: u32, data: &[u8]) -> Vec<u8> {
+    let mut packet = BytesMut::with_capacity(AUDIO_PACKET_HEADER_SIZE + data.len());
+    packet.put_u8(AUDIO_PACKET_TYPE);
+    packet.put_u32(stream_id);
+    packet.put_u32(packet_id);
+    packet.put_slice(data);
+    packet.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_audio_packet() {
+        let packet = frame_audio_packet(0x0102_0304, 7, &[0xaa, 0xbb]);
+        assert_eq!(packet, [1, 1, 2, 3, 4, 0, 0, 0, 7, 0xaa, 0xbb]);
+    }
 }
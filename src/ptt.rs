@@ -0,0 +1,242 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Push-to-talk transmission of captured audio
+
+use std::sync::Arc;
+
+use crate::client::{ClientHandle, StopReason, TransmitEvent};
+use crate::encoder::{EncoderConfig, OPUS_CODEC, OpusEncoder};
+use crate::error::Result;
+use crate::outbound::OutboundStreamHandle;
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::{error, info, warn};
+
+/// Shared push-to-talk control
+///
+/// Clones control the same button, so it can be pressed from a key handler,
+/// a signal handler or application code.
+#[derive(Debug, Clone)]
+pub struct PttControl {
+    pressed: Arc<watch::Sender<bool>>,
+}
+
+impl Default for PttControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PttControl {
+    /// Create a released push-to-talk control
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            pressed: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    /// Start transmitting
+    pub fn press(&self) {
+        self.pressed.send_replace(true);
+    }
+
+    /// Stop transmitting
+    pub fn release(&self) {
+        self.pressed.send_replace(false);
+    }
+
+    /// Press if released, release if pressed
+    pub fn toggle(&self) {
+        self.pressed.send_modify(|p| *p = !*p);
+    }
+
+    /// Whether the control is pressed
+    #[must_use]
+    pub fn is_pressed(&self) -> bool {
+        *self.pressed.borrow()
+    }
+
+    /// Watch for presses and releases
+    #[must_use]
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.pressed.subscribe()
+    }
+}
+
+/// Transmit captured audio while push-to-talk is pressed
+///
+/// Each press starts a stream through `client` and each release stops it.
+/// Audio captured while released is discarded. If the transmit time-out
+/// timer stops a stream, push-to-talk must be released before the next
+/// transmission. Returns when the capture ends or every [`PttControl`] has
+/// been dropped.
+///
+/// # Errors
+///
+/// Returns an error if the encoder settings are invalid
+pub async fn transmit_with_ptt(
+    client: ClientHandle,
+    ptt: PttControl,
+    mut pcm_rx: mpsc::Receiver<Vec<i16>>,
+    config: EncoderConfig,
+) -> Result<()> {
+    let mut pressed = ptt.subscribe();
+    drop(ptt);
+
+    loop {
+        // Wait for a press, discarding audio
+        while !*pressed.borrow_and_update() {
+            tokio::select! {
+                changed = pressed.changed() => if changed.is_err() { return Ok(()) },
+                pcm = pcm_rx.recv() => if pcm.is_none() { return Ok(()) },
+            }
+        }
+
+        let mut events = client.transmit_events();
+        let mut stream = match PcmStream::start(&client, &config).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to start transmission: {e}");
+                wait_for_release(&mut pressed).await;
+                continue;
+            }
+        };
+
+        let ended = loop {
+            tokio::select! {
+                changed = pressed.changed() => {
+                    if changed.is_err() {
+                        break PttEnd::ControlDropped;
+                    }
+                    if !*pressed.borrow_and_update() {
+                        break PttEnd::Released;
+                    }
+                }
+                pcm = pcm_rx.recv() => {
+                    let Some(pcm) = pcm else { break PttEnd::CaptureEnded };
+                    stream.send(&pcm)?;
+                }
+                event = events.recv() => {
+                    if is_timed_out(&event, stream.stream_id()) {
+                        break PttEnd::TimedOut;
+                    }
+                }
+            }
+        };
+
+        match ended {
+            PttEnd::Released => stream.finish().await?,
+            PttEnd::ControlDropped | PttEnd::CaptureEnded => {
+                stream.finish().await?;
+                return Ok(());
+            }
+            PttEnd::TimedOut => {
+                warn!("Transmission timed out; release push-to-talk to continue");
+                wait_for_release(&mut pressed).await;
+            }
+        }
+    }
+}
+
+/// Why a push-to-talk transmission ended
+enum PttEnd {
+    Released,
+    ControlDropped,
+    CaptureEnded,
+    TimedOut,
+}
+
+/// Whether a transmit event reports that the time-out timer stopped `stream_id`
+///
+/// A closed event channel means the client has stopped, which also ends the stream.
+pub(crate) fn is_timed_out(
+    event: &std::result::Result<TransmitEvent, broadcast::error::RecvError>,
+    stream_id: u32,
+) -> bool {
+    match event {
+        Ok(TransmitEvent::Stopped {
+            stream_id: stopped,
+            reason: StopReason::TimedOut,
+        }) => *stopped == stream_id,
+        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => false,
+        Err(broadcast::error::RecvError::Closed) => true,
+    }
+}
+
+/// An outbound stream fed with 16kHz mono PCM
+#[derive(Debug)]
+pub(crate) struct PcmStream {
+    stream: OutboundStreamHandle,
+    encoder: OpusEncoder,
+}
+
+impl PcmStream {
+    /// Start a stream through `client`, encoding with `config`
+    pub(crate) async fn start(client: &ClientHandle, config: &EncoderConfig) -> Result<Self> {
+        let encoder = OpusEncoder::new(config.clone())?;
+        let stream = client
+            .start_audio_stream(
+                OPUS_CODEC,
+                Some(encoder.codec_header()),
+                encoder.packet_duration(),
+            )
+            .await?;
+        info!("Transmitting on stream {}", stream.stream_id());
+
+        Ok(Self { stream, encoder })
+    }
+
+    /// Id of the stream
+    pub(crate) fn stream_id(&self) -> u32 {
+        self.stream.stream_id()
+    }
+
+    /// Encode PCM and send every whole packet
+    pub(crate) fn send(&mut self, pcm: &[i16]) -> Result<()> {
+        for packet in self.encoder.encode(pcm)? {
+            self.stream.send(packet)?;
+        }
+        Ok(())
+    }
+
+    /// Send any held audio and stop the stream
+    pub(crate) async fn finish(mut self) -> Result<()> {
+        if let Some(packet) = self.encoder.flush()? {
+            self.stream.send(packet)?;
+        }
+        let stream_id = self.stream.stream_id();
+        self.stream.finish().await?;
+        info!("Stopped transmitting on stream {stream_id}");
+        Ok(())
+    }
+}
+
+async fn wait_for_release(pressed: &mut watch::Receiver<bool>) {
+    while *pressed.borrow_and_update() {
+        if pressed.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ptt_control() {
+        let ptt = PttControl::new();
+        let other = ptt.clone();
+        assert!(!ptt.is_pressed());
+
+        other.press();
+        assert!(ptt.is_pressed());
+        ptt.toggle();
+        assert!(!other.is_pressed());
+        ptt.toggle();
+        assert!(other.is_pressed());
+        other.release();
+        assert!(!ptt.is_pressed());
+    }
+}
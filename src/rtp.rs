@@ -0,0 +1,730 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! RTP bridge for radio-over-IP and voice-over-IP equipment
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::PCM_I16_TO_F32;
+use crate::audio_file::packet_duration_us;
+use crate::capture::CaptureConverter;
+use crate::client::ClientHandle;
+use crate::encoder::{EncoderConfig, OPUS_CODEC};
+use crate::error::{Result, ZelloError};
+use crate::inbound::InboundTransmission;
+use crate::message::CodecHeader;
+use crate::outbound::OutboundStreamHandle;
+use crate::ptt::{PcmStream, is_timed_out};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::{debug, error, info, warn};
+
+/// RTP protocol version
+const RTP_VERSION: u8 = 2;
+
+/// Size of the fixed RTP header
+const RTP_HEADER_SIZE: usize = 12;
+
+/// Static payload type for G.711 µ-law
+pub const PCMU_PAYLOAD_TYPE: u8 = 0;
+
+/// RTP clock rate for Opus, which is 48kHz whatever the audio bandwidth
+const OPUS_CLOCK_RATE: u32 = 48_000;
+
+/// Sample rate of G.711 audio
+const PCMU_SAMPLE_RATE: u32 = 8_000;
+
+/// Largest datagram read from the socket
+const MAX_DATAGRAM_SIZE: usize = 1500;
+
+/// An RTP packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtpPacket {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: Vec<u8>,
+}
+
+impl RtpPacket {
+    /// Parse a datagram, skipping any CSRCs, header extension and padding
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the datagram is not a valid RTP packet
+    pub fn parse(datagram: &[u8]) -> Result<Self> {
+        let invalid =
+            |reason: &str| ZelloError::ProtocolError(format!("Invalid RTP packet: {reason}"));
+
+        if datagram.len() < RTP_HEADER_SIZE {
+            return Err(invalid("too short"));
+        }
+        if datagram[0] >> 6 != RTP_VERSION {
+            return Err(invalid("not version 2"));
+        }
+
+        let padding = datagram[0] & 0x20 != 0;
+        let extension = datagram[0] & 0x10 != 0;
+        let csrc_count = usize::from(datagram[0] & 0x0f);
+
+        let mut start = RTP_HEADER_SIZE + 4 * csrc_count;
+        if extension {
+            let words = datagram
+                .get(start + 2..start + 4)
+                .ok_or_else(|| invalid("truncated extension"))?;
+            start += 4 + 4 * usize::from(u16::from_be_bytes([words[0], words[1]]));
+        }
+
+        let mut end = datagram.len();
+        if padding {
+            end = end.saturating_sub(usize::from(datagram[end - 1]));
+        }
+        let payload = datagram
+            .get(start..end)
+            .ok_or_else(|| invalid("truncated payload"))?;
+
+        Ok(Self {
+            marker: datagram[1] & 0x80 != 0,
+            payload_type: datagram[1] & 0x7f,
+            sequence: u16::from_be_bytes([datagram[2], datagram[3]]),
+            timestamp: u32::from_be_bytes([datagram[4], datagram[5], datagram[6], datagram[7]]),
+            ssrc: u32::from_be_bytes([datagram[8], datagram[9], datagram[10], datagram[11]]),
+            payload: payload.to_vec(),
+        })
+    }
+
+    /// Serialize the packet with a fixed header
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(RTP_HEADER_SIZE + self.payload.len());
+        datagram.push(RTP_VERSION << 6);
+        datagram.push((u8::from(self.marker) << 7) | (self.payload_type & 0x7f));
+        datagram.extend_from_slice(&self.sequence.to_be_bytes());
+        datagram.extend_from_slice(&self.timestamp.to_be_bytes());
+        datagram.extend_from_slice(&self.ssrc.to_be_bytes());
+        datagram.extend_from_slice(&self.payload);
+        datagram
+    }
+}
+
+/// Settings for the RTP bridge
+#[derive(Debug, Clone)]
+pub struct RtpConfig {
+    /// Dynamic payload type used for Opus
+    pub opus_payload_type: u8,
+    /// Silence on the socket after which an inbound RTP stream is stopped
+    pub hang_time: Duration,
+    /// Encoder settings for audio that is not already Opus
+    pub encoder: EncoderConfig,
+}
+
+impl Default for RtpConfig {
+    fn default() -> Self {
+        Self {
+            opus_payload_type: 111,
+            hang_time: Duration::from_millis(500),
+            encoder: EncoderConfig::default(),
+        }
+    }
+}
+
+/// Send each inbound transmission to `destination` as an RTP Opus stream
+///
+/// Every transmission gets its own random SSRC and starting sequence number,
+/// and its first packet carries the marker bit. Returns when the client stops
+/// delivering transmissions.
+///
+/// # Errors
+///
+/// Returns an error if a packet cannot be sent
+pub async fn send_rtp(
+    mut transmissions: mpsc::UnboundedReceiver<InboundTransmission>,
+    socket: UdpSocket,
+    destination: SocketAddr,
+    config: &RtpConfig,
+) -> Result<()> {
+    let random = RandomState::new();
+
+    while let Some(mut transmission) = transmissions.recv().await {
+        let info = transmission.info().clone();
+        // Split a random value into the SSRC and the initial timestamp, and hash it
+        // again for an independent initial sequence number
+        let seed = random
+            .hash_one((info.stream_id, info.started_at))
+            .to_be_bytes();
+        let ssrc = u32::from_be_bytes([seed[0], seed[1], seed[2], seed[3]]);
+        let mut timestamp = u32::from_be_bytes([seed[4], seed[5], seed[6], seed[7]]);
+        let [.., high, low] = random.hash_one(seed).to_be_bytes();
+        let mut sequence = u16::from_be_bytes([high, low]);
+        let step = info.packet_duration * (OPUS_CLOCK_RATE / 1000);
+        info!(
+            "Sending stream {} from {} to {destination} as RTP SSRC {ssrc:08x}",
+            info.stream_id, info.from
+        );
+
+        let mut last_packet_id = None;
+        while let Some(packet) = transmission.next_packet().await {
+            // Advance the timestamp over packets lost before they reached us
+            if let Some(last) = last_packet_id {
+                let lost = packet.packet_id.wrapping_sub(last).saturating_sub(1);
+                timestamp = timestamp.wrapping_add(step.wrapping_mul(lost));
+            }
+
+            let rtp = RtpPacket {
+                marker: last_packet_id.is_none(),
+                payload_type: config.opus_payload_type,
+                sequence,
+                timestamp,
+                ssrc,
+                payload: packet.data,
+            };
+            socket.send_to(&rtp.to_bytes(), destination).await?;
+
+            let duration = packet_duration_us(&rtp.payload)
+                .map_or(step, |us| us * (OPUS_CLOCK_RATE / 1000) / 1000);
+            sequence = sequence.wrapping_add(1);
+            timestamp = timestamp.wrapping_add(duration);
+            last_packet_id = Some(packet.packet_id);
+        }
+    }
+    Ok(())
+}
+
+/// An RTP stream being transmitted to Zello
+enum RtpInbound {
+    Opus(OutboundStreamHandle),
+    Pcmu(PcmStream, Box<CaptureConverter>),
+}
+
+impl RtpInbound {
+    fn stream_id(&self) -> u32 {
+        match self {
+            Self::Opus(stream) => stream.stream_id(),
+            Self::Pcmu(stream, _) => stream.stream_id(),
+        }
+    }
+
+    /// Send the payload of an RTP packet
+    fn send(&mut self, payload: Vec<u8>) -> Result<()> {
+        match self {
+            Self::Opus(stream) => stream.send(payload),
+            Self::Pcmu(stream, converter) => {
+                let samples: Vec<f32> = payload
+                    .iter()
+                    .map(|&byte| f32::from(mulaw_to_linear(byte)) * PCM_I16_TO_F32)
+                    .collect();
+                let mut pcm = Vec::new();
+                converter.process(&samples, &mut pcm);
+                stream.send(&pcm)
+            }
+        }
+    }
+
+    async fn finish(self) -> Result<()> {
+        match self {
+            Self::Opus(stream) => stream.finish().await,
+            Self::Pcmu(stream, _) => stream.finish().await,
+        }
+    }
+}
+
+/// Transmit RTP Opus or PCMU audio arriving on `socket` to the channel
+///
+/// A stream is started when packets arrive and stopped when none have
+/// arrived for the hang time or a new SSRC takes over. Opus is forwarded
+/// without transcoding; PCMU is resampled and encoded. Packets with other
+/// payload types, and late or repeated packets, are dropped.
+///
+/// # Errors
+///
+/// Returns an error if the socket fails or the client has stopped
+pub async fn receive_rtp(client: ClientHandle, socket: UdpSocket, config: RtpConfig) -> Result<()> {
+    let mut events = client.transmit_events();
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut stream: Option<RtpInbound> = None;
+    let mut source: Option<(u32, u16)> = None;
+    let mut held_off = false;
+
+    loop {
+        let wait = if source.is_some() {
+            config.hang_time
+        } else {
+            Duration::MAX
+        };
+
+        let received = tokio::select! {
+            received = timeout(wait, socket.recv_from(&mut buffer)) => received,
+            event = events.recv() => {
+                if stream.as_ref().is_some_and(|s| is_timed_out(&event, s.stream_id())) {
+                    warn!("RTP transmission timed out; waiting for the input to go quiet");
+                    stream = None;
+                    held_off = true;
+                }
+                continue;
+            }
+        };
+
+        let Ok(received) = received else {
+            // Quiet for the hang time
+            if let Some(stream) = stream.take() {
+                stream.finish().await?;
+            }
+            source = None;
+            held_off = false;
+            continue;
+        };
+        let (size, from) = received?;
+
+        let packet = match RtpPacket::parse(&buffer[..size]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("Ignoring datagram from {from}: {e}");
+                continue;
+            }
+        };
+        if packet.payload_type != config.opus_payload_type
+            && packet.payload_type != PCMU_PAYLOAD_TYPE
+        {
+            debug!(
+                "Ignoring RTP payload type {} from {from}",
+                packet.payload_type
+            );
+            continue;
+        }
+
+        match source {
+            // Drop repeated and late packets
+            Some((ssrc, last)) if ssrc == packet.ssrc && !follows(packet.sequence, last) => {
+                continue;
+            }
+            Some((ssrc, _)) if ssrc == packet.ssrc => {}
+            Some(_) => {
+                info!("RTP source changed to SSRC {:08x}", packet.ssrc);
+                if let Some(stream) = stream.take() {
+                    stream.finish().await?;
+                }
+                held_off = false;
+            }
+            None => {}
+        }
+        source = Some((packet.ssrc, packet.sequence));
+
+        if held_off {
+            continue;
+        }
+        if stream.is_none() {
+            match start_inbound(&client, &packet, &config).await {
+                Ok(started) => {
+                    info!(
+                        "Transmitting RTP SSRC {:08x} from {from} on stream {}",
+                        packet.ssrc,
+                        started.stream_id()
+                    );
+                    stream = Some(started);
+                }
+                Err(ZelloError::NotConnected) => return Err(ZelloError::NotConnected),
+                Err(e) => {
+                    error!("Failed to start RTP transmission: {e}");
+                    held_off = true;
+                    continue;
+                }
+            }
+        }
+
+        if let Some(stream) = &mut stream {
+            stream.send(packet.payload)?;
+        }
+    }
+}
+
+/// Start a Zello stream for the first packet of an RTP stream
+async fn start_inbound(
+    client: &ClientHandle,
+    packet: &RtpPacket,
+    config: &RtpConfig,
+) -> Result<RtpInbound> {
+    if packet.payload_type == PCMU_PAYLOAD_TYPE {
+        let converter = CaptureConverter::new(PCMU_SAMPLE_RATE)
+            .map_err(|e| ZelloError::AudioError(e.to_string()))?;
+        let stream = PcmStream::start(client, &config.encoder).await?;
+        return Ok(RtpInbound::Pcmu(stream, Box::new(converter)));
+    }
+
+    let duration_ms = packet_duration_us(&packet.payload).map_or(20, |us| us / 1000);
+    let header = CodecHeader {
+        sample_rate_hz: 16000,
+        frames_per_packet: 1,
+        frame_size_ms: u8::try_from(duration_ms).unwrap_or(60),
+    };
+    let stream = client
+        .start_audio_stream(OPUS_CODEC, Some(header), duration_ms)
+        .await?;
+    Ok(RtpInbound::Opus(stream))
+}
+
+/// Whether `sequence` comes after `last`, allowing for wrap-around
+fn follows(sequence: u16, last: u16) -> bool {
+    let ahead = sequence.wrapping_sub(last);
+    ahead != 0 && ahead < u16::MAX / 2
+}
+
+/// Decode a G.711 µ-law sample
+fn mulaw_to_linear(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = i16::from(byte & 0x0f);
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    if byte & 0x80 == 0 {
+        magnitude
+    } else {
+        -magnitude
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ClientCommand, StopReason, TransmitEvent};
+    use crate::floor::FloorState;
+    use crate::inbound::InboundPacket;
+    use crate::inbound::tests::info;
+    use tokio::sync::{broadcast, watch};
+
+    #[test]
+    fn test_packet_round_trip() {
+        let packet = RtpPacket {
+            marker: true,
+            payload_type: 111,
+            sequence: 65535,
+            timestamp: 0xdead_beef,
+            ssrc: 0x1234_5678,
+            payload: vec![1, 2, 3],
+        };
+        assert_eq!(RtpPacket::parse(&packet.to_bytes()).expect("parse"), packet);
+    }
+
+    #[test]
+    fn test_parse_skips_csrcs_extension_and_padding() {
+        let mut datagram = vec![0xb1, 0x00, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        datagram.extend_from_slice(&[9, 9, 9, 9]); // one CSRC
+        datagram.extend_from_slice(&[0xbe, 0xde, 0, 1, 7, 7, 7, 7]); // one-word extension
+        datagram.extend_from_slice(&[0xaa, 0xbb]);
+        datagram.extend_from_slice(&[0, 0, 3]); // three bytes of padding
+
+        let packet = RtpPacket::parse(&datagram).expect("parse");
+        assert_eq!(packet.payload_type, PCMU_PAYLOAD_TYPE);
+        assert_eq!(packet.ssrc, 3);
+        assert_eq!(packet.payload, [0xaa, 0xbb]);
+
+        assert!(RtpPacket::parse(&datagram[..8]).is_err());
+    }
+
+    #[test]
+    fn test_sequence_wraps() {
+        assert!(follows(1, 0));
+        assert!(follows(0, u16::MAX));
+        assert!(!follows(5, 5));
+        assert!(!follows(4, 5));
+    }
+
+    #[test]
+    fn test_mulaw() {
+        assert_eq!(mulaw_to_linear(0xff), 0);
+        assert_eq!(mulaw_to_linear(0x80), 32124);
+        assert_eq!(mulaw_to_linear(0x00), -32124);
+    }
+
+    #[tokio::test]
+    async fn test_sends_transmission_as_rtp() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.expect("bind");
+        let sender = UdpSocket::bind("127.0.0.1:0").await.expect("bind");
+        let destination = receiver.local_addr().expect("address");
+
+        let (transmissions_tx, transmissions) = mpsc::unbounded_channel();
+        let (packets_tx, transmission) = InboundTransmission::new(info());
+        transmissions_tx.send(transmission).expect("send");
+        drop(transmissions_tx);
+        // Packet 2 is lost on the way in; TOC 0x18 is one 60ms frame
+        for packet_id in [0, 1, 3] {
+            packets_tx
+                .send(InboundPacket {
+                    packet_id,
+                    data: vec![0x18, 0],
+                })
+                .expect("send");
+        }
+        drop(packets_tx);
+
+        send_rtp(transmissions, sender, destination, &RtpConfig::default())
+            .await
+            .expect("send");
+
+        let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+        let mut packets = Vec::new();
+        for _ in 0..3 {
+            let size = receiver.recv(&mut buffer).await.expect("receive");
+            packets.push(RtpPacket::parse(&buffer[..size]).expect("parse"));
+        }
+
+        assert!(packets[0].marker && !packets[1].marker && !packets[2].marker);
+        assert!(
+            packets
+                .iter()
+                .all(|p| p.payload_type == 111 && p.ssrc == packets[0].ssrc)
+        );
+        assert_eq!(packets[1].sequence, packets[0].sequence.wrapping_add(1));
+        assert_eq!(packets[2].sequence, packets[0].sequence.wrapping_add(2));
+        assert_eq!(
+            packets[1].timestamp.wrapping_sub(packets[0].timestamp),
+            2880
+        );
+        assert_eq!(
+            packets[2].timestamp.wrapping_sub(packets[1].timestamp),
+            5760
+        );
+    }
+
+    /// A command the RTP receiver gave the client
+    #[derive(Debug, PartialEq, Eq)]
+    enum Sent {
+        Start {
+            stream_id: u32,
+            packet_duration: u32,
+        },
+        Packet {
+            stream_id: u32,
+            data: Vec<u8>,
+        },
+        Stop {
+            stream_id: u32,
+        },
+    }
+
+    /// A client that starts streams numbered from 1 and reports every command
+    fn fake_client() -> (
+        ClientHandle,
+        broadcast::Sender<TransmitEvent>,
+        mpsc::UnboundedReceiver<Sent>,
+    ) {
+        let (commands, mut received) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(8);
+        let (floor, floor_rx) = watch::channel(FloorState::Clear);
+        let (sent_tx, sent) = mpsc::unbounded_channel();
+        let handle = ClientHandle::new(commands, events.clone(), floor_rx);
+
+        tokio::spawn(async move {
+            let _floor = floor;
+            let mut next_stream_id = 1;
+            while let Some(command) = received.recv().await {
+                let sent = match command {
+                    ClientCommand::StartStream {
+                        packet_duration,
+                        reply,
+                        ..
+                    } => {
+                        let stream_id = next_stream_id;
+                        next_stream_id += 1;
+                        let _ = reply.send(Ok(stream_id));
+                        Sent::Start {
+                            stream_id,
+                            packet_duration,
+                        }
+                    }
+                    ClientCommand::SendAudioPacket {
+                        stream_id, data, ..
+                    } => Sent::Packet { stream_id, data },
+                    ClientCommand::StopStream { stream_id, reply } => {
+                        if let Some(reply) = reply {
+                            let _ = reply.send(Ok(()));
+                        }
+                        Sent::Stop { stream_id }
+                    }
+                    _ => continue,
+                };
+                let _ = sent_tx.send(sent);
+            }
+        });
+        (handle, events, sent)
+    }
+
+    /// Start `receive_rtp` with a short hang time, returning the socket to send to
+    async fn start_receiver(client: ClientHandle) -> (UdpSocket, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.expect("bind");
+        let address = socket.local_addr().expect("address");
+        let config = RtpConfig {
+            hang_time: Duration::from_millis(200),
+            ..RtpConfig::default()
+        };
+        tokio::spawn(receive_rtp(client, socket, config));
+        let sender = UdpSocket::bind("127.0.0.1:0").await.expect("bind");
+        (sender, address)
+    }
+
+    async fn send_packet(
+        sender: &UdpSocket,
+        address: SocketAddr,
+        ssrc: u32,
+        sequence: u16,
+        payload_type: u8,
+        payload: Vec<u8>,
+    ) {
+        let packet = RtpPacket {
+            marker: false,
+            payload_type,
+            sequence,
+            timestamp: u32::from(sequence) * 960,
+            ssrc,
+            payload,
+        };
+        sender
+            .send_to(&packet.to_bytes(), address)
+            .await
+            .expect("send");
+    }
+
+    async fn next_sent(sent: &mut mpsc::UnboundedReceiver<Sent>) -> Sent {
+        tokio::time::timeout(Duration::from_secs(2), sent.recv())
+            .await
+            .expect("no command in time")
+            .expect("client stopped")
+    }
+
+    #[tokio::test]
+    async fn test_receives_opus_and_follows_sources() {
+        let (client, _events, mut sent) = fake_client();
+        let (sender, address) = start_receiver(client).await;
+
+        // TOC 0x08 is one 20ms frame
+        for sequence in [10u8, 11, 11, 9, 12] {
+            let payload = vec![0x08, sequence];
+            send_packet(&sender, address, 1, sequence.into(), 111, payload).await;
+        }
+        assert_eq!(
+            next_sent(&mut sent).await,
+            Sent::Start {
+                stream_id: 1,
+                packet_duration: 20
+            }
+        );
+        // The repeated and late packets are dropped
+        for sequence in [10, 11, 12] {
+            assert_eq!(
+                next_sent(&mut sent).await,
+                Sent::Packet {
+                    stream_id: 1,
+                    data: vec![0x08, sequence]
+                }
+            );
+        }
+        // Quiet for the hang time
+        assert_eq!(next_sent(&mut sent).await, Sent::Stop { stream_id: 1 });
+
+        send_packet(&sender, address, 2, 500, 111, vec![0x08, 1]).await;
+        send_packet(&sender, address, 3, 7, 111, vec![0x08, 2]).await;
+        assert!(matches!(
+            next_sent(&mut sent).await,
+            Sent::Start { stream_id: 2, .. }
+        ));
+        assert!(matches!(
+            next_sent(&mut sent).await,
+            Sent::Packet { stream_id: 2, .. }
+        ));
+        // A new SSRC takes over
+        assert_eq!(next_sent(&mut sent).await, Sent::Stop { stream_id: 2 });
+        assert!(matches!(
+            next_sent(&mut sent).await,
+            Sent::Start { stream_id: 3, .. }
+        ));
+        assert_eq!(
+            next_sent(&mut sent).await,
+            Sent::Packet {
+                stream_id: 3,
+                data: vec![0x08, 2]
+            }
+        );
+
+        // Other payload types are ignored
+        send_packet(&sender, address, 3, 8, 8, vec![0xff; 160]).await;
+        assert_eq!(next_sent(&mut sent).await, Sent::Stop { stream_id: 3 });
+    }
+
+    #[tokio::test]
+    async fn test_receives_pcmu_as_encoded_opus() {
+        let (client, _events, mut sent) = fake_client();
+        let (sender, address) = start_receiver(client).await;
+
+        // 20ms packets of a µ-law square wave
+        for sequence in 0..12 {
+            let payload = (0..160)
+                .map(|i| if i % 16 < 8 { 0x90 } else { 0x10 })
+                .collect();
+            send_packet(&sender, address, 1, sequence, PCMU_PAYLOAD_TYPE, payload).await;
+        }
+
+        assert_eq!(
+            next_sent(&mut sent).await,
+            Sent::Start {
+                stream_id: 1,
+                packet_duration: 60
+            }
+        );
+        let mut packets = 0;
+        loop {
+            match next_sent(&mut sent).await {
+                Sent::Packet { stream_id: 1, data } => {
+                    assert!(!data.is_empty());
+                    packets += 1;
+                }
+                Sent::Stop { stream_id: 1 } => break,
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+        // 240ms of audio in the encoder's 60ms packets
+        assert_eq!(packets, 4, "{packets} packets encoded");
+    }
+
+    #[tokio::test]
+    async fn test_holds_off_after_time_out() {
+        let (client, events, mut sent) = fake_client();
+        let (sender, address) = start_receiver(client).await;
+
+        send_packet(&sender, address, 1, 0, 111, vec![0x08, 0]).await;
+        assert!(matches!(
+            next_sent(&mut sent).await,
+            Sent::Start { stream_id: 1, .. }
+        ));
+        assert!(matches!(
+            next_sent(&mut sent).await,
+            Sent::Packet { stream_id: 1, .. }
+        ));
+
+        events
+            .send(TransmitEvent::Stopped {
+                stream_id: 1,
+                reason: StopReason::TimedOut,
+            })
+            .expect("event");
+        // The abandoned stream is stopped
+        assert_eq!(next_sent(&mut sent).await, Sent::Stop { stream_id: 1 });
+
+        // Packets keep coming, but nothing is sent until they pause
+        for sequence in 1..4 {
+            send_packet(&sender, address, 1, sequence, 111, vec![0x08, 0]).await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(sent.try_recv().is_err());
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        send_packet(&sender, address, 1, 4, 111, vec![0x08, 0]).await;
+        assert!(matches!(
+            next_sent(&mut sent).await,
+            Sent::Start { stream_id: 2, .. }
+        ));
+    }
+}
@@ -0,0 +1,126 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Transmit time-out timer and lockout for outbound streams
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::error::{Result, ZelloError};
+use tokio::time::Instant;
+
+/// Limits on outbound transmissions
+#[derive(Debug, Clone, Default)]
+pub struct TransmitLimits {
+    /// Longest a stream may stay open before it is stopped
+    pub max_duration: Option<Duration>,
+    /// Time after a stream times out before another may be started
+    pub lockout: Option<Duration>,
+}
+
+/// Tracks open outbound streams against the transmit limits
+#[derive(Debug, Default)]
+pub(crate) struct TransmitTimer {
+    limits: TransmitLimits,
+    started: HashMap<u32, Instant>,
+    locked_until: Option<Instant>,
+}
+
+impl TransmitTimer {
+    pub(crate) fn limits(&self) -> &TransmitLimits {
+        &self.limits
+    }
+
+    pub(crate) fn set_limits(&mut self, limits: TransmitLimits) {
+        self.limits = limits;
+    }
+
+    /// Check that a new stream may be started
+    pub(crate) fn check_start(&self, now: Instant) -> Result<()> {
+        match self.locked_until {
+            Some(until) if until > now => Err(ZelloError::TransmitLockout(until - now)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn start(&mut self, stream_id: u32, now: Instant) {
+        self.started.insert(stream_id, now);
+    }
+
+    /// Forget a stream, starting the lockout if it timed out
+    pub(crate) fn stop(&mut self, stream_id: u32, now: Instant, timed_out: bool) {
+        self.started.remove(&stream_id);
+        if timed_out && let Some(lockout) = self.limits.lockout {
+            self.locked_until = Some(now + lockout);
+        }
+    }
+
+    /// Whether a stream has been open longer than allowed
+    pub(crate) fn is_expired(&self, stream_id: u32, now: Instant) -> bool {
+        self.deadline(stream_id)
+            .is_some_and(|deadline| deadline <= now)
+    }
+
+    /// Streams that have been open longer than allowed
+    pub(crate) fn expired(&self, now: Instant) -> Vec<u32> {
+        self.started
+            .keys()
+            .copied()
+            .filter(|&id| self.is_expired(id, now))
+            .collect()
+    }
+
+    /// When the next stream will time out
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.started
+            .keys()
+            .filter_map(|&id| self.deadline(id))
+            .min()
+    }
+
+    fn deadline(&self, stream_id: u32) -> Option<Instant> {
+        let started = self.started.get(&stream_id)?;
+        Some(*started + self.limits.max_duration?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_streams_expire_and_lock_out() {
+        let mut timer = TransmitTimer::default();
+        timer.set_limits(TransmitLimits {
+            max_duration: Some(Duration::from_secs(30)),
+            lockout: Some(Duration::from_secs(10)),
+        });
+
+        let start = Instant::now();
+        timer.start(1, start);
+        assert_eq!(timer.next_deadline(), Some(start + Duration::from_secs(30)));
+        assert!(timer.expired(start + Duration::from_secs(29)).is_empty());
+        assert_eq!(timer.expired(start + Duration::from_secs(30)), [1]);
+
+        let stopped = start + Duration::from_secs(30);
+        timer.stop(1, stopped, true);
+        assert_eq!(timer.next_deadline(), None);
+        assert!(matches!(
+            timer.check_start(stopped + Duration::from_secs(4)),
+            Err(ZelloError::TransmitLockout(remaining)) if remaining == Duration::from_secs(6)
+        ));
+        assert!(timer.check_start(stopped + Duration::from_secs(10)).is_ok());
+    }
+
+    #[test]
+    fn test_no_limits() {
+        let mut timer = TransmitTimer::default();
+        let start = Instant::now();
+        timer.start(1, start);
+        assert_eq!(timer.next_deadline(), None);
+        assert!(!timer.is_expired(1, start + Duration::from_secs(599)));
+
+        timer.stop(1, start, false);
+        assert!(timer.check_start(start).is_ok());
+    }
+}
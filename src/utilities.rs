@@ -3,30 +3,43 @@
 
 //! Utility functions for Zello client operations
 
-use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::encoder::{EncoderConfig, OpusEncoder};
+use crate::playback::{
+    PlaybackConfig, PlaybackReader, PlaybackStats, PlaybackWriter, playback_buffer,
+    process_audio_output,
+};
 use crate::{
-    CPAL_BUFFER_SIZE, CPAL_CHANNELS, CPAL_SAMPLE_RATE, CPAL_VECTOR_QUEUE_CAPACITY, OPUS_CHANNELS,
-    OPUS_SAMPLE_RATE, PCM_I16_TO_F32,
+    CPAL_BUFFER_SIZE, CPAL_CHANNELS, CPAL_SAMPLE_RATE, OPUS_CHANNELS, OPUS_SAMPLE_RATE,
+    PCM_BUFFER_SIZE,
 };
 use crate::{Credentials, ZelloClient, ZelloConfig};
 use anyhow::{Result, anyhow};
 use audiopus::coder::Decoder;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, Stream, StreamConfig};
-use crossbeam_channel::Receiver;
+use cpal::{
+    BufferSize, Device, FromSample, SampleFormat, SizedSample, Stream, StreamConfig,
+    SupportedBufferSize,
+};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
 use dotenvy::{dotenv, from_path};
 use tokio::sync::Mutex;
-use tracing::{debug, error, info};
+use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
 
 /// Initialize logging with environment filter
+///
+/// Logs go to standard error, leaving standard output free for audio.
 pub fn initialize_logging() -> Result<()> {
     tracing_subscriber::fmt()
         .with_env_filter(
             EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
         )
+        .with_writer(std::io::stderr)
         .init();
     Ok(())
 }
@@ -74,9 +87,72 @@ pub fn load_credentials() -> Result<Credentials> {
         password,
         token,
         channel,
+        server_url: std::env::var("ZELLO_SERVER").ok(),
     })
 }
 
+/// Load credentials for the far side of a bridge to `channel`
+///
+/// `ZELLO_BRIDGE_USERNAME`, `ZELLO_BRIDGE_PASSWORD`, `ZELLO_BRIDGE_TOKEN` and
+/// `ZELLO_BRIDGE_SERVER` override the corresponding `primary` credentials.
+#[must_use]
+pub fn load_bridge_credentials(primary: &Credentials, channel: String) -> Credentials {
+    let var =
+        |name: &str, fallback: &str| std::env::var(name).unwrap_or_else(|_| fallback.to_string());
+
+    Credentials {
+        username: var("ZELLO_BRIDGE_USERNAME", &primary.username),
+        password: var("ZELLO_BRIDGE_PASSWORD", &primary.password),
+        token: var("ZELLO_BRIDGE_TOKEN", &primary.token),
+        channel,
+        server_url: std::env::var("ZELLO_BRIDGE_SERVER")
+            .ok()
+            .or_else(|| primary.server_url.clone()),
+    }
+}
+
+/// Load the token that callers of the gateway must present
+///
+/// # Errors
+///
+/// Returns an error if `ZELLO_GATEWAY_TOKEN` is not set or is empty
+pub fn load_gateway_token() -> Result<String> {
+    std::env::var("ZELLO_GATEWAY_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| anyhow!("Please set ZELLO_GATEWAY_TOKEN environment variable"))
+}
+
+/// Load the MQTT broker login from `ZELLO_MQTT_USERNAME` and `ZELLO_MQTT_PASSWORD`
+///
+/// Returns `None` if no username is set, for brokers that allow anonymous clients.
+#[must_use]
+pub fn load_mqtt_credentials() -> Option<(String, String)> {
+    let username = std::env::var("ZELLO_MQTT_USERNAME")
+        .ok()
+        .filter(|username| !username.is_empty())?;
+    let password = std::env::var("ZELLO_MQTT_PASSWORD").unwrap_or_default();
+    Some((username, password))
+}
+
+/// Load the IRC server password from `ZELLO_IRC_PASSWORD`, if it is set
+#[must_use]
+pub fn load_irc_password() -> Option<String> {
+    std::env::var("ZELLO_IRC_PASSWORD")
+        .ok()
+        .filter(|password| !password.is_empty())
+}
+
+/// Load the key for signing webhook bodies from `ZELLO_WEBHOOK_SECRET`
+///
+/// Returns `None` if it is not set or is empty, in which case bodies are unsigned.
+#[must_use]
+pub fn load_webhook_secret() -> Option<String> {
+    std::env::var("ZELLO_WEBHOOK_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+}
+
 /// Create an Opus audio decoder
 ///
 /// # Errors
@@ -87,15 +163,257 @@ pub fn create_decoder() -> Result<Arc<Mutex<Decoder>>> {
     Ok(Arc::new(Mutex::new(decoder)))
 }
 
-/// Get the default audio output device
+/// Create an Opus audio encoder for outbound streams
 ///
 /// # Errors
 ///
-/// Returns an error if no output device is found
-pub fn get_audio_device() -> Result<Device> {
+/// Returns an error if the settings are invalid or encoder creation fails
+pub fn create_encoder(config: EncoderConfig) -> Result<OpusEncoder> {
+    Ok(OpusEncoder::new(config)?)
+}
+
+/// Selection of an audio input or output device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// The host's default device
+    #[default]
+    Default,
+    /// Device at this position in the list from `list_output_devices` or `list_input_devices`
+    Index(usize),
+    /// Device with this name (case-insensitive, or a unique partial match)
+    Name(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() || s.eq_ignore_ascii_case("default") {
+            Ok(Self::Default)
+        } else if let Ok(index) = s.parse::<usize>() {
+            Ok(Self::Index(index))
+        } else {
+            Ok(Self::Name(s.to_string()))
+        }
+    }
+}
+
+/// Description of an audio input or output device
+#[derive(Debug, Clone)]
+pub struct AudioDeviceInfo {
+    pub index: usize,
+    pub name: String,
+    pub is_default: bool,
+    pub sample_rates: Vec<(u32, u32)>,
+    pub channels: Vec<u16>,
+}
+
+impl fmt::Display for AudioDeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let marker = if self.is_default { "*" } else { " " };
+        let rates = self
+            .sample_rates
+            .iter()
+            .map(|(min, max)| {
+                if min == max {
+                    format!("{min}")
+                } else {
+                    format!("{min}-{max}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let channels = self
+            .channels
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(
+            f,
+            "{marker}{:>3}: {} (rates: {rates} Hz; channels: {channels})",
+            self.index, self.name
+        )
+    }
+}
+
+/// List the available audio output devices
+///
+/// # Errors
+///
+/// Returns an error if the devices cannot be enumerated
+pub fn list_output_devices() -> Result<Vec<AudioDeviceInfo>> {
+    list_devices(Direction::Output)
+}
+
+/// List the available audio input devices
+///
+/// # Errors
+///
+/// Returns an error if the devices cannot be enumerated
+pub fn list_input_devices() -> Result<Vec<AudioDeviceInfo>> {
+    list_devices(Direction::Input)
+}
+
+/// Whether a device is used for playback or capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Input,
+    Output,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Input => write!(f, "input"),
+            Self::Output => write!(f, "output"),
+        }
+    }
+}
+
+fn host_devices(host: &cpal::Host, direction: Direction) -> Result<Vec<Device>> {
+    Ok(match direction {
+        Direction::Input => host.input_devices()?.collect(),
+        Direction::Output => host.output_devices()?.collect(),
+    })
+}
+
+fn list_devices(direction: Direction) -> Result<Vec<AudioDeviceInfo>> {
+    let host = cpal::default_host();
+    let default_device = match direction {
+        Direction::Input => host.default_input_device(),
+        Direction::Output => host.default_output_device(),
+    };
+    let default_name = default_device.and_then(|d| d.name().ok());
+
+    let mut devices = Vec::new();
+    for (index, device) in host_devices(&host, direction)?.into_iter().enumerate() {
+        let name = device
+            .name()
+            .unwrap_or_else(|_| format!("<device {index}>"));
+
+        let supported: Vec<_> = match direction {
+            Direction::Input => device
+                .supported_input_configs()
+                .map(Iterator::collect)
+                .unwrap_or_default(),
+            Direction::Output => device
+                .supported_output_configs()
+                .map(Iterator::collect)
+                .unwrap_or_default(),
+        };
+
+        let mut sample_rates = Vec::new();
+        let mut channels = Vec::new();
+        for config in supported {
+            let range = (config.min_sample_rate().0, config.max_sample_rate().0);
+            if !sample_rates.contains(&range) {
+                sample_rates.push(range);
+            }
+            if !channels.contains(&config.channels()) {
+                channels.push(config.channels());
+            }
+        }
+        sample_rates.sort_unstable();
+        channels.sort_unstable();
+
+        devices.push(AudioDeviceInfo {
+            index,
+            is_default: default_name.as_deref() == Some(name.as_str()),
+            name,
+            sample_rates,
+            channels,
+        });
+    }
+
+    Ok(devices)
+}
+
+/// Get an audio output device
+///
+/// # Errors
+///
+/// Returns an error if no matching output device is found
+pub fn get_audio_device(selector: &DeviceSelector) -> Result<Device> {
+    select_device(selector, Direction::Output)
+}
+
+/// Get an audio input device
+///
+/// # Errors
+///
+/// Returns an error if no matching input device is found
+pub fn get_input_device(selector: &DeviceSelector) -> Result<Device> {
+    select_device(selector, Direction::Input)
+}
+
+fn select_device(selector: &DeviceSelector, direction: Direction) -> Result<Device> {
     let host = cpal::default_host();
-    host.default_output_device()
-        .ok_or_else(|| anyhow!("No output device found"))
+
+    let index = match selector {
+        DeviceSelector::Default => {
+            let device = match direction {
+                Direction::Input => host.default_input_device(),
+                Direction::Output => host.default_output_device(),
+            };
+            return device.ok_or_else(|| anyhow!("No {direction} device found"));
+        }
+        DeviceSelector::Index(index) => *index,
+        DeviceSelector::Name(name) => {
+            let devices = list_devices(direction)?;
+            find_device_index(&devices, name).ok_or_else(|| {
+                anyhow!(
+                    "No {direction} device matches '{name}'. Available devices:\n{}",
+                    format_device_list(&devices)
+                )
+            })?
+        }
+    };
+
+    host_devices(&host, direction)?
+        .into_iter()
+        .nth(index)
+        .ok_or_else(|| {
+            let devices = list_devices(direction).unwrap_or_default();
+            anyhow!(
+                "No {direction} device at index {index}. Available devices:\n{}",
+                format_device_list(&devices)
+            )
+        })
+}
+
+/// Find a device by exact, case-insensitive or unique partial name
+fn find_device_index(devices: &[AudioDeviceInfo], name: &str) -> Option<usize> {
+    if let Some(device) = devices.iter().find(|d| d.name == name) {
+        return Some(device.index);
+    }
+
+    let wanted = name.to_lowercase();
+    if let Some(device) = devices.iter().find(|d| d.name.to_lowercase() == wanted) {
+        return Some(device.index);
+    }
+
+    let mut partial = devices
+        .iter()
+        .filter(|d| d.name.to_lowercase().contains(&wanted));
+    match (partial.next(), partial.next()) {
+        (Some(device), None) => Some(device.index),
+        _ => None,
+    }
+}
+
+/// Format a device list, one device per line
+#[must_use]
+pub fn format_device_list(devices: &[AudioDeviceInfo]) -> String {
+    if devices.is_empty() {
+        return "  (none)".to_string();
+    }
+    devices
+        .iter()
+        .map(|d| format!("  {d}"))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 /// Create audio stream configuration
@@ -108,57 +426,202 @@ pub fn create_stream_config() -> StreamConfig {
     }
 }
 
+/// Negotiate an output stream configuration supported by the device
+///
+/// The Zello format (16kHz mono) is used when the device supports it, otherwise
+/// the device's default configuration. The buffer size is kept to the same
+/// duration as `PCM_BUFFER_SIZE` at the Zello rate, within the device's limits.
+///
+/// # Errors
+///
+/// Returns an error if the device configurations cannot be queried
+pub fn negotiate_output_config(device: &Device) -> Result<(StreamConfig, SampleFormat)> {
+    let preferred = create_stream_config();
+
+    let supported = device
+        .supported_output_configs()?
+        .filter(|c| {
+            format_rank(c.sample_format()).is_some()
+                && c.min_sample_rate() <= preferred.sample_rate
+                && preferred.sample_rate <= c.max_sample_rate()
+        })
+        .min_by_key(|c| {
+            (
+                c.channels().abs_diff(preferred.channels),
+                format_rank(c.sample_format()),
+            )
+        })
+        .map(|c| c.with_sample_rate(preferred.sample_rate));
+
+    let supported = match supported {
+        Some(config) => config,
+        None => device.default_output_config()?,
+    };
+
+    if format_rank(supported.sample_format()).is_none() {
+        return Err(anyhow!(
+            "Unsupported output sample format: {}",
+            supported.sample_format()
+        ));
+    }
+
+    let sample_rate = supported.sample_rate();
+    let frames = u64::try_from(PCM_BUFFER_SIZE).unwrap_or(u64::MAX) * u64::from(sample_rate.0)
+        / u64::from(preferred.sample_rate.0);
+    let frames = cpal::FrameCount::try_from(frames).unwrap_or(cpal::FrameCount::MAX);
+    let buffer_size = match supported.buffer_size() {
+        SupportedBufferSize::Range { min, max } => BufferSize::Fixed(frames.clamp(*min, *max)),
+        SupportedBufferSize::Unknown => BufferSize::Default,
+    };
+
+    Ok((
+        StreamConfig {
+            channels: supported.channels(),
+            sample_rate,
+            buffer_size,
+        },
+        supported.sample_format(),
+    ))
+}
+
+/// Preference order of the sample formats playback and capture can use
+pub(crate) fn format_rank(format: SampleFormat) -> Option<u8> {
+    match format {
+        SampleFormat::F32 => Some(0),
+        SampleFormat::I16 => Some(1),
+        SampleFormat::I32 => Some(2),
+        SampleFormat::U16 => Some(3),
+        SampleFormat::F64 => Some(4),
+        SampleFormat::U8 => Some(5),
+        SampleFormat::I8 => Some(6),
+        _ => None,
+    }
+}
+
+/// A running audio output stream and its playback counters
+pub struct AudioOutput {
+    stream: Stream,
+    stats: Arc<PlaybackStats>,
+}
+
+impl fmt::Debug for AudioOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AudioOutput")
+            .field("stats", &self.stats)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AudioOutput {
+    /// Underrun and overrun counters for this output
+    #[must_use]
+    pub fn stats(&self) -> Arc<PlaybackStats> {
+        self.stats.clone()
+    }
+
+    /// The underlying cpal stream
+    pub fn stream(&self) -> &Stream {
+        &self.stream
+    }
+}
+
 /// Setup audio output stream
 ///
 /// # Errors
 ///
 /// Returns an error if stream creation or playback fails
-pub fn setup_audio_output(pcm_rx: Arc<Mutex<Receiver<Vec<i16>>>>) -> Result<Stream> {
-    let device = get_audio_device()?;
-    let stream_config = create_stream_config();
+pub fn setup_audio_output(
+    pcm_rx: Arc<Mutex<Receiver<Vec<i16>>>>,
+    device: &DeviceSelector,
+) -> Result<AudioOutput> {
+    setup_audio_output_with_config(pcm_rx, device, PlaybackConfig::default())
+}
 
-    let mut buffer = VecDeque::<f32>::with_capacity(CPAL_VECTOR_QUEUE_CAPACITY);
-    let err_fn = |err| error!("Stream error: {err:?}");
+/// Setup audio output stream with pre-buffering and drift correction settings
+///
+/// # Errors
+///
+/// Returns an error if stream creation or playback fails
+pub fn setup_audio_output_with_config(
+    pcm_rx: Arc<Mutex<Receiver<Vec<i16>>>>,
+    device: &DeviceSelector,
+    config: PlaybackConfig,
+) -> Result<AudioOutput> {
+    let device = get_audio_device(device)?;
+    let (stream_config, sample_format) = negotiate_output_config(&device)?;
 
-    let stream = device.build_output_stream(
-        &stream_config,
-        move |output: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            process_audio_output(output, &pcm_rx, &mut buffer);
-        },
-        err_fn,
-        None,
-    )?;
+    info!(
+        "Audio output: {} ({} Hz, {} channel(s), {sample_format})",
+        device.name().unwrap_or_default(),
+        stream_config.sample_rate.0,
+        stream_config.channels
+    );
+
+    let (writer, reader, stats) =
+        playback_buffer(stream_config.sample_rate.0, stream_config.channels, config)?;
+    spawn_playback_writer(pcm_rx, writer)?;
+
+    let stream = match sample_format {
+        SampleFormat::F32 => build_output_stream::<f32>(&device, &stream_config, reader)?,
+        SampleFormat::I16 => build_output_stream::<i16>(&device, &stream_config, reader)?,
+        SampleFormat::I32 => build_output_stream::<i32>(&device, &stream_config, reader)?,
+        SampleFormat::U16 => build_output_stream::<u16>(&device, &stream_config, reader)?,
+        SampleFormat::F64 => build_output_stream::<f64>(&device, &stream_config, reader)?,
+        SampleFormat::U8 => build_output_stream::<u8>(&device, &stream_config, reader)?,
+        SampleFormat::I8 => build_output_stream::<i8>(&device, &stream_config, reader)?,
+        format => return Err(anyhow!("Unsupported output sample format: {format}")),
+    };
 
     stream.play()?;
-    Ok(stream)
+    Ok(AudioOutput { stream, stats })
 }
 
-/// Process audio output by filling the output buffer
-pub fn process_audio_output(
-    output: &mut [f32],
-    pcm_rx: &Arc<Mutex<Receiver<Vec<i16>>>>,
-    buffer: &mut VecDeque<f32>,
-) {
-    // Try to refill buffer from channel
-    if let Ok(rx) = pcm_rx.try_lock() {
-        while let Ok(pcm) = rx.try_recv() {
-            debug!("🎤 Received PCM chunk: {} samples", pcm.len());
-            for &sample in &pcm {
-                buffer.push_back(f32::from(sample) * PCM_I16_TO_F32);
+/// How often an idle playback writer checks for the end of a transmission
+const END_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Feed decoded PCM into the playback ring buffer on its own thread
+fn spawn_playback_writer(
+    pcm_rx: Arc<Mutex<Receiver<Vec<i16>>>>,
+    mut writer: PlaybackWriter,
+) -> Result<()> {
+    std::thread::Builder::new()
+        .name("zello-playback".to_string())
+        .spawn(move || {
+            let pcm_rx = pcm_rx.blocking_lock();
+            loop {
+                match pcm_rx.recv_timeout(END_POLL_INTERVAL) {
+                    Ok(pcm) => writer.write(&pcm),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                if pcm_rx.is_empty() {
+                    writer.write_requested_end();
+                }
             }
-        }
-    }
+        })?;
+    Ok(())
+}
 
-    debug!(
-        "🎤 Output buffer size: {}, internal buffer: {}",
-        output.len(),
-        buffer.len()
-    );
+/// Build an output stream producing samples of type `T`
+fn build_output_stream<T>(
+    device: &Device,
+    stream_config: &StreamConfig,
+    mut reader: PlaybackReader,
+) -> Result<Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let err_fn = |err| error!("Stream error: {err:?}");
 
-    // Fill output from buffer
-    for out in output.iter_mut() {
-        *out = buffer.pop_front().unwrap_or(0.0);
-    }
+    let stream = device.build_output_stream(
+        stream_config,
+        move |output: &mut [T], _: &cpal::OutputCallbackInfo| {
+            process_audio_output(output, &mut reader);
+        },
+        err_fn,
+        None,
+    )?;
+    Ok(stream)
 }
 
 /// Connect to Zello and authenticate
@@ -171,12 +634,16 @@ pub async fn connect_to_zello(credentials: &Credentials) -> Result<ZelloClient>
     info!("Username: {}", credentials.username);
     info!("Channel: {}", credentials.channel);
 
-    let config = ZelloConfig::new(
+    let mut config = ZelloConfig::new(
         credentials.username.clone(),
         credentials.password.clone(),
         credentials.token.clone(),
         credentials.channel.clone(),
     );
+    if let Some(url) = &credentials.server_url {
+        info!("Server: {url}");
+        config = config.with_server_url(url);
+    }
 
     match ZelloClient::new(config).await {
         Ok(client) => {
@@ -186,3 +653,46 @@ pub async fn connect_to_zello(credentials: &Credentials) -> Result<ZelloClient>
         Err(e) => Err(anyhow!("✗ Failed to connect or authenticate: {e}")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(index: usize, name: &str) -> AudioDeviceInfo {
+        AudioDeviceInfo {
+            index,
+            name: name.to_string(),
+            is_default: false,
+            sample_rates: vec![(8000, 48000)],
+            channels: vec![1, 2],
+        }
+    }
+
+    #[test]
+    fn test_device_selector_parse() {
+        assert_eq!(
+            "default".parse::<DeviceSelector>(),
+            Ok(DeviceSelector::Default)
+        );
+        assert_eq!("2".parse::<DeviceSelector>(), Ok(DeviceSelector::Index(2)));
+        assert_eq!(
+            "USB Headset".parse::<DeviceSelector>(),
+            Ok(DeviceSelector::Name("USB Headset".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_find_device_index() {
+        let devices = [
+            device(0, "pulse"),
+            device(1, "USB Headset, USB Audio"),
+            device(2, "HDMI Output"),
+            device(3, "HDMI Output 2"),
+        ];
+        assert_eq!(find_device_index(&devices, "pulse"), Some(0));
+        assert_eq!(find_device_index(&devices, "usb headset"), Some(1));
+        assert_eq!(find_device_index(&devices, "hdmi output"), Some(2));
+        assert_eq!(find_device_index(&devices, "HDMI"), None);
+        assert_eq!(find_device_index(&devices, "speaker"), None);
+    }
+}
@@ -0,0 +1,274 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Per-user volume, mute and solo controls for received audio
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::error::{Result, ZelloError};
+use serde::{Deserialize, Serialize};
+
+/// Volume settings for a single user
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct UserVolume {
+    /// Linear gain applied to this user's audio
+    #[serde(default = "unity_gain")]
+    pub gain: f32,
+    /// Whether this user's audio is silenced
+    #[serde(default)]
+    pub muted: bool,
+    /// Whether this user is soloed; when any user is soloed only they are heard
+    #[serde(default)]
+    pub solo: bool,
+}
+
+impl Default for UserVolume {
+    fn default() -> Self {
+        Self {
+            gain: unity_gain(),
+            muted: false,
+            solo: false,
+        }
+    }
+}
+
+fn unity_gain() -> f32 {
+    1.0
+}
+
+/// Volume settings for all users, with a master volume applied on top
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VolumeSettings {
+    /// Linear gain applied to all received audio
+    #[serde(default = "unity_gain")]
+    pub master_gain: f32,
+    /// Settings keyed by callsign
+    #[serde(default)]
+    pub users: HashMap<String, UserVolume>,
+}
+
+impl Default for VolumeSettings {
+    fn default() -> Self {
+        Self {
+            master_gain: unity_gain(),
+            users: HashMap::new(),
+        }
+    }
+}
+
+impl VolumeSettings {
+    /// Load settings from a JSON file
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or parsed
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Save settings to a JSON file
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be written
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// Gain to apply to audio from `callsign`, or zero if it should not be heard
+    #[must_use]
+    pub fn gain_for(&self, callsign: Option<&str>) -> f32 {
+        let user = callsign
+            .and_then(|c| self.users.get(c))
+            .copied()
+            .unwrap_or_default();
+
+        let soloing = self.users.values().any(|u| u.solo);
+        if user.muted || (soloing && !user.solo) {
+            return 0.0;
+        }
+
+        self.master_gain * user.gain
+    }
+}
+
+/// Shared, runtime-adjustable volume settings with optional persistence
+#[derive(Debug, Clone, Default)]
+pub struct VolumeControl {
+    settings: Arc<RwLock<VolumeSettings>>,
+    path: Option<PathBuf>,
+}
+
+impl VolumeControl {
+    /// Create volume controls that are saved to `path` whenever they change
+    ///
+    /// Existing settings are loaded from the file if it exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an existing file cannot be read or parsed
+    pub fn with_config_file(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let settings = if path.exists() {
+            VolumeSettings::load(&path)?
+        } else {
+            VolumeSettings::default()
+        };
+
+        Ok(Self {
+            settings: Arc::new(RwLock::new(settings)),
+            path: Some(path),
+        })
+    }
+
+    /// A copy of the current settings
+    #[must_use]
+    pub fn settings(&self) -> VolumeSettings {
+        self.read(Clone::clone)
+    }
+
+    /// Gain to apply to audio from `callsign`
+    #[must_use]
+    pub fn gain_for(&self, callsign: Option<&str>) -> f32 {
+        self.read(|s| s.gain_for(callsign))
+    }
+
+    /// Set the master volume
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the gain is invalid or the settings cannot be saved
+    pub fn set_master_gain(&self, gain: f32) -> Result<()> {
+        validate_gain(gain)?;
+        self.update(|s| s.master_gain = gain)
+    }
+
+    /// Set the volume for a user
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the gain is invalid or the settings cannot be saved
+    pub fn set_user_gain(&self, callsign: &str, gain: f32) -> Result<()> {
+        validate_gain(gain)?;
+        self.update(|s| s.users.entry(callsign.to_string()).or_default().gain = gain)
+    }
+
+    /// Mute or unmute a user
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the settings cannot be saved
+    pub fn set_user_muted(&self, callsign: &str, muted: bool) -> Result<()> {
+        self.update(|s| s.users.entry(callsign.to_string()).or_default().muted = muted)
+    }
+
+    /// Solo or unsolo a user
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the settings cannot be saved
+    pub fn set_user_solo(&self, callsign: &str, solo: bool) -> Result<()> {
+        self.update(|s| s.users.entry(callsign.to_string()).or_default().solo = solo)
+    }
+
+    /// Remove all settings for a user
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the settings cannot be saved
+    pub fn reset_user(&self, callsign: &str) -> Result<()> {
+        self.update(|s| {
+            s.users.remove(callsign);
+        })
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&VolumeSettings) -> T) -> T {
+        let settings = self
+            .settings
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        f(&settings)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut VolumeSettings)) -> Result<()> {
+        let mut settings = self
+            .settings
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        f(&mut settings);
+
+        if let Some(path) = &self.path {
+            settings.save(path)?;
+        }
+        Ok(())
+    }
+}
+
+fn validate_gain(gain: f32) -> Result<()> {
+    if gain.is_finite() && gain >= 0.0 {
+        Ok(())
+    } else {
+        Err(ZelloError::ConfigError(format!("Invalid gain: {gain}")))
+    }
+}
+
+/// Scale PCM samples by `gain`, saturating at the sample limits
+pub fn apply_gain(pcm: &mut [i16], gain: f32) {
+    if (gain - 1.0).abs() < f32::EPSILON {
+        return;
+    }
+    for sample in pcm {
+        // Truncation is intended: the value is clamped to the i16 range
+        #[allow(clippy::cast_possible_truncation)]
+        let scaled =
+            (f32::from(*sample) * gain).clamp(f32::from(i16::MIN), f32::from(i16::MAX)) as i16;
+        *sample = scaled;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gain_mute_and_solo() {
+        let volume = VolumeControl::default();
+        volume.set_master_gain(0.5).expect("master");
+        volume.set_user_gain("loud", 0.5).expect("gain");
+        assert!((volume.gain_for(Some("loud")) - 0.25).abs() < f32::EPSILON);
+        assert!((volume.gain_for(Some("other")) - 0.5).abs() < f32::EPSILON);
+
+        volume.set_user_muted("loud", true).expect("mute");
+        assert!(volume.gain_for(Some("loud")).abs() < f32::EPSILON);
+
+        volume.set_user_solo("other", true).expect("solo");
+        assert!(volume.gain_for(Some("third")).abs() < f32::EPSILON);
+        assert!(volume.gain_for(None).abs() < f32::EPSILON);
+        assert!(volume.gain_for(Some("other")) > 0.0);
+
+        assert!(volume.set_user_gain("bad", -1.0).is_err());
+    }
+
+    #[test]
+    fn test_apply_gain_saturates() {
+        let mut pcm = [1000, -1000, 30000, -30000];
+        apply_gain(&mut pcm, 2.0);
+        assert_eq!(pcm, [2000, -2000, i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn test_settings_persist() {
+        let path = std::env::temp_dir().join(format!("zello-volume-{}.json", std::process::id()));
+        let volume = VolumeControl::with_config_file(&path).expect("create");
+        volume.set_user_gain("quiet", 2.0).expect("gain");
+
+        let reloaded = VolumeControl::with_config_file(&path).expect("reload");
+        assert_eq!(reloaded.settings(), volume.settings());
+        let _ = std::fs::remove_file(path);
+    }
+}
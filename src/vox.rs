@@ -0,0 +1,268 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Voice-operated transmission of captured audio
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::CPAL_SAMPLE_RATE;
+use crate::agc::{db_to_linear, rms};
+use crate::client::ClientHandle;
+use crate::encoder::EncoderConfig;
+use crate::error::Result;
+use crate::ptt::{PcmStream, is_timed_out};
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+/// Settings for voice-operated transmission
+#[derive(Debug, Clone)]
+pub struct VoxConfig {
+    /// RMS level that keys the transmitter, in dBFS
+    pub threshold_dbfs: f32,
+    /// Time the level must stay above the threshold before keying
+    pub attack: Duration,
+    /// Time the level must stay below the threshold before unkeying
+    pub hang_time: Duration,
+    /// Audio from before keying that is sent at the start of each transmission
+    pub pre_roll: Duration,
+}
+
+impl Default for VoxConfig {
+    fn default() -> Self {
+        Self {
+            threshold_dbfs: -40.0,
+            attack: Duration::from_millis(40),
+            hang_time: Duration::from_millis(1500),
+            pre_roll: Duration::from_millis(300),
+        }
+    }
+}
+
+/// What to do with a chunk of captured audio
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VoxAction {
+    /// Not transmitting; the audio is held for pre-roll
+    Idle,
+    /// Start transmitting, beginning with this audio (pre-roll included)
+    Start(Vec<i16>),
+    /// Carry on transmitting this audio
+    Continue(Vec<i16>),
+    /// Stop transmitting
+    Stop,
+}
+
+/// Decides when captured audio should key the transmitter
+#[derive(Debug, Clone)]
+pub struct VoxDetector {
+    config: VoxConfig,
+    threshold: f32,
+    keyed: bool,
+    above: Duration,
+    quiet: Duration,
+    pre_roll: VecDeque<Vec<i16>>,
+    pre_roll_samples: usize,
+    pre_roll_max: usize,
+}
+
+impl VoxDetector {
+    /// Create a detector with these settings
+    #[must_use]
+    pub fn new(config: VoxConfig) -> Self {
+        let pre_roll_max =
+            usize::try_from(config.pre_roll.as_millis() * u128::from(CPAL_SAMPLE_RATE.0) / 1000)
+                .unwrap_or(usize::MAX);
+
+        Self {
+            threshold: db_to_linear(config.threshold_dbfs),
+            keyed: false,
+            above: Duration::ZERO,
+            quiet: Duration::ZERO,
+            pre_roll: VecDeque::new(),
+            pre_roll_samples: 0,
+            pre_roll_max,
+            config,
+        }
+    }
+
+    /// Settings in use
+    #[must_use]
+    pub fn config(&self) -> &VoxConfig {
+        &self.config
+    }
+
+    /// Whether the transmitter is keyed
+    #[must_use]
+    pub fn is_keyed(&self) -> bool {
+        self.keyed
+    }
+
+    /// Take a chunk of 16kHz mono PCM and decide what to do with it
+    pub fn process(&mut self, pcm: Vec<i16>) -> VoxAction {
+        let duration = Duration::from_micros(
+            u64::try_from(pcm.len()).unwrap_or(u64::MAX) * 1_000_000
+                / u64::from(CPAL_SAMPLE_RATE.0),
+        );
+        let loud = rms(&pcm) >= self.threshold;
+
+        if self.keyed {
+            if loud {
+                self.quiet = Duration::ZERO;
+            } else {
+                self.quiet += duration;
+                if self.quiet > self.config.hang_time {
+                    self.keyed = false;
+                    self.above = Duration::ZERO;
+                    self.remember(pcm);
+                    return VoxAction::Stop;
+                }
+            }
+            return VoxAction::Continue(pcm);
+        }
+
+        self.above = if loud {
+            self.above + duration
+        } else {
+            Duration::ZERO
+        };
+        self.remember(pcm);
+
+        if loud && self.above >= self.config.attack {
+            self.keyed = true;
+            self.quiet = Duration::ZERO;
+            self.pre_roll_samples = 0;
+            return VoxAction::Start(self.pre_roll.drain(..).flatten().collect());
+        }
+
+        VoxAction::Idle
+    }
+
+    /// Hold audio for pre-roll, keeping at least the configured duration
+    fn remember(&mut self, pcm: Vec<i16>) {
+        self.pre_roll_samples += pcm.len();
+        self.pre_roll.push_back(pcm);
+
+        while let Some(front) = self.pre_roll.front() {
+            if self.pre_roll_samples - front.len() < self.pre_roll_max {
+                break;
+            }
+            self.pre_roll_samples -= front.len();
+            self.pre_roll.pop_front();
+        }
+    }
+}
+
+/// Transmit captured audio whenever it is loud enough
+///
+/// Each time the detector keys a stream is started through `client`, and it
+/// is stopped once the audio has stayed quiet for the hang time. If the
+/// transmit time-out timer stops a stream, the next transmission waits until
+/// the input has gone quiet. Returns when the capture ends.
+///
+/// # Errors
+///
+/// Returns an error if the encoder settings are invalid or the client has stopped
+pub async fn transmit_with_vox(
+    client: ClientHandle,
+    mut pcm_rx: mpsc::Receiver<Vec<i16>>,
+    vox: VoxConfig,
+    config: EncoderConfig,
+) -> Result<()> {
+    let mut detector = VoxDetector::new(vox);
+    let mut events = client.transmit_events();
+    let mut stream: Option<PcmStream> = None;
+
+    loop {
+        let pcm = tokio::select! {
+            pcm = pcm_rx.recv() => match pcm {
+                Some(pcm) => pcm,
+                None => break,
+            },
+            event = events.recv() => {
+                if stream.as_ref().is_some_and(|s| is_timed_out(&event, s.stream_id())) {
+                    warn!("Transmission timed out; waiting for the input to go quiet");
+                    stream = None;
+                }
+                continue;
+            }
+        };
+
+        match detector.process(pcm) {
+            VoxAction::Idle => {}
+            VoxAction::Start(audio) => match PcmStream::start(&client, &config).await {
+                Ok(mut started) => {
+                    started.send(&audio)?;
+                    stream = Some(started);
+                }
+                Err(e) => error!("Failed to start transmission: {e}"),
+            },
+            VoxAction::Continue(audio) => {
+                if let Some(stream) = &mut stream {
+                    stream.send(&audio)?;
+                }
+            }
+            VoxAction::Stop => {
+                if let Some(stream) = stream.take() {
+                    stream.finish().await?;
+                }
+            }
+        }
+    }
+
+    if let Some(stream) = stream {
+        stream.finish().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 20ms of audio at a constant level
+    fn chunk(level: i16) -> Vec<i16> {
+        vec![level; 320]
+    }
+
+    #[test]
+    fn test_silence_does_not_key() {
+        let mut vox = VoxDetector::new(VoxConfig::default());
+        for _ in 0..100 {
+            assert_eq!(vox.process(chunk(10)), VoxAction::Idle);
+        }
+        assert!(!vox.is_keyed());
+    }
+
+    #[test]
+    fn test_keys_with_pre_roll_and_hangs() {
+        let mut vox = VoxDetector::new(VoxConfig {
+            attack: Duration::from_millis(40),
+            hang_time: Duration::from_millis(100),
+            pre_roll: Duration::from_millis(60),
+            ..VoxConfig::default()
+        });
+
+        for _ in 0..10 {
+            assert_eq!(vox.process(chunk(10)), VoxAction::Idle);
+        }
+        assert_eq!(vox.process(chunk(5000)), VoxAction::Idle);
+
+        // Keyed once above the threshold for the attack time, with 60ms of pre-roll
+        let VoxAction::Start(audio) = vox.process(chunk(5000)) else {
+            panic!("expected the transmitter to key");
+        };
+        assert_eq!(audio.len(), 3 * 320);
+        assert_eq!(&audio[..320], chunk(10).as_slice());
+
+        // Held through a short pause, released after the hang time
+        for _ in 0..5 {
+            assert!(matches!(vox.process(chunk(10)), VoxAction::Continue(_)));
+        }
+        assert!(matches!(vox.process(chunk(5000)), VoxAction::Continue(_)));
+        for _ in 0..5 {
+            assert!(matches!(vox.process(chunk(10)), VoxAction::Continue(_)));
+        }
+        assert_eq!(vox.process(chunk(10)), VoxAction::Stop);
+        assert!(!vox.is_keyed());
+    }
+}
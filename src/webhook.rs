@@ -0,0 +1,610 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// SPDX-FileCopyrightText: 2024 John C. Murray
+
+//! Outbound webhooks that POST channel events to other services
+
+use std::collections::hash_map::RandomState;
+use std::fmt::Write as _;
+use std::hash::BuildHasher;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::{Result, ZelloError};
+use crate::inbound::{InboundTransmission, TransmissionInfo};
+use crate::monitor::ChannelEvent;
+use crate::ogg_stream::ogg_opus_file;
+use crate::parrot::record_transmission;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, sleep_until};
+use tracing::{debug, error, info, warn};
+
+/// Header carrying the HMAC-SHA256 of the body
+pub const SIGNATURE_HEADER: &str = "X-Zello-Signature";
+
+/// Header carrying the delivery's id, which is the same on every attempt
+pub const DELIVERY_HEADER: &str = "X-Zello-Delivery";
+
+/// Settings for webhook delivery
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// Every event is posted to each of these URLs
+    pub urls: Vec<String>,
+    /// Key for signing bodies; unsigned if `None`
+    pub secret: Option<String>,
+    /// Attach each transmission's audio as Ogg/Opus to its end event
+    pub attach_audio: bool,
+    /// Longest transmission attached; the rest is dropped
+    pub max_clip: Duration,
+    /// Directory holding deliveries that have not yet succeeded
+    pub queue_dir: PathBuf,
+    /// Attempts made before a delivery is given up
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for each one after
+    pub initial_backoff: Duration,
+    /// Longest wait between retries
+    pub max_backoff: Duration,
+    /// Time allowed for each attempt
+    pub timeout: Duration,
+}
+
+impl WebhookConfig {
+    /// Settings posting to `urls` and queueing in `queue_dir`, with defaults for the rest
+    #[must_use]
+    pub fn new(urls: Vec<String>, queue_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            urls,
+            secret: None,
+            attach_audio: false,
+            max_clip: Duration::from_mins(2),
+            queue_dir: queue_dir.into(),
+            max_attempts: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_mins(10),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Wait before the attempt following `attempts` failures
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// A body waiting to be posted to one URL
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Delivery {
+    id: String,
+    url: String,
+    body: String,
+    attempts: u32,
+    /// Milliseconds since the Unix epoch
+    next_attempt: u64,
+}
+
+impl Delivery {
+    fn due_in(&self) -> Duration {
+        Duration::from_millis(self.next_attempt.saturating_sub(unix_millis()))
+    }
+}
+
+/// Deliveries kept on disk, one file each, so they survive a restart
+#[derive(Debug, Clone)]
+struct RetryQueue {
+    dir: PathBuf,
+}
+
+impl RetryQueue {
+    fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Every delivery in the queue, skipping files that cannot be read
+    fn load(&self) -> Result<Vec<Delivery>> {
+        let mut deliveries = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            match std::fs::read(&path)
+                .map_err(ZelloError::from)
+                .and_then(|data| Ok(serde_json::from_slice(&data)?))
+            {
+                Ok(delivery) => deliveries.push(delivery),
+                Err(e) => warn!(
+                    "Skipping unreadable webhook delivery {}: {e}",
+                    path.display()
+                ),
+            }
+        }
+        Ok(deliveries)
+    }
+
+    fn save(&self, delivery: &Delivery) -> Result<()> {
+        let temporary = self.dir.join(format!("{}.tmp", delivery.id));
+        std::fs::write(&temporary, serde_json::to_vec(delivery)?)?;
+        std::fs::rename(temporary, self.path(delivery))?;
+        Ok(())
+    }
+
+    fn remove(&self, delivery: &Delivery) {
+        if let Err(e) = std::fs::remove_file(self.path(delivery)) {
+            warn!("Failed to remove webhook delivery {}: {e}", delivery.id);
+        }
+    }
+
+    fn path(&self, delivery: &Delivery) -> PathBuf {
+        self.dir.join(format!("{}.json", delivery.id))
+    }
+}
+
+/// How an attempt went
+enum Outcome {
+    Delivered,
+    Retry(String),
+    Rejected(String),
+}
+
+/// Post channel events to every configured URL until the client stops
+///
+/// Text messages, transmission ends, presence changes and channel status are
+/// delivered. With `attach_audio`, `transmissions` must be given and each
+/// transmission end carries its audio. Failed deliveries are retried with
+/// exponential backoff, including after a restart. Attempts run concurrently,
+/// so a slow or unreachable URL does not hold up the others.
+///
+/// # Errors
+///
+/// Returns an error if the queue directory cannot be used or the HTTP client
+/// cannot be created
+pub async fn run_webhooks(
+    mut events: broadcast::Receiver<ChannelEvent>,
+    transmissions: Option<mpsc::UnboundedReceiver<InboundTransmission>>,
+    config: WebhookConfig,
+) -> Result<()> {
+    let queue = RetryQueue::open(&config.queue_dir)?;
+    let mut pending = queue.load()?;
+    if !pending.is_empty() {
+        info!("Resuming {} queued webhook deliveries", pending.len());
+    }
+    let http = reqwest::Client::builder()
+        .timeout(config.timeout)
+        .user_agent(concat!("zello-client/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|e| ZelloError::ConfigError(format!("Webhook client: {e}")))?;
+
+    let config = Arc::new(config);
+    let mut in_flight = JoinSet::new();
+    let (recorded_tx, mut recorded) = mpsc::unbounded_channel();
+    let mut transmissions = transmissions.filter(|_| config.attach_audio);
+    let ids = RandomState::new();
+
+    loop {
+        pending.sort_by_key(|delivery| delivery.next_attempt);
+        let next_due = pending
+            .first()
+            .map(|delivery| Instant::now() + delivery.due_in());
+
+        let payload = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => match payload_for(&event, config.attach_audio) {
+                    Some(payload) => payload,
+                    None => continue,
+                },
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Webhooks fell behind and missed {missed} events");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            Some(transmission) = next_transmission(&mut transmissions) => {
+                let recorded_tx = recorded_tx.clone();
+                let max_clip = config.max_clip;
+                tokio::spawn(async move {
+                    let info = transmission.info().clone();
+                    let audio = record_transmission(transmission, max_clip).await;
+                    let audio = (!audio.packets.is_empty()).then(|| ogg_opus_file(&audio, &info.from));
+                    let _ = recorded_tx.send((info, audio));
+                });
+                continue;
+            }
+            Some((info, audio)) = recorded.recv() => recorded_payload(&info, audio.as_deref()),
+            () = sleep_until_due(next_due) => {
+                let now = unix_millis();
+                let due = pending.partition_point(|delivery| delivery.next_attempt <= now).max(1);
+                for delivery in pending.drain(..due) {
+                    in_flight.spawn(attempt(http.clone(), config.clone(), queue.clone(), delivery));
+                }
+                continue;
+            }
+            Some(finished) = in_flight.join_next() => {
+                match finished {
+                    Ok(Some(retry)) => pending.push(retry),
+                    Ok(None) => {}
+                    Err(e) => warn!("Webhook attempt failed: {e}"),
+                }
+                continue;
+            }
+        };
+
+        let body = payload.to_string();
+        for url in &config.urls {
+            let delivery = Delivery {
+                id: format!("{:016x}", ids.hash_one((&body, url, SystemTime::now()))),
+                url: url.clone(),
+                body: body.clone(),
+                attempts: 0,
+                next_attempt: unix_millis(),
+            };
+            if let Err(e) = queue.save(&delivery) {
+                warn!("Failed to queue webhook delivery on disk: {e}");
+            }
+            pending.push(delivery);
+        }
+    }
+}
+
+/// Make one attempt, returning the delivery if it should be retried
+async fn attempt(
+    http: reqwest::Client,
+    config: Arc<WebhookConfig>,
+    queue: RetryQueue,
+    mut delivery: Delivery,
+) -> Option<Delivery> {
+    delivery.attempts += 1;
+    let outcome = post(&http, &config, &delivery).await;
+
+    let reason = match outcome {
+        Outcome::Delivered => {
+            debug!("Delivered webhook {} to {}", delivery.id, delivery.url);
+            queue.remove(&delivery);
+            return None;
+        }
+        Outcome::Rejected(reason) => {
+            error!(
+                "Webhook {} to {} was rejected, dropping it: {reason}",
+                delivery.id, delivery.url
+            );
+            queue.remove(&delivery);
+            return None;
+        }
+        Outcome::Retry(reason) => reason,
+    };
+
+    if delivery.attempts >= config.max_attempts {
+        error!(
+            "Giving up on webhook {} to {} after {} attempts: {reason}",
+            delivery.id, delivery.url, delivery.attempts
+        );
+        queue.remove(&delivery);
+        return None;
+    }
+
+    let backoff = config.backoff(delivery.attempts);
+    warn!(
+        "Webhook {} to {} failed ({reason}), retrying in {backoff:?}",
+        delivery.id, delivery.url
+    );
+    delivery.next_attempt =
+        unix_millis() + u64::try_from(backoff.as_millis()).unwrap_or(u64::MAX / 2);
+    if let Err(e) = queue.save(&delivery) {
+        warn!("Failed to update queued webhook {}: {e}", delivery.id);
+    }
+    Some(delivery)
+}
+
+async fn post(http: &reqwest::Client, config: &WebhookConfig, delivery: &Delivery) -> Outcome {
+    let mut request = http
+        .post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(DELIVERY_HEADER, &delivery.id)
+        .body(delivery.body.clone());
+    if let Some(secret) = &config.secret {
+        request = request.header(SIGNATURE_HEADER, signature(secret, &delivery.body));
+    }
+
+    match request.send().await {
+        Ok(response) if response.status().is_success() => Outcome::Delivered,
+        Ok(response) => {
+            let status = response.status();
+            // The receiver will never take it, unless it was busy or timed out
+            if status.is_client_error()
+                && status != reqwest::StatusCode::REQUEST_TIMEOUT
+                && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+            {
+                Outcome::Rejected(status.to_string())
+            } else {
+                Outcome::Retry(status.to_string())
+            }
+        }
+        Err(e) => Outcome::Retry(e.to_string()),
+    }
+}
+
+/// The `sha256=` HMAC of `body` keyed with `secret`, in hex
+fn signature(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .fold(String::from("sha256="), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// The body for an event, or `None` if it is not delivered
+///
+/// Transmission ends come from the recording instead when audio is attached.
+fn payload_for(event: &ChannelEvent, attach_audio: bool) -> Option<serde_json::Value> {
+    match event {
+        ChannelEvent::Text { .. }
+        | ChannelEvent::Presence { .. }
+        | ChannelEvent::ChannelStatus { .. } => {}
+        ChannelEvent::TransmissionEnd { .. } if !attach_audio => {}
+        _ => return None,
+    }
+    let mut payload = serde_json::to_value(event).ok()?;
+    payload["timestamp"] = unix_millis().into();
+    Some(payload)
+}
+
+/// The body for a recorded transmission, with its audio if there was any
+fn recorded_payload(info: &TransmissionInfo, audio: Option<&[u8]>) -> serde_json::Value {
+    let duration = info.started_at.elapsed().unwrap_or_default();
+    let event = ChannelEvent::TransmissionEnd {
+        stream_id: info.stream_id,
+        channel: info.channel.clone(),
+        from: info.from.clone(),
+        duration_ms: u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+    };
+    let mut payload = serde_json::to_value(&event).unwrap_or_default();
+    payload["timestamp"] = unix_millis().into();
+    if let Some(audio) = audio {
+        payload["audio"] = serde_json::json!({
+            "content_type": "audio/ogg; codecs=opus",
+            "data": STANDARD.encode(audio),
+        });
+    }
+    payload
+}
+
+async fn next_transmission(
+    transmissions: &mut Option<mpsc::UnboundedReceiver<InboundTransmission>>,
+) -> Option<InboundTransmission> {
+    match transmissions {
+        Some(transmissions) => transmissions.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn sleep_until_due(due: Option<Instant>) {
+    match due {
+        Some(due) => sleep_until(due).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| {
+            u64::try_from(since.as_millis()).unwrap_or(u64::MAX)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn queue_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("zello-webhook-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            signature("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_limit() {
+        let config = WebhookConfig::new(Vec::new(), "unused");
+        assert_eq!(config.backoff(1), Duration::from_secs(1));
+        assert_eq!(config.backoff(4), Duration::from_secs(8));
+        assert_eq!(config.backoff(11), Duration::from_mins(10));
+        assert_eq!(config.backoff(100), Duration::from_mins(10));
+    }
+
+    #[test]
+    fn test_queue_survives_reopening() {
+        let dir = queue_dir("queue");
+        let delivery = Delivery {
+            id: "0123456789abcdef".to_string(),
+            url: "http://localhost/hook".to_string(),
+            body: r#"{"type":"text"}"#.to_string(),
+            attempts: 2,
+            next_attempt: 1_000,
+        };
+        RetryQueue::open(&dir)
+            .expect("open")
+            .save(&delivery)
+            .expect("save");
+
+        let queue = RetryQueue::open(&dir).expect("reopen");
+        assert_eq!(queue.load().expect("load"), vec![delivery.clone()]);
+        queue.remove(&delivery);
+        assert!(queue.load().expect("load").is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_payload_filtering() {
+        let end = ChannelEvent::TransmissionEnd {
+            stream_id: 7,
+            channel: "channel".to_string(),
+            from: "alice".to_string(),
+            duration_ms: 1500,
+        };
+        let payload = payload_for(&end, false).expect("payload");
+        assert_eq!(payload["type"], "transmission_end");
+        assert_eq!(payload["duration_ms"], 1500);
+        assert!(payload["timestamp"].is_u64());
+        assert!(payload_for(&end, true).is_none());
+        assert!(payload_for(&ChannelEvent::Connection { connected: true }, false).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed_delivery_is_retried() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new().route(
+            "/hook",
+            post({
+                let calls = calls.clone();
+                let received = received.clone();
+                move |headers: HeaderMap, body: String| async move {
+                    // Fail the first attempt
+                    if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                    let signature = headers[SIGNATURE_HEADER].to_str().unwrap_or_default();
+                    received
+                        .lock()
+                        .expect("lock")
+                        .push((signature.to_string(), body));
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let address = listener.local_addr().expect("address");
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let dir = queue_dir("retry");
+        let mut config = WebhookConfig::new(vec![format!("http://{address}/hook")], &dir);
+        config.secret = Some("secret".to_string());
+        config.initial_backoff = Duration::from_millis(10);
+        let (events_tx, events) = broadcast::channel(8);
+        let webhooks = tokio::spawn(run_webhooks(events, None, config));
+
+        events_tx
+            .send(ChannelEvent::Text {
+                channel: "channel".to_string(),
+                from: "alice".to_string(),
+                author: None,
+                for_user: None,
+                text: "hello".to_string(),
+            })
+            .expect("send");
+        for _ in 0..200 {
+            if !received.lock().expect("lock").is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        webhooks.abort();
+
+        let received = received.lock().expect("lock").clone();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let (sent_signature, body) = &received[0];
+        assert_eq!(*sent_signature, signature("secret", body));
+        let body: serde_json::Value = serde_json::from_str(body).expect("json");
+        assert_eq!(body["type"], "text");
+        assert_eq!(body["text"], "hello");
+        // Delivered, so nothing is left to retry
+        assert!(
+            RetryQueue::open(&dir)
+                .expect("open")
+                .load()
+                .expect("load")
+                .is_empty()
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn test_dead_url_does_not_hold_up_others() {
+        // Accepts connections but never replies, so each attempt runs to the time-out
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let dead_address = dead.local_addr().expect("address");
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = dead.accept().await {
+                held.push(socket);
+            }
+        });
+
+        let (bodies_tx, mut bodies) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/hook",
+            post(move |body: String| async move {
+                let _ = bodies_tx.send(body);
+                StatusCode::NO_CONTENT
+            }),
+        );
+        let live = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let live_address = live.local_addr().expect("address");
+        tokio::spawn(async move { axum::serve(live, app).await });
+
+        let dir = queue_dir("dead");
+        let mut config = WebhookConfig::new(
+            vec![
+                format!("http://{dead_address}/hook"),
+                format!("http://{live_address}/hook"),
+            ],
+            &dir,
+        );
+        config.timeout = Duration::from_secs(30);
+        let (events_tx, events) = broadcast::channel(8);
+        let webhooks = tokio::spawn(run_webhooks(events, None, config));
+
+        for online in [true, false] {
+            events_tx
+                .send(ChannelEvent::Presence {
+                    channel: "channel".to_string(),
+                    from: "alice".to_string(),
+                    online,
+                })
+                .expect("send");
+            let body = tokio::time::timeout(Duration::from_secs(2), bodies.recv())
+                .await
+                .expect("live URL held up by the dead one")
+                .expect("body");
+            let body: serde_json::Value = serde_json::from_str(&body).expect("json");
+            assert_eq!(body["online"], online);
+        }
+        webhooks.abort();
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
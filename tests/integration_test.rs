@@ -123,6 +123,7 @@ fn test_message_constructors() {
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn test_message_success_check() {
     let msg = Response::Generic {
         seq: 1,
@@ -189,20 +190,21 @@ async fn test_audio_stream_lifecycle() {
     let mut client = ZelloClient::new(config).await.expect("Failed to connect");
 
     // Start audio stream
-    let stream_id = client
+    let mut stream = client
         .start_audio_stream("opus", 20)
         .await
         .expect("Failed to start stream");
 
-    assert!(stream_id > 0);
+    assert!(stream.stream_id() > 0);
 
     // Send some dummy audio data
     let audio_data = vec![0u8; 100];
-    let result = client.send_audio_packet(stream_id, audio_data).await;
+    let result = stream.send(audio_data).await;
     assert!(result.is_ok());
+    assert_eq!(stream.packets_sent(), 1);
 
     // Stop the stream
-    let result = client.stop_audio_stream(stream_id).await;
+    let result = stream.finish().await;
     assert!(result.is_ok());
 }
 